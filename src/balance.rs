@@ -0,0 +1,141 @@
+//! Inter-channel balance checks, for catching mix problems such as a hot or
+//! missing channel.
+
+use crate::math;
+
+/// The role of a channel within a layout, which decides how it is grouped when
+/// checking balance.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Channel {
+    Left,
+    Right,
+    Center,
+    Lfe,
+    LeftSurround,
+    RightSurround,
+
+    /// A channel that is only included in the overall spread.
+    Other,
+}
+
+impl Channel {
+    fn is_front(self) -> bool {
+        matches!(self, Self::Left | Self::Right | Self::Center)
+    }
+
+    fn is_surround(self) -> bool {
+        matches!(self, Self::LeftSurround | Self::RightSurround)
+    }
+}
+
+/// A comparison of the loudness of the channels in a layout, in LU.
+///
+/// Each value is `None` if the layout lacks the channels needed for it, or if
+/// all of those channels are silent. A value is infinite if only one side of
+/// the comparison is silent, which usually means that a channel is missing.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Balance {
+    /// The loudness of the left channel relative to the right. Positive values
+    /// mean that the left channel is louder.
+    pub left_right: Option<f64>,
+
+    /// The combined loudness of the surround channels relative to the
+    /// combined loudness of the front channels (left, right and centre).
+    pub surround_to_front: Option<f64>,
+
+    /// The loudness of the loudest channel relative to the quietest, not
+    /// counting the LFE channel.
+    pub spread: Option<f64>,
+}
+
+impl Balance {
+    /// Compares a set of per-channel loudness values in LKFS, such as those
+    /// from [`Loudness::channel_loudness`](crate::gated_loudness::Loudness::channel_loudness),
+    /// given the role of each channel.
+    pub fn new(channel_loudness: &[f64], roles: &[Channel]) -> Self {
+        assert_eq!(channel_loudness.len(), roles.len(), "one role is needed per channel");
+
+        let channels = || channel_loudness.iter().copied().zip(roles.iter().copied());
+
+        // The combined power of the channels that match a predicate, if there
+        // are any.
+        let power = |predicate: &dyn Fn(Channel) -> bool| {
+            channels()
+                .filter(|(_, role)| predicate(*role))
+                .map(|(l, _)| to_power(l))
+                .fold(None, |acc: Option<f64>, p| Some(acc.unwrap_or(0.0) + p))
+        };
+
+        let left_right = ratio(
+            power(&|r| r == Channel::Left),
+            power(&|r| r == Channel::Right),
+        );
+
+        let surround_to_front = ratio(
+            power(&|r| r.is_surround()),
+            power(&|r| r.is_front()),
+        );
+
+        let (min, max) = channels()
+            .filter(|(_, role)| *role != Channel::Lfe)
+            .map(|(l, _)| to_power(l))
+            .fold((None, None), |(min, max): (Option<f64>, Option<f64>), p| {
+                (Some(min.map_or(p, |m| m.min(p))), Some(max.map_or(p, |m| m.max(p))))
+            });
+
+        Self {
+            left_right,
+            surround_to_front,
+            spread: ratio(max, min),
+        }
+    }
+}
+
+fn to_power(loudness: f64) -> f64 {
+    math::powf(10.0, (loudness + 0.691) / 10.0)
+}
+
+// The ratio of two powers in LU, where both powers must be present and at least
+// one must be non-zero.
+fn ratio(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    let (a, b) = (a?, b?);
+
+    if a == 0.0 && b == 0.0 { None }
+    else { Some(10.0 * math::log10(a / b)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn balance() {
+        use Channel::*;
+
+        let roles = [Left, Right, Center, Lfe, LeftSurround, RightSurround];
+
+        let balance = Balance::new(&[-20.0, -23.0, -20.0, -10.0, -30.0, -30.0], &roles);
+
+        assert_abs_diff_eq!(balance.left_right.unwrap(), 3.0, epsilon = 1e-9);
+        assert_abs_diff_eq!(
+            balance.surround_to_front.unwrap(),
+            10.0 * (2.0 * to_power(-30.0) / (2.0 * to_power(-20.0) + to_power(-23.0))).log10(),
+            epsilon = 1e-9,
+        );
+        assert_abs_diff_eq!(balance.spread.unwrap(), 10.0, epsilon = 1e-9);
+
+        // A missing right channel shows up as an infinite imbalance.
+        let balance = Balance::new(&[-20.0, f64::NEG_INFINITY, -20.0, -10.0, -30.0, -30.0], &roles);
+
+        assert_eq!(balance.left_right, Some(f64::INFINITY));
+        assert_eq!(balance.spread, Some(f64::INFINITY));
+
+        // Stereo has no surround channels to compare.
+        let balance = Balance::new(&[-20.0, -20.0], &[Left, Right]);
+
+        assert_abs_diff_eq!(balance.left_right.unwrap(), 0.0, epsilon = 1e-9);
+        assert_eq!(balance.surround_to_front, None);
+    }
+}
@@ -0,0 +1,178 @@
+//! Checkpoints of [`Pipeline`](crate::pipeline::Pipeline) state, so that long
+//! scans can be saved to disk and resumed later.
+//!
+//! A checkpoint records the number of frames processed so far, which is the
+//! frame offset that a reader should seek to before feeding the rest of the
+//! input into the resumed pipeline. Results are then identical to those of an
+//! uninterrupted run. With the `serde` feature enabled, checkpoints can be
+//! serialized with any `serde` format. Formats that do not round-trip `f64`
+//! values exactly will cause small differences in the resumed results.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use sampara::Frame;
+use sampara::biquad::Params;
+
+use crate::filter::MAX_SECTIONS;
+use crate::non_finite::{NonFiniteCounts, NonFiniteRun};
+use crate::pipeline::BlockSource;
+use crate::util::Util;
+
+/// The version of the checkpoint layout. This is increased whenever the layout
+/// changes, and checkpoints from other versions are rejected.
+pub const CHECKPOINT_VERSION: u32 = 10;
+
+/// Reasons that a [`Checkpoint`] cannot be resumed from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CheckpointError {
+    /// The checkpoint was saved with a different layout version.
+    Version(u32),
+
+    /// The checkpoint was saved from a pipeline with a different sample rate,
+    /// channel count, weighting, set of gatings, block schemes or regions, or
+    /// one of the block schemes could not save its state.
+    Mismatch,
+
+    /// The checkpoint contents are inconsistent, which usually means that it
+    /// has been corrupted.
+    Invalid,
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Version(v) => write!(
+                f, "unsupported checkpoint version {} (expected {})", v, CHECKPOINT_VERSION,
+            ),
+            Self::Mismatch => write!(f, "checkpoint does not match the pipeline configuration"),
+            Self::Invalid => write!(f, "checkpoint is invalid or corrupted"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CheckpointError {}
+
+/// The saved state of a single gating or block scheme within a pipeline.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct MeterCheckpoint {
+    pub source: BlockSource,
+
+    // The state of the gate or block generator, if it could be saved.
+    pub state: Option<Vec<f64>>,
+
+    // The powers of every gated block so far, one frame after another, if the
+    // source is used for an average or a maximum.
+    pub blocks: Vec<f64>,
+
+    // The weighted power of each block within each sliding window, in order
+    // of window length.
+    pub sliding: Vec<Vec<f64>>,
+}
+
+/// The saved state of the programme or of a single region within a pipeline.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct ChainCheckpoint {
+    // The state of the current sub-block, which is shared by every gating.
+    pub sub_blocks: Vec<f64>,
+
+    pub meters: Vec<MeterCheckpoint>,
+}
+
+/// The saved state of a [`Pipeline`](crate::pipeline::Pipeline), created with
+/// [`Pipeline::checkpoint`](crate::pipeline::Pipeline::checkpoint) and resumed
+/// with [`PipelineBuilder::resume`](crate::pipeline::PipelineBuilder::resume).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checkpoint {
+    pub(crate) version: u32,
+    pub(crate) sample_rate: u32,
+    pub(crate) channels: usize,
+    pub(crate) frames: u64,
+    pub(crate) weighting: Vec<Option<[f64; 5]>>,
+    pub(crate) filter: Vec<f64>,
+    pub(crate) program: Option<ChainCheckpoint>,
+    pub(crate) regions: Vec<ChainCheckpoint>,
+    pub(crate) non_finite: Vec<NonFiniteRun>,
+    pub(crate) non_finite_counts: NonFiniteCounts,
+}
+
+impl Checkpoint {
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The number of frames that had been processed when the checkpoint was
+    /// taken. Input should be resumed from this frame offset.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub(crate) fn validate(
+        &self,
+        sample_rate: u32,
+        channels: usize,
+        weighting: &[Option<[f64; 5]>],
+    ) -> Result<(), CheckpointError>
+    {
+        if self.version != CHECKPOINT_VERSION {
+            return Err(CheckpointError::Version(self.version));
+        }
+
+        if self.sample_rate != sample_rate || self.channels != channels || self.weighting != weighting {
+            return Err(CheckpointError::Mismatch);
+        }
+
+        let is_valid =
+            self.filter.len() == 2 * MAX_SECTIONS * channels
+            && self.non_finite.iter().all(|r| r.frames.start < r.frames.end && r.frames.end <= self.frames)
+            && self.program.iter().chain(self.regions.iter()).all(|c| {
+                c.meters.iter().all(|m| m.blocks.len() % channels == 0)
+            })
+        ;
+
+        if is_valid { Ok(()) } else { Err(CheckpointError::Invalid) }
+    }
+}
+
+/// Lists the coefficients of each weighting filter section, so that the filter
+/// state is only restored into a filter with the same sections.
+pub(crate) fn weighting_coefficients(sections: &[Option<Params<f64>>; MAX_SECTIONS]) -> Vec<Option<[f64; 5]>> {
+    sections.iter()
+        .map(|s| s.as_ref().map(|p| [p.b0, p.b1, p.b2, p.a1, p.a2]))
+        .collect()
+}
+
+/// Appends the channels of each frame to a flat list of samples.
+pub(crate) fn flatten<F, I, const N: usize>(frames: I) -> Vec<f64>
+where
+    F: Frame<N, Sample = f64>,
+    I: IntoIterator<Item = F>,
+{
+    frames.into_iter().flat_map(|f| f.into_channels()).collect()
+}
+
+/// Splits a flat list of samples back into frames. The length must be a
+/// multiple of the number of channels.
+pub(crate) fn unflatten<F, const N: usize>(samples: &[f64]) -> impl Iterator<Item = F> + '_
+where
+    F: Frame<N, Sample = f64>,
+{
+    samples.chunks_exact(N).map(|chunk| {
+        let mut x = [0.0; N];
+        x.copy_from_slice(chunk);
+        Util::array_to_frame(x)
+    })
+}
+
+/// Converts a count that was saved as part of a list of numbers back into an
+/// integer, or returns `None` if it is negative, fractional or too large to
+/// have been saved exactly.
+pub(crate) fn whole(x: f64) -> Option<u64> {
+    if (0.0..9007199254740992.0).contains(&x) && x == (x as u64) as f64 { Some(x as u64) }
+    else { None }
+}
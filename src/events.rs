@@ -0,0 +1,372 @@
+//! Loudness and peak alarms for continuous broadcast monitoring.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use sampara::Frame;
+
+use crate::filter::{KWeightFilter, UnsupportedSampleRate};
+use crate::gated_loudness::{GatedPowers, Gating};
+use crate::math;
+use crate::peak::TruePeak;
+use crate::util::Util;
+
+// Short-term loudness uses a 3 second window, as in EBU Tech 3341, but is
+// updated every 100 ms so that alarms react quickly.
+const SHORTTERM_GATING: Gating = Gating::Custom { gate_len_ms: 3000, delta_len_ms: 100 };
+
+// The number of short-term steps between one window and the adjacent one
+// before it.
+const ADJACENT_STEPS: usize = 30;
+
+/// A condition to raise an alarm for.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Rule {
+    /// Short-term loudness stays above `limit` LUFS for at least
+    /// `duration_ms`. The alarm ends once it falls below
+    /// `limit - hysteresis`.
+    ShorttermAbove { limit: f64, duration_ms: u64, hysteresis: f64 },
+
+    /// The true peak of any channel exceeds `ceiling` dBTP. The alarm ends
+    /// once the true peak has stayed below `ceiling - hysteresis` for
+    /// `hold_ms`.
+    TruePeakAbove { ceiling: f64, hysteresis: f64, hold_ms: u64 },
+
+    /// Short-term loudness differs from that of the adjacent window before it
+    /// by more than `jump` LU, such as at the start of a loud ad break. The
+    /// alarm ends once the difference falls below `jump - hysteresis`.
+    LoudnessJump { jump: f64, hysteresis: f64 },
+}
+
+/// Whether an alarm has started or ended.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Edge {
+    Start,
+    End,
+}
+
+/// An alarm raised by an [`EventDetector`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Event {
+    /// The index of the rule that raised the alarm, in the order that rules
+    /// were given to the detector.
+    pub rule: usize,
+
+    pub edge: Edge,
+
+    /// The frame at which the condition started or ended. For rules with a
+    /// minimum duration, this is earlier than the frame at which the start
+    /// event was emitted.
+    pub frame: u64,
+
+    /// The time at which the condition started or ended, in seconds.
+    pub secs: f64,
+
+    /// The value that was checked by the rule when the event was emitted:
+    /// the short-term loudness in LUFS, the true peak in dBTP, or the
+    /// loudness difference in LU.
+    pub value: f64,
+}
+
+impl Event {
+    fn new(sample_rate: u32, rule: usize, edge: Edge, frame: u64, value: f64) -> Self {
+        Self {
+            rule,
+            edge,
+            frame,
+            secs: frame as f64 / sample_rate as f64,
+            value,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RuleState {
+    rule: Rule,
+    active: bool,
+
+    // When the condition was first met, for rules with a minimum duration,
+    // or last met, for rules with a hold time.
+    mark: Option<u64>,
+}
+
+/// Watches a signal for the conditions described by a set of [`Rule`]s, and
+/// emits timestamped start and end events for each one.
+pub struct EventDetector<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    sample_rate: u32,
+    frames: u64,
+
+    k_filter: KWeightFilter<F, N>,
+    gated_powers: GatedPowers<F, N>,
+    g_weights: F,
+
+    // Only needed if there are any true peak rules.
+    true_peak: Option<TruePeak<N>>,
+
+    // Recent short-term loudness values, for comparing adjacent windows.
+    history: VecDeque<f64>,
+
+    rules: Vec<RuleState>,
+}
+
+impl<F, const N: usize> EventDetector<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Creates a detector for the given rules.
+    ///
+    /// Panics if the sample rate is not supported by the K-weighting filter.
+    pub fn new<I>(sample_rate: u32, g_weights: F, rules: I) -> Self
+    where
+        I: IntoIterator<Item = Rule>,
+    {
+        match Self::try_new(sample_rate, g_weights, rules) {
+            Ok(detector) => detector,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates a detector for the given rules, or returns an error if the
+    /// sample rate is not supported by the K-weighting filter.
+    pub fn try_new<I>(sample_rate: u32, g_weights: F, rules: I) -> Result<Self, UnsupportedSampleRate>
+    where
+        I: IntoIterator<Item = Rule>,
+    {
+        let k_filter = KWeightFilter::try_new(sample_rate)?;
+
+        let rules = rules.into_iter()
+            .map(|rule| RuleState { rule, active: false, mark: None })
+            .collect::<Vec<_>>();
+
+        let needs_true_peak = rules.iter()
+            .any(|r| matches!(r.rule, Rule::TruePeakAbove { .. }));
+
+        Ok(Self {
+            sample_rate,
+            frames: 0,
+            k_filter,
+            gated_powers: GatedPowers::new(sample_rate, SHORTTERM_GATING),
+            g_weights,
+            true_peak: if needs_true_peak { Some(TruePeak::new()) } else { None },
+            history: VecDeque::with_capacity(ADJACENT_STEPS + 1),
+            rules,
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.frames = 0;
+        self.k_filter.reset();
+        self.gated_powers.reset();
+
+        if let Some(true_peak) = self.true_peak.as_mut() {
+            true_peak.reset();
+        }
+
+        self.history.clear();
+
+        for state in self.rules.iter_mut() {
+            state.active = false;
+            state.mark = None;
+        }
+    }
+
+    /// Processes a frame, calling `on_event` for every event that it causes.
+    pub fn push<E>(&mut self, input: F, mut on_event: E)
+    where
+        E: FnMut(Event),
+    {
+        let pos = self.frames;
+        self.frames += 1;
+
+        if let Some(true_peak) = self.true_peak.as_mut() {
+            let peak = 20.0 * math::log10(true_peak.push(Util::frame_to_array(input)));
+            self.check_true_peak(pos, peak, &mut on_event);
+        }
+
+        let filtered = self.k_filter.process(input);
+
+        if let Some(gated_powers) = self.gated_powers.process(filtered) {
+            let loudness = Util::loudness(gated_powers, self.g_weights);
+
+            if self.history.len() == ADJACENT_STEPS + 1 {
+                self.history.pop_front();
+            }
+            self.history.push_back(loudness);
+
+            self.check_shortterm(self.frames, loudness, &mut on_event);
+        }
+    }
+
+    fn check_true_peak<E>(&mut self, pos: u64, peak: f64, on_event: &mut E)
+    where
+        E: FnMut(Event),
+    {
+        let sample_rate = self.sample_rate;
+
+        for (i, state) in self.rules.iter_mut().enumerate() {
+            let (ceiling, hysteresis, hold_ms) = match state.rule {
+                Rule::TruePeakAbove { ceiling, hysteresis, hold_ms } => (ceiling, hysteresis, hold_ms),
+                _ => continue,
+            };
+
+            let hold = Util::ms_to_samples(hold_ms, sample_rate);
+
+            if !state.active {
+                if peak > ceiling {
+                    state.active = true;
+                    state.mark = Some(pos);
+                    on_event(Event::new(sample_rate, i, Edge::Start, pos, peak));
+                }
+            }
+            else if peak > ceiling - hysteresis {
+                state.mark = Some(pos);
+            }
+            else if state.mark.map_or(true, |m| pos - m >= hold) {
+                state.active = false;
+                state.mark = None;
+                on_event(Event::new(sample_rate, i, Edge::End, pos, peak));
+            }
+        }
+    }
+
+    fn check_shortterm<E>(&mut self, pos: u64, loudness: f64, on_event: &mut E)
+    where
+        E: FnMut(Event),
+    {
+        // The difference between this window and the adjacent one before it.
+        // Differences between two silent windows are treated as zero.
+        let jump = match self.history.front() {
+            Some(prev) if self.history.len() == ADJACENT_STEPS + 1 => {
+                let diff = (loudness - prev).abs();
+                if diff.is_nan() { 0.0 } else { diff }
+            },
+            _ => 0.0,
+        };
+
+        let sample_rate = self.sample_rate;
+
+        for (i, state) in self.rules.iter_mut().enumerate() {
+            match state.rule {
+                Rule::ShorttermAbove { limit, duration_ms, hysteresis } => {
+                    let duration = Util::ms_to_samples(duration_ms, sample_rate);
+
+                    if !state.active {
+                        if loudness > limit {
+                            let onset = *state.mark.get_or_insert(pos);
+
+                            if pos - onset >= duration {
+                                state.active = true;
+                                on_event(Event::new(sample_rate, i, Edge::Start, onset, loudness));
+                            }
+                        }
+                        else {
+                            state.mark = None;
+                        }
+                    }
+                    else if loudness < limit - hysteresis {
+                        state.active = false;
+                        state.mark = None;
+                        on_event(Event::new(sample_rate, i, Edge::End, pos, loudness));
+                    }
+                },
+                Rule::LoudnessJump { jump: limit, hysteresis } => {
+                    if !state.active && jump > limit {
+                        state.active = true;
+                        on_event(Event::new(sample_rate, i, Edge::Start, pos, jump));
+                    }
+                    else if state.active && jump < limit - hysteresis {
+                        state.active = false;
+                        on_event(Event::new(sample_rate, i, Edge::End, pos, jump));
+                    }
+                },
+                Rule::TruePeakAbove { .. } => {},
+            }
+        }
+    }
+
+    pub fn feed<I, E>(&mut self, frames: I, mut on_event: E)
+    where
+        I: IntoIterator<Item = F>,
+        E: FnMut(Event),
+    {
+        for frame in frames.into_iter() {
+            self.push(frame, &mut on_event);
+        }
+    }
+
+    /// Ends any alarms that are still active at the end of the signal.
+    pub fn finish<E>(mut self, mut on_event: E)
+    where
+        E: FnMut(Event),
+    {
+        let pos = self.frames;
+
+        for (i, state) in self.rules.iter_mut().enumerate() {
+            if state.active {
+                state.active = false;
+                on_event(Event::new(self.sample_rate, i, Edge::End, pos, f64::NAN));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::TestUtil;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    #[test]
+    fn alarms() {
+        let rules = [
+            Rule::ShorttermAbove { limit: -15.0, duration_ms: 2000, hysteresis: 1.0 },
+            Rule::TruePeakAbove { ceiling: -1.0, hysteresis: 0.5, hold_ms: 500 },
+            Rule::LoudnessJump { jump: 10.0, hysteresis: 2.0 },
+        ];
+
+        let mut detector = EventDetector::<f64, 1>::new(SAMPLE_RATE, 1.0, rules.iter().copied());
+        let mut events = Vec::new();
+
+        // A quiet tone, with a loud section from 10 to 20 seconds that peaks
+        // near full scale from 12 to 14 seconds.
+        let envelope = |t: f64| {
+            if (12.0..14.0).contains(&t) { 0.95 }
+            else if (10.0..20.0).contains(&t) { 0.7 }
+            else { 0.05 }
+        };
+        let frames = TestUtil::sine(SAMPLE_RATE, 997.0, 28.0, envelope);
+
+        detector.feed(frames, |e| events.push(e));
+        detector.finish(|e| events.push(e));
+
+        let for_rule = |rule: usize| {
+            events.iter().filter(|e| e.rule == rule).copied().collect::<Vec<_>>()
+        };
+
+        let shortterm = for_rule(0);
+        assert_eq!(shortterm.len(), 2);
+        assert_eq!(shortterm[0].edge, Edge::Start);
+        assert!((10.0..11.0).contains(&shortterm[0].secs));
+        assert_eq!(shortterm[1].edge, Edge::End);
+        assert!((22.0..23.0).contains(&shortterm[1].secs));
+
+        let true_peak = for_rule(1);
+        assert_eq!(true_peak.len(), 2);
+        assert_eq!(true_peak[0].edge, Edge::Start);
+        assert!((12.0..12.01).contains(&true_peak[0].secs));
+        assert_eq!(true_peak[1].edge, Edge::End);
+        assert!((14.4..14.6).contains(&true_peak[1].secs));
+
+        // Both the rise and the fall of the loud section are jumps.
+        let jumps = for_rule(2);
+        assert_eq!(jumps.len(), 4);
+        assert_eq!(jumps.iter().filter(|e| e.edge == Edge::Start).count(), 2);
+        assert!((10.0..13.0).contains(&jumps[0].secs));
+        assert!((20.0..24.0).contains(&jumps[2].secs));
+    }
+}
@@ -0,0 +1,184 @@
+//! Multichannel biquad filter sections.
+//!
+//! Each section keeps separate state for every channel, but shares its
+//! coefficients across all of them. This allows channels to be filtered in
+//! parallel SIMD lanes when the `simd` feature is enabled. The SIMD path
+//! performs exactly the same operations in the same order as the scalar path,
+//! so their outputs are bit-identical.
+//!
+//! Sections are computed in transposed direct form II, which rounds
+//! differently from the direct form biquads in `sampara`. For the K-weighting
+//! filter, the two agree to within 4096 ulps of the peak output level.
+
+#[cfg(feature = "simd")]
+use core::simd::f64x4;
+
+use sampara::biquad::Params;
+
+use crate::util::Util;
+
+#[cfg(feature = "simd")]
+const LANES: usize = 4;
+
+/// The most that the K-weighting filter's output may differ from a cascade of
+/// `sampara` biquads, in ulps of the peak output level. Rounding differences
+/// are fed back through the high-pass stage, whose poles lie very close to the
+/// unit circle, so they can build up to a few thousand ulps.
+#[cfg(test)]
+pub(crate) const MAX_ULPS: f64 = 4096.0;
+
+/// A biquad filter section in transposed direct form II, with state for each
+/// of `N` channels.
+#[derive(Clone, Debug)]
+pub(crate) struct Section<const N: usize> {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+
+    s1: [f64; N],
+    s2: [f64; N],
+}
+
+impl<const N: usize> From<Params<f64>> for Section<N> {
+    fn from(params: Params<f64>) -> Self {
+        Self {
+            b0: params.b0,
+            b1: params.b1,
+            b2: params.b2,
+            a1: params.a1,
+            a2: params.a2,
+            s1: [0.0; N],
+            s2: [0.0; N],
+        }
+    }
+}
+
+impl<const N: usize> Section<N> {
+    pub fn reset(&mut self) {
+        self.s1 = [0.0; N];
+        self.s2 = [0.0; N];
+    }
+
+    /// Returns the per-channel filter state.
+    #[cfg(feature = "alloc")]
+    pub fn state(&self) -> [[f64; N]; 2] {
+        [self.s1, self.s2]
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn set_state(&mut self, state: [[f64; N]; 2]) {
+        let [s1, s2] = state;
+        self.s1 = s1;
+        self.s2 = s2;
+    }
+
+    /// Flushes state values that are too small to affect the output to zero,
+    /// so that they never decay into subnormal numbers.
+    #[inline]
+    pub fn flush_denormals(&mut self) {
+        for s in self.s1.iter_mut().chain(self.s2.iter_mut()) {
+            *s = Util::den(*s);
+        }
+    }
+
+    /// Filters one frame in place, using the SIMD path if it is enabled.
+    #[inline]
+    pub fn process(&mut self, x: &mut [f64; N]) {
+        #[cfg(feature = "simd")]
+        self.process_simd(x);
+
+        #[cfg(not(feature = "simd"))]
+        self.process_scalar(x);
+    }
+
+    /// Filters one frame in place, one channel at a time.
+    #[cfg_attr(feature = "simd", allow(dead_code))]
+    #[inline]
+    pub fn process_scalar(&mut self, x: &mut [f64; N]) {
+        let Self { b0, b1, b2, a1, a2, .. } = *self;
+
+        for ((x, s1), s2) in x.iter_mut().zip(self.s1.iter_mut()).zip(self.s2.iter_mut()) {
+            let y = b0 * *x + *s1;
+            *s1 = b1 * *x - a1 * y + *s2;
+            *s2 = b2 * *x - a2 * y;
+            *x = y;
+        }
+    }
+
+    /// Filters one frame in place, several channels at a time. Channel counts
+    /// that are not a multiple of the lane count are padded with zeros.
+    #[cfg(feature = "simd")]
+    #[inline]
+    pub fn process_simd(&mut self, x: &mut [f64; N]) {
+        let b0 = f64x4::splat(self.b0);
+        let b1 = f64x4::splat(self.b1);
+        let b2 = f64x4::splat(self.b2);
+        let a1 = f64x4::splat(self.a1);
+        let a2 = f64x4::splat(self.a2);
+
+        let load = |src: &[f64]| {
+            let mut lanes = [0.0; LANES];
+            lanes[..src.len()].copy_from_slice(src);
+            f64x4::from_array(lanes)
+        };
+
+        let mut start = 0;
+        while start < N {
+            let end = (start + LANES).min(N);
+            let len = end - start;
+
+            let xv = load(&x[start..end]);
+            let s1v = load(&self.s1[start..end]);
+            let s2v = load(&self.s2[start..end]);
+
+            let y = b0 * xv + s1v;
+            let s1v = b1 * xv - a1 * y + s2v;
+            let s2v = b2 * xv - a2 * y;
+
+            x[start..end].copy_from_slice(&y.to_array()[..len]);
+            self.s1[start..end].copy_from_slice(&s1v.to_array()[..len]);
+            self.s2[start..end].copy_from_slice(&s2v.to_array()[..len]);
+
+            start = end;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "simd"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simd_matches_scalar() {
+        let params = Params {
+            a1: -1.6906592931824103,
+            a2:  0.7324807742158501,
+            b0:  1.5351248595869702,
+            b1: -2.6916961894063807,
+            b2:  1.19839281085285,
+        };
+
+        let mut scalar = Section::<6>::from(params);
+        let mut simd = scalar.clone();
+
+        for i in 0..10000 {
+            let t = i as f64;
+            let input = [
+                (t * 0.01).sin(), (t * 0.02).cos(), (t * 0.003).sin(),
+                (t * 0.5).sin() * 0.1, 1.0, if i % 7 == 0 { -1.0 } else { 0.0 },
+            ];
+
+            let mut expected = input;
+            let mut produced = input;
+
+            scalar.process_scalar(&mut expected);
+            simd.process_simd(&mut produced);
+
+            for (e, p) in expected.iter().zip(produced.iter()) {
+                assert_eq!(e.to_bits(), p.to_bits());
+            }
+        }
+    }
+}
@@ -0,0 +1,1176 @@
+mod biquad;
+mod fixed_point;
+mod response;
+mod svf;
+mod weighting;
+
+use core::f64::consts::PI;
+use core::fmt;
+use core::marker::PhantomData;
+
+use sampara::{Frame, Processor};
+use sampara::biquad::Params;
+
+use crate::math;
+use crate::non_finite::{NonFinite, NonFiniteCounts, NonFiniteError, NonFiniteGuard};
+use crate::util::Util;
+
+use self::biquad::Section;
+use self::svf::SvfSection;
+use self::weighting::Design;
+
+pub use self::fixed_point::*;
+pub use self::response::*;
+pub use self::weighting::*;
+
+/// The lowest sample rate that the weighting filters support, in Hz.
+pub const MIN_SAMPLE_RATE: u32 = 8000;
+
+/// The highest sample rate that the weighting filters support, in Hz.
+pub const MAX_SAMPLE_RATE: u32 = 768000;
+
+// Above this sample rate, filter stages use state variable sections instead of
+// biquads. At these rates the poles of the high pass stage lie very close to
+// the unit circle, where a biquad needs far more precision.
+const SVF_SAMPLE_RATE: u32 = 192000;
+
+/// The error returned when creating a filter at a sample rate outside of
+/// [`MIN_SAMPLE_RATE`] to [`MAX_SAMPLE_RATE`]. Below that range, the shelving
+/// stage of K-weighting comes too close to the Nyquist frequency to be
+/// meaningful, and above it, results have not been verified.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UnsupportedSampleRate(pub u32);
+
+impl fmt::Display for UnsupportedSampleRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "unsupported sample rate {} Hz (expected {} to {} Hz, or higher for some weightings)",
+            self.0, MIN_SAMPLE_RATE, MAX_SAMPLE_RATE,
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for UnsupportedSampleRate {}
+
+/// Checks that a sample rate is within the supported range.
+pub fn check_sample_rate(sample_rate: u32) -> Result<(), UnsupportedSampleRate> {
+    if (MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&sample_rate) { Ok(()) }
+    else { Err(UnsupportedSampleRate(sample_rate)) }
+}
+
+/// Checks that a sample rate is within the supported range, and that it is
+/// high enough for a weighting, as given by [`Weighting::min_sample_rate`].
+pub fn check_weighting_sample_rate<W>(sample_rate: u32, weighting: &W) -> Result<(), UnsupportedSampleRate>
+where
+    W: Weighting + ?Sized,
+{
+    check_sample_rate(sample_rate)?;
+
+    if sample_rate >= weighting.min_sample_rate() { Ok(()) }
+    else { Err(UnsupportedSampleRate(sample_rate)) }
+}
+
+// The published ITU BS.1770 coefficients for each filter stage, at 48 kHz.
+const SPEC_SAMPLE_RATE: u32 = 48000;
+const SPEC_SHELVING: Params<f64> = Params {
+    a1: -1.69065929318241,
+    a2:  0.73248077421585,
+    b0:  1.53512485958697,
+    b1: -2.69169618940638,
+    b2:  1.19839281085285,
+};
+const SPEC_HIGHPASS: Params<f64> = Params {
+    a1: -1.99004745483398,
+    a2:  0.99007225036621,
+    b0:  1.0,
+    b1: -2.0,
+    b2:  1.0,
+};
+
+/// Where the coefficients of a [`KWeightFilter`] come from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CoefficientSource {
+    /// Calculated from the analog prototype of each filter stage, at any
+    /// sample rate. At 48 kHz, these differ from the published ITU BS.1770
+    /// tables in the last few digits.
+    Derived,
+
+    /// The published ITU BS.1770 tables at 48 kHz. No tables are published
+    /// for other sample rates, so at those rates the coefficients are
+    /// calculated in the same way as [`Self::Derived`], from the analog
+    /// prototype that was fitted to the 48 kHz tables.
+    SpecExact,
+}
+
+impl Default for CoefficientSource {
+    fn default() -> Self {
+        Self::Derived
+    }
+}
+
+/// How the filter stages deal with state values that decay towards zero, such
+/// as during long silences.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Denormals {
+    /// Flushes state values below 1e-15 to zero after every frame. These are
+    /// about 300 dB below full scale, so this has no measurable effect on
+    /// loudness.
+    Flush,
+
+    /// Leaves the state untouched. After long silences, the state can decay
+    /// into subnormal numbers, which are many times slower to process on most
+    /// CPUs.
+    Keep,
+}
+
+impl Default for Denormals {
+    fn default() -> Self {
+        Self::Flush
+    }
+}
+
+// The gain of the shelving filter at high frequencies, in dB, and the
+// exponent that gives the gain at its corner frequency from that.
+const SHELF_HEIGHT: f64 = 3.999843853973347;
+const SHELF_VB_EXP: f64 = 0.4996667741545416;
+
+#[derive(Copy, Clone, Debug)]
+enum Kind {
+    Shelving, HighPass,
+}
+
+impl Kind {
+    fn coefficients_from(&self, sample_rate: u32, source: CoefficientSource) -> Params<f64> {
+        match (source, self) {
+            (CoefficientSource::SpecExact, Self::Shelving) if sample_rate == SPEC_SAMPLE_RATE => SPEC_SHELVING,
+            (CoefficientSource::SpecExact, Self::HighPass) if sample_rate == SPEC_SAMPLE_RATE => SPEC_HIGHPASS,
+            _ => self.coefficients(sample_rate),
+        }
+    }
+
+    /// The corner frequency and Q of the analog prototype filter.
+    fn prototype(&self) -> (f64, f64) {
+        match self {
+            Self::Shelving => (1681.974450955533, 0.7071752369554196),
+            Self::HighPass => (38.13547087602444, 0.5003270373238773),
+        }
+    }
+
+    /// The gains of the shelving filter at high frequencies and at its corner
+    /// frequency, as linear amplitudes.
+    fn shelf_gains() -> (f64, f64) {
+        let vh = math::powf(10.0, SHELF_HEIGHT / 20.0);
+        let vb = math::powf(vh, SHELF_VB_EXP);
+
+        (vh, vb)
+    }
+
+    /// The analog prototype at a sample rate, with the corner frequency
+    /// prewarped in the same way as [`Self::coefficients`].
+    fn analog(&self, sample_rate: u32) -> Prototype {
+        let (f0, q) = self.prototype();
+
+        let g = math::tan(PI * f0 / sample_rate as f64);
+
+        let numerator =
+            match self {
+                Self::Shelving => {
+                    let (vh, vb) = Self::shelf_gains();
+                    [vh, vb / q, 1.0]
+                },
+
+                // The digital numerator is fixed at (1, -2, 1), so the gain at
+                // high frequencies depends on the corner.
+                Self::HighPass => [1.0 + g * (g + 1.0 / q), 0.0, 0.0],
+            }
+        ;
+
+        Prototype { g, k: 1.0 / q, numerator }
+    }
+
+    fn design(&self, sample_rate: u32) -> Design {
+        (self.coefficients(sample_rate), self.analog(sample_rate))
+    }
+
+    fn coefficients(&self, sample_rate: u32) -> Params<f64> {
+        let (f0, q) = self.prototype();
+
+        let k = math::tan(PI * f0 / sample_rate as f64);
+        let k_by_q = k / q;
+        let k_sq = k * k;
+
+        let a0 = 1.0 + k_by_q + k_sq;
+        let a1 = 2.0 * (k_sq - 1.0) / a0;
+        let a2 = (1.0 - k_by_q + k_sq) / a0;
+
+        let (b0, b1, b2) =
+            match self {
+                Self::Shelving => {
+                    let (vh, vb) = Self::shelf_gains();
+
+                    let b0 = (vh + vb * k_by_q + k_sq) / a0;
+                    let b1 = 2.0 * (k_sq - vh) / a0;
+                    let b2 = (vh - vb * k_by_q + k_sq) / a0;
+
+                    (b0, b1, b2)
+                },
+                Self::HighPass => (1.0, -2.0, 1.0),
+            }
+        ;
+
+        Params { a1, a2, b0, b1, b2, }
+    }
+}
+
+// A single filter stage, which is a biquad at common sample rates and a state
+// variable section at very high ones.
+#[derive(Clone, Debug)]
+enum Stage<const N: usize> {
+    Biquad(Section<N>),
+    Svf(SvfSection<N>),
+}
+
+impl<const N: usize> Stage<N> {
+    fn new(params: Params<f64>, prototype: Option<Prototype>, sample_rate: u32) -> Self {
+        match prototype {
+            Some(prototype) if sample_rate > SVF_SAMPLE_RATE => Self::Svf(SvfSection::from(prototype)),
+            _ => Self::Biquad(Section::from(params)),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Self::Biquad(section) => section.reset(),
+            Self::Svf(section) => section.reset(),
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    fn state(&self) -> [[f64; N]; 2] {
+        match self {
+            Self::Biquad(section) => section.state(),
+            Self::Svf(section) => section.state(),
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    fn set_state(&mut self, state: [[f64; N]; 2]) {
+        match self {
+            Self::Biquad(section) => section.set_state(state),
+            Self::Svf(section) => section.set_state(state),
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, x: &mut [f64; N]) {
+        match self {
+            Self::Biquad(section) => section.process(x),
+            Self::Svf(section) => section.process(x),
+        }
+    }
+
+    #[inline]
+    fn flush_denormals(&mut self) {
+        match self {
+            Self::Biquad(section) => section.flush_denormals(),
+            Self::Svf(section) => section.flush_denormals(),
+        }
+    }
+}
+
+/// A K-weighting filter, as described in ITU BS.1770. Sample rates from
+/// [`MIN_SAMPLE_RATE`] to [`MAX_SAMPLE_RATE`] are supported.
+///
+/// This is a [`WeightingFilter`] with the two K-weighting stages, which can be
+/// named without choosing a weighting.
+#[derive(Clone, Debug)]
+pub struct KWeightFilter<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    inner: WeightingFilter<F, N>,
+}
+
+impl<F, const N: usize> KWeightFilter<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Creates a filter for the given sample rate.
+    ///
+    /// Panics if the sample rate is not supported.
+    pub fn new(sample_rate: u32) -> Self {
+        Self::with_coefficients(sample_rate, CoefficientSource::Derived)
+    }
+
+    /// Creates a filter for the given sample rate, or returns an error if the
+    /// sample rate is not supported.
+    pub fn try_new(sample_rate: u32) -> Result<Self, UnsupportedSampleRate> {
+        Self::try_with_coefficients(sample_rate, CoefficientSource::Derived)
+    }
+
+    /// Creates a filter using the given coefficient source.
+    ///
+    /// Panics if the sample rate is not supported.
+    pub fn with_coefficients(sample_rate: u32, source: CoefficientSource) -> Self {
+        match Self::try_with_coefficients(sample_rate, source) {
+            Ok(filter) => filter,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    pub fn try_with_coefficients(
+        sample_rate: u32,
+        source: CoefficientSource,
+    ) -> Result<Self, UnsupportedSampleRate>
+    {
+        let inner = WeightingFilter::try_new(sample_rate, &KWeighting(source))?;
+
+        Ok(Self { inner })
+    }
+
+    /// Sets how the filter state is kept from becoming subnormal. By default,
+    /// tiny state values are flushed to zero.
+    pub fn set_denormals(&mut self, denormals: Denormals) {
+        self.inner.set_denormals(denormals);
+    }
+
+    /// Sets what to do with input frames that contain NaN or infinite
+    /// samples. By default, those samples are replaced with zero.
+    ///
+    /// Panics if the policy is [`NonFinite::Skip`], since a filter has to
+    /// output something for every frame.
+    pub fn set_non_finite(&mut self, policy: NonFinite) {
+        self.inner.set_non_finite(policy);
+    }
+
+    /// Returns the number of non-finite input samples seen since the filter
+    /// was created or reset.
+    pub fn non_finite_counts(&self) -> NonFiniteCounts {
+        self.inner.non_finite_counts()
+    }
+
+    pub fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    /// Filters a single frame.
+    ///
+    /// Panics if the frame is rejected for containing a non-finite sample,
+    /// which only happens under [`NonFinite::Reject`]. Use
+    /// [`Self::try_process`] to handle rejected frames instead.
+    pub fn process(&mut self, input: F) -> F {
+        self.inner.process(input)
+    }
+
+    /// Filters a single frame, or returns an error if it is rejected for
+    /// containing a non-finite sample.
+    pub fn try_process(&mut self, input: F) -> Result<F, NonFiniteError> {
+        self.inner.try_process(input)
+    }
+
+    /// Filters a buffer of interleaved samples in place. The buffer length
+    /// must be a multiple of the number of channels.
+    ///
+    /// Panics if a frame is rejected for containing a non-finite sample. Use
+    /// [`Self::try_process`] to handle rejected frames under
+    /// [`NonFinite::Reject`].
+    pub fn process_interleaved(&mut self, samples: &mut [f64]) {
+        self.inner.process_interleaved(samples);
+    }
+
+    /// Filters a set of planar (one buffer per channel) samples in place. All
+    /// channel buffers must have the same length.
+    ///
+    /// Panics if a frame is rejected for containing a non-finite sample. Use
+    /// [`Self::try_process`] to handle rejected frames under
+    /// [`NonFinite::Reject`].
+    pub fn process_planar(&mut self, channels: &mut [&mut [f64]]) {
+        self.inner.process_planar(channels);
+    }
+
+    /// Returns the state of both filter stages, for checkpointing.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn state(&self) -> [[f64; N]; 4] {
+        let [[a, b], [c, d], _] = self.inner.state();
+        [a, b, c, d]
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn set_state(&mut self, state: [[f64; N]; 4]) {
+        let [a, b, c, d] = state;
+        self.inner.set_state([[a, b], [c, d], [[0.0; N]; 2]]);
+    }
+
+    /// Filters a single frame stored as an array, in place. This does not
+    /// check for non-finite samples.
+    #[inline]
+    pub(crate) fn process_array(&mut self, x: &mut [f64; N]) {
+        self.inner.process_array(x);
+    }
+}
+
+impl<F, const N: usize> Processor for KWeightFilter<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    type Input = F;
+    type Output = F;
+
+    fn process(&mut self, input: Self::Input) -> Self::Output {
+        self.inner.process(input)
+    }
+}
+
+/// A filter for any [`Weighting`], which is chosen when the filter is created.
+/// With K-weighting, this gives exactly the same output as [`KWeightFilter`].
+#[derive(Clone, Debug)]
+pub struct WeightingFilter<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    sections: [Option<Stage<N>>; MAX_SECTIONS],
+    denormals: Denormals,
+    guard: NonFiniteGuard,
+    frames: u64,
+    _marker: PhantomData<F>,
+}
+
+impl<F, const N: usize> WeightingFilter<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Creates a filter for the given sample rate.
+    ///
+    /// Panics if the sample rate is not supported.
+    pub fn new<W>(sample_rate: u32, weighting: &W) -> Self
+    where
+        W: Weighting + ?Sized,
+    {
+        match Self::try_new(sample_rate, weighting) {
+            Ok(filter) => filter,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates a filter for the given sample rate, or returns an error if the
+    /// sample rate is not supported.
+    pub fn try_new<W>(sample_rate: u32, weighting: &W) -> Result<Self, UnsupportedSampleRate>
+    where
+        W: Weighting + ?Sized,
+    {
+        check_weighting_sample_rate(sample_rate, weighting)?;
+
+        let sections = weighting.sections(sample_rate);
+        let prototypes = weighting.prototypes(sample_rate).unwrap_or([None; MAX_SECTIONS]);
+
+        Ok(Self::with_stages(sample_rate, sections, prototypes))
+    }
+
+    /// Creates a filter from section coefficients that were precalculated for
+    /// the given sample rate. Without their analog prototypes, the sections
+    /// are biquads at every sample rate.
+    pub fn from_sections(sample_rate: u32, sections: [Option<Params<f64>>; MAX_SECTIONS]) -> Self {
+        Self::with_stages(sample_rate, sections, [None; MAX_SECTIONS])
+    }
+
+    fn with_stages(
+        sample_rate: u32,
+        sections: [Option<Params<f64>>; MAX_SECTIONS],
+        prototypes: [Option<Prototype>; MAX_SECTIONS],
+    ) -> Self
+    {
+        Self {
+            sections: core::array::from_fn(|i| sections[i].map(|p| Stage::new(p, prototypes[i], sample_rate))),
+            denormals: Denormals::default(),
+            guard: NonFiniteGuard::default(),
+            frames: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets how the filter state is kept from becoming subnormal. By default,
+    /// tiny state values are flushed to zero.
+    pub fn set_denormals(&mut self, denormals: Denormals) {
+        self.denormals = denormals;
+    }
+
+    /// Sets what to do with input frames that contain NaN or infinite
+    /// samples. By default, those samples are replaced with zero.
+    ///
+    /// Panics if the policy is [`NonFinite::Skip`], since a filter has to
+    /// output something for every frame. Frames can be skipped by a
+    /// `Pipeline` instead.
+    pub fn set_non_finite(&mut self, policy: NonFinite) {
+        assert!(policy != NonFinite::Skip, "filters cannot skip frames");
+
+        self.guard.set_policy(policy);
+    }
+
+    /// Returns the number of non-finite input samples seen since the filter
+    /// was created or reset.
+    pub fn non_finite_counts(&self) -> NonFiniteCounts {
+        self.guard.counts()
+    }
+
+    pub fn reset(&mut self) {
+        for section in self.sections.iter_mut().flatten() {
+            section.reset();
+        }
+
+        self.guard.reset();
+        self.frames = 0;
+    }
+
+    /// Filters a single frame.
+    ///
+    /// Panics if the frame is rejected for containing a non-finite sample,
+    /// which only happens under [`NonFinite::Reject`]. Use
+    /// [`Self::try_process`] to handle rejected frames instead.
+    pub fn process(&mut self, input: F) -> F {
+        Processor::process(self, input)
+    }
+
+    /// Filters a single frame, or returns an error if it is rejected for
+    /// containing a non-finite sample.
+    pub fn try_process(&mut self, input: F) -> Result<F, NonFiniteError> {
+        let mut x = Util::frame_to_array(input);
+
+        self.try_process_array(&mut x)?;
+
+        Ok(Util::array_to_frame(x))
+    }
+
+    /// Filters a buffer of interleaved samples in place. The buffer length
+    /// must be a multiple of the number of channels.
+    ///
+    /// Panics if a frame is rejected for containing a non-finite sample. Use
+    /// [`Self::try_process`] to handle rejected frames under
+    /// [`NonFinite::Reject`].
+    pub fn process_interleaved(&mut self, samples: &mut [f64]) {
+        assert_eq!(samples.len() % N, 0, "incomplete frame in interleaved buffer");
+
+        for chunk in samples.chunks_exact_mut(N) {
+            let mut x = [0.0; N];
+            x.copy_from_slice(chunk);
+
+            self.process_checked(&mut x);
+
+            chunk.copy_from_slice(&x);
+        }
+    }
+
+    /// Filters a set of planar (one buffer per channel) samples in place. All
+    /// channel buffers must have the same length.
+    ///
+    /// Panics if a frame is rejected for containing a non-finite sample. Use
+    /// [`Self::try_process`] to handle rejected frames under
+    /// [`NonFinite::Reject`].
+    pub fn process_planar(&mut self, channels: &mut [&mut [f64]]) {
+        let len = Util::planar_len(channels.iter().map(|c| c.len()), N);
+
+        for i in 0..len {
+            let mut x = [0.0; N];
+            for (x, c) in x.iter_mut().zip(channels.iter()) {
+                *x = c[i];
+            }
+
+            self.process_checked(&mut x);
+
+            for (x, c) in x.iter().zip(channels.iter_mut()) {
+                c[i] = *x;
+            }
+        }
+    }
+
+    /// Returns the state of every section, for checkpointing. Unused sections
+    /// have a state of all zeros.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn state(&self) -> [[[f64; N]; 2]; MAX_SECTIONS] {
+        let mut state = [[[0.0; N]; 2]; MAX_SECTIONS];
+
+        for (s, section) in state.iter_mut().zip(self.sections.iter()) {
+            if let Some(section) = section {
+                *s = section.state();
+            }
+        }
+
+        state
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn set_state(&mut self, state: [[[f64; N]; 2]; MAX_SECTIONS]) {
+        for (s, section) in state.iter().zip(self.sections.iter_mut()) {
+            if let Some(section) = section {
+                section.set_state(*s);
+            }
+        }
+    }
+
+    // Applies the non-finite policy to a frame, then filters it in place. The
+    // policy is never `Skip`, so every frame that gets through is processed.
+    fn try_process_array(&mut self, x: &mut [f64; N]) -> Result<(), NonFiniteError> {
+        self.guard.check(x, self.frames)?;
+        self.process_array(x);
+
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    fn process_checked(&mut self, x: &mut [f64; N]) {
+        if let Err(err) = self.try_process_array(x) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Filters a single frame stored as an array, in place. This does not
+    /// check for non-finite samples.
+    #[inline]
+    pub(crate) fn process_array(&mut self, x: &mut [f64; N]) {
+        let flush = self.denormals == Denormals::Flush;
+
+        for section in self.sections.iter_mut().flatten() {
+            section.process(x);
+
+            if flush {
+                section.flush_denormals();
+            }
+        }
+    }
+}
+
+impl<F, const N: usize> Processor for WeightingFilter<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    type Input = F;
+    type Output = F;
+
+    fn process(&mut self, input: Self::Input) -> Self::Output {
+        let mut x = Util::frame_to_array(input);
+
+        self.process_checked(&mut x);
+
+        Util::array_to_frame(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coefficients() {
+        // ITU BS.1770 provides coefficients for both filters at a 48KHz
+        // sampling rate:
+        //     Shelving:
+        //         a1: -1.69065929318241
+        //         a2:  0.73248077421585
+        //         b1: -2.69169618940638
+        //         b2:  1.19839281085285
+        //         b0:  1.53512485958697
+        //     Highpass:
+        //         a1: -1.99004745483398
+        //         a2:  0.99007225036621
+        //         b0:  1.0
+        //         b1: -2.0
+        //         b2:  1.0
+        // However, the biquad coefficients are calculated from scratch from any
+        // arbitrary sample rate, including @ 48KHz. The expected calculated
+        // coefficients @ 48KHz are very close to the ITU BS.1770 values, but
+        // not exact. As a result, in all of these tests the hard-coded
+        // coefficients @ 48KHz do not exactly match those in ITU BS.1770, and
+        // that is intentional.
+        let expected = Params {
+            a1: -1.6906592931824103,
+            a2:  0.7324807742158501,
+            b0:  1.5351248595869702,
+            b1: -2.6916961894063807,
+            b2:  1.19839281085285,
+        };
+        let produced = Kind::Shelving.coefficients(48000);
+
+        assert_eq!(expected, produced);
+
+        let expected = Params {
+            a1: -1.6636551132560204,
+            a2:  0.7125954280732254,
+            b0:  1.5308412300503478,
+            b1: -2.6509799951547297,
+            b2:  1.169079079921587,
+        };
+        let produced = Kind::Shelving.coefficients(44100);
+
+        assert_eq!(expected, produced);
+
+        let expected = Params {
+            a1: -0.2933807824149212,
+            a2:  0.18687510604540827,
+            b0:  1.3216235689299776,
+            b1: -0.7262554913156911,
+            b2:  0.2981262460162007,
+        };
+        let produced = Kind::Shelving.coefficients(8000);
+
+        assert_eq!(expected, produced);
+
+        let expected = Params {
+            a1: -1.9222022306074886,
+            a2:  0.9251177351168259,
+            b0:  1.572227215091279,
+            b1: -3.0472830515615508,
+            b2:  1.4779713409796094,
+        };
+        let produced = Kind::Shelving.coefficients(192000);
+
+        assert_eq!(expected, produced);
+
+        let expected = Params {
+            a1: -1.9900474548339797,
+            a2:  0.9900722503662099,
+            b0:  1.0,
+            b1: -2.0,
+            b2:  1.0,
+        };
+        let produced = Kind::HighPass.coefficients(48000);
+
+        assert_eq!(expected, produced);
+    }
+
+    #[test]
+    fn coefficient_sources() {
+        use crate::gated_loudness::{GatedPowers, Gating, Loudness};
+
+        // The coefficients published in ITU BS.1770, table 1 and table 2.
+        let published_shelving = Params {
+            a1: -1.69065929318241,
+            a2:  0.73248077421585,
+            b0:  1.53512485958697,
+            b1: -2.69169618940638,
+            b2:  1.19839281085285,
+        };
+        let published_highpass = Params {
+            a1: -1.99004745483398,
+            a2:  0.99007225036621,
+            b0:  1.0,
+            b1: -2.0,
+            b2:  1.0,
+        };
+
+        let as_array = |p: &Params<f64>| [p.b0, p.b1, p.b2, p.a1, p.a2];
+
+        // At 48 kHz, the exact source uses the published values as is, and the
+        // derived coefficients only agree with them to the published precision.
+        for (kind, published) in [(Kind::Shelving, published_shelving), (Kind::HighPass, published_highpass)].iter() {
+            let exact = kind.coefficients_from(48000, CoefficientSource::SpecExact);
+            let derived = kind.coefficients_from(48000, CoefficientSource::Derived);
+
+            assert_eq!(as_array(&exact), as_array(published));
+
+            for (d, p) in as_array(&derived).iter().zip(as_array(published).iter()) {
+                assert!((d - p).abs() <= 1.0e-9, "derived {}, published {}", d, p);
+            }
+        }
+
+        // Away from 48 kHz, both sources are the same.
+        for &kind in [Kind::Shelving, Kind::HighPass].iter() {
+            assert_eq!(
+                as_array(&kind.coefficients_from(44100, CoefficientSource::SpecExact)),
+                as_array(&kind.coefficients_from(44100, CoefficientSource::Derived)),
+            );
+        }
+
+        // The EBU Tech 3341 minimum requirements test signals 1 to 5: stereo
+        // 1 kHz sines, given as (dBFS, seconds) segments, with the integrated
+        // loudness that each must read, within 0.1 LU.
+        let cases: [(&[(f64, f64)], f64); 5] = [
+            (&[(-23.0, 20.0)], -23.0),
+            (&[(-33.0, 20.0)], -33.0),
+            (&[(-36.0, 10.0), (-23.0, 60.0), (-36.0, 10.0)], -23.0),
+            (&[(-72.0, 10.0), (-36.0, 10.0), (-23.0, 60.0), (-36.0, 10.0), (-72.0, 10.0)], -23.0),
+            (&[(-26.0, 20.0), (-20.0, 20.1), (-26.0, 20.0)], -23.0),
+        ];
+
+        const SAMPLE_RATE: u32 = 48000;
+
+        for &source in [CoefficientSource::Derived, CoefficientSource::SpecExact].iter() {
+            for &(segments, expected) in cases.iter() {
+                let mut k_filter = KWeightFilter::<[f64; 2], 2>::with_coefficients(SAMPLE_RATE, source);
+                let mut gated_powers = GatedPowers::new(SAMPLE_RATE, Gating::Momentary);
+                let mut loudness = Loudness::new([1.0, 1.0]);
+
+                let mut i = 0usize;
+                for &(dbfs, secs) in segments.iter() {
+                    let amp = 10.0f64.powf(dbfs / 20.0);
+                    let len = (secs * SAMPLE_RATE as f64).round() as usize;
+
+                    for _ in 0..len {
+                        let x = amp * (2.0 * PI * 1000.0 * i as f64 / SAMPLE_RATE as f64).sin();
+
+                        if let Some(gp) = gated_powers.process(k_filter.process([x, x])) {
+                            loudness.push(gp);
+                        }
+
+                        i += 1;
+                    }
+                }
+
+                let reading = loudness.calculate().unwrap();
+                assert!(
+                    (reading - expected).abs() <= 0.1,
+                    "{:?}: expected {}, read {}", source, expected, reading,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn interleaved_and_planar() {
+        const SAMPLE_RATE: u32 = 44100;
+
+        let frames = (0..1000)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                [(2.0 * PI * 440.0 * t).sin(), (2.0 * PI * 3000.0 * t).cos()]
+            })
+            .collect::<Vec<_>>();
+
+        let mut k_filter = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+        let expected = frames.iter().map(|f| k_filter.process(*f)).collect::<Vec<_>>();
+
+        let mut interleaved = frames.iter().flat_map(|f| f.iter().copied()).collect::<Vec<_>>();
+        let mut k_filter = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+        k_filter.process_interleaved(&mut interleaved);
+
+        let mut left = frames.iter().map(|f| f[0]).collect::<Vec<_>>();
+        let mut right = frames.iter().map(|f| f[1]).collect::<Vec<_>>();
+        let mut k_filter = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+        k_filter.process_planar(&mut [&mut left[..], &mut right[..]]);
+
+        for (i, e) in expected.iter().enumerate() {
+            assert_eq!(e, &[interleaved[2 * i], interleaved[2 * i + 1]]);
+            assert_eq!(e, &[left[i], right[i]]);
+        }
+    }
+
+    #[test]
+    fn weighting_filter() {
+        const SAMPLE_RATE: u32 = 44100;
+
+        let frames = (0..10000)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                [(2.0 * PI * 440.0 * t).sin(), if i == 0 { 1.0 } else { 0.0 }]
+            })
+            .collect::<Vec<_>>();
+
+        let mut k_filter = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+        let mut weighting_filter = WeightingFilter::<[f64; 2], 2>::new(SAMPLE_RATE, &StandardWeighting::K);
+        let mut z_filter = WeightingFilter::<[f64; 2], 2>::new(SAMPLE_RATE, &StandardWeighting::Z);
+
+        for frame in frames.iter() {
+            assert_eq!(k_filter.process(*frame), weighting_filter.process(*frame));
+            assert_eq!(*frame, z_filter.process(*frame));
+        }
+    }
+
+    #[test]
+    fn sample_rate_range() {
+        use crate::gated_loudness::{GatedPowers, Gating, Loudness, Q29GatedPowers};
+
+        assert_eq!(KWeightFilter::<f64, 1>::try_new(4000).err(), Some(UnsupportedSampleRate(4000)));
+        assert_eq!(KWeightFilter::<f64, 1>::try_new(1000000).err(), Some(UnsupportedSampleRate(1000000)));
+        assert!(WeightingFilter::<f64, 1>::try_new(7999, &StandardWeighting::K).is_err());
+
+        // Everything that owns a weighting filter reports the same error.
+        {
+            use crate::events::EventDetector;
+            use crate::leq::LeqM;
+            use crate::pipeline::PipelineBuilder;
+            use crate::realtime::RealtimeMeter;
+            use crate::segment_search::SegmentSearch;
+            use crate::silence::{SilenceDetector, Threshold};
+
+            let err = Some(UnsupportedSampleRate(4000));
+
+            assert_eq!(PipelineBuilder::<f64, 1>::try_new(4000, 1.0).err(), err);
+            assert_eq!(RealtimeMeter::<f64, 1>::try_new(4000, 1.0).err(), err);
+            assert_eq!(SilenceDetector::<f64, 1>::try_new(4000, 1.0, Threshold::Lufs(-60.0), 1000).err(), err);
+            assert_eq!(SegmentSearch::<f64, 1>::try_new(4000, 1.0, 3000).err(), err);
+            assert_eq!(EventDetector::<f64, 1>::try_new(4000, 1.0, None).err(), err);
+            assert_eq!(LeqM::<f64, 1>::try_new(4000, 1.0).err(), err);
+            assert_eq!(Q31KWeightFilter::<1>::try_new(4000).err(), err);
+            assert_eq!(Q29GatedPowers::<1, 4>::try_new(4000, Gating::Momentary).err(), err);
+
+            // A dBFS threshold does not need the filter.
+            assert!(SilenceDetector::<f64, 1>::try_new(4000, 1.0, Threshold::Dbfs(-60.0), 1000).is_ok());
+
+            // M-weighting needs a higher rate than the others.
+            let err = Some(UnsupportedSampleRate(8000));
+
+            assert_eq!(WeightingFilter::<f64, 1>::try_new(8000, &StandardWeighting::M).err(), err);
+            assert_eq!(FrequencyResponse::try_new(8000, &StandardWeighting::M).err(), err);
+            assert_eq!(LeqM::<f64, 1>::try_new(8000, 1.0).err(), err);
+            assert_eq!(
+                PipelineBuilder::<f64, 1>::new(8000, 1.0).try_weighting(&StandardWeighting::M).err(),
+                err,
+            );
+
+            assert!(WeightingFilter::<f64, 1>::try_new(8000, &StandardWeighting::A).is_ok());
+            assert!(LeqM::<f64, 1>::try_new(20758, 1.0).is_ok());
+        }
+
+        // BS.1770 gives a reading of -3.01 LKFS for a 0 dBFS 997 Hz sine. The
+        // filters are derived from an analog prototype, and the bilinear
+        // transform warps the shelving stage by a different amount at each
+        // sample rate, so the reading drifts by a few hundredths of an LU.
+        // Every supported rate has to stay within 0.05 LU, half the
+        // tolerance that EBU Tech 3341 allows for a meter.
+        const TOLERANCE: f64 = 0.05;
+
+        let sample_rates = [
+            8000, 11025, 16000, 22050, 32000, 44100, 48000, 88200,
+            96000, 176400, 192000, 352800, 384000, 705600, 768000,
+        ];
+
+        for &sample_rate in sample_rates.iter() {
+            let mut k_filter = KWeightFilter::<f64, 1>::try_new(sample_rate).unwrap();
+            let mut gated_powers = GatedPowers::momentary(sample_rate);
+            let mut loudness = Loudness::new(1.0);
+
+            for i in 0..(sample_rate as usize * 2) {
+                let t = i as f64 / sample_rate as f64;
+
+                if let Some(gp) = gated_powers.process(k_filter.process((2.0 * PI * 997.0 * t).sin())) {
+                    loudness.push(gp);
+                }
+            }
+
+            let reading = loudness.calculate().unwrap();
+
+            assert!((reading + 3.01).abs() <= TOLERANCE, "{} Hz reads {} LKFS", sample_rate, reading);
+        }
+    }
+
+    #[test]
+    fn svf_matches_biquad() {
+        // Both stage types have the same transfer function, so at a rate where
+        // biquads are still accurate, their outputs should agree closely.
+        const SAMPLE_RATE: u32 = 192000;
+
+        for kind in [Kind::Shelving, Kind::HighPass].iter() {
+            let mut biquad = Section::<2>::from(kind.coefficients(SAMPLE_RATE));
+            let mut svf = SvfSection::<2>::from(kind.analog(SAMPLE_RATE));
+
+            for i in 0..SAMPLE_RATE {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                let mut x = [(2.0 * PI * 997.0 * t).sin(), if i == 0 { 1.0 } else { 0.0 }];
+                let mut y = x;
+
+                biquad.process(&mut x);
+                svf.process(&mut y);
+
+                for (e, p) in x.iter().zip(y.iter()) {
+                    assert!((e - p).abs() <= 1.0e-9, "expected {}, produced {}", e, p);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn denormal_protection() {
+        // A second of loud input, followed by minutes of silence. Without
+        // flushing, the filter state decays into subnormal numbers within a
+        // few seconds, which are far slower to calculate with on most CPUs.
+        const SAMPLE_RATE: u32 = 48000;
+        const TRANSIENT_FRAMES: usize = SAMPLE_RATE as usize;
+        const SILENT_FRAMES: usize = 3 * 60 * SAMPLE_RATE as usize;
+
+        let transient = (0..TRANSIENT_FRAMES)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                [(2.0 * PI * 997.0 * t).sin(), (2.0 * PI * 40.0 * t).sin()]
+            })
+            .collect::<Vec<_>>();
+
+        let mut flushed = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+        let mut kept = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+        kept.set_denormals(Denormals::Keep);
+
+        let has_subnormals = |filter: &KWeightFilter<[f64; 2], 2>| {
+            filter.state().iter().flatten().any(|s| s.is_subnormal())
+        };
+
+        // Flushing only touches values far below anything audible, so the
+        // output matches that of the unprotected filter throughout.
+        let silence = core::iter::repeat([0.0; 2]).take(SILENT_FRAMES);
+        let mut kept_subnormals = false;
+
+        for frame in transient.iter().copied().chain(silence) {
+            let e = kept.process(frame);
+            let p = flushed.process(frame);
+
+            for (e, p) in e.iter().zip(p.iter()) {
+                assert!((e - p).abs() <= 1.0e-14, "expected {}, produced {}", e, p);
+            }
+
+            kept_subnormals |= has_subnormals(&kept);
+            assert!(!has_subnormals(&flushed));
+        }
+
+        // Without protection, the state does become subnormal.
+        assert!(kept_subnormals);
+
+        // The state has been flushed all the way to zero, so the filter is
+        // back where it started.
+        assert!(flushed.state().iter().flatten().all(|&s| s == 0.0));
+
+        let mut fresh = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+        for frame in transient.iter() {
+            assert_eq!(fresh.process(*frame), flushed.process(*frame));
+        }
+    }
+
+    #[test]
+    #[ignore = "timing benchmark; run with --ignored --nocapture"]
+    fn denormal_protection_timing() {
+        use std::time::Instant;
+
+        // Times minutes of silence after a transient, with and without
+        // flushing. On most CPUs the unprotected filter is many times slower,
+        // as its state decays into subnormal numbers.
+        const SAMPLE_RATE: u32 = 48000;
+        const TRANSIENT_FRAMES: usize = SAMPLE_RATE as usize;
+        const SILENT_FRAMES: usize = 5 * 60 * SAMPLE_RATE as usize;
+
+        let transient = (0..TRANSIENT_FRAMES)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                [(2.0 * PI * 997.0 * t).sin(), (2.0 * PI * 40.0 * t).sin()]
+            })
+            .collect::<Vec<_>>();
+
+        let time_silence = |denormals: Denormals| {
+            let mut filter = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+            filter.set_denormals(denormals);
+
+            for frame in transient.iter() {
+                filter.process(*frame);
+            }
+
+            let start = Instant::now();
+            let mut sum = 0.0;
+            for _ in 0..SILENT_FRAMES {
+                sum += filter.process([0.0; 2])[0];
+            }
+
+            (start.elapsed(), sum)
+        };
+
+        let (flush_time, flush_sum) = time_silence(Denormals::Flush);
+        let (keep_time, keep_sum) = time_silence(Denormals::Keep);
+
+        println!("silence with Flush: {:?}, with Keep: {:?}", flush_time, keep_time);
+
+        assert!((flush_sum - keep_sum).abs() <= 1.0e-9);
+        assert!(flush_time <= keep_time, "flush took {:?}, keep took {:?}", flush_time, keep_time);
+    }
+
+    #[test]
+    fn non_finite_input() {
+        use crate::non_finite::{NonFinite, NonFiniteError};
+
+        const SAMPLE_RATE: u32 = 48000;
+
+        let frames = (0..1000)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                [(2.0 * PI * 997.0 * t).sin(), (2.0 * PI * 60.0 * t).sin()]
+            })
+            .collect::<Vec<_>>();
+
+        let mut zeroed = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+        let mut rejected = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+        let mut reference = KWeightFilter::<[f64; 2], 2>::new(SAMPLE_RATE);
+
+        rejected.set_non_finite(NonFinite::Reject);
+
+        for (i, frame) in frames.iter().enumerate() {
+            if i == 500 {
+                let bad = [f64::NAN, frame[1]];
+
+                assert_eq!(zeroed.process(bad), reference.process([0.0, frame[1]]));
+                assert_eq!(rejected.try_process(bad), Err(NonFiniteError { frame: 500, channel: 0 }));
+            }
+            else {
+                assert_eq!(zeroed.process(*frame), reference.process(*frame));
+                rejected.process(*frame);
+            }
+        }
+
+        assert_eq!(zeroed.non_finite_counts().samples, 1);
+        assert_eq!(zeroed.non_finite_counts().first, Some(500));
+        assert_eq!(rejected.non_finite_counts().samples, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "filters cannot skip frames")]
+    fn non_finite_skip() {
+        use crate::non_finite::NonFinite;
+
+        KWeightFilter::<[f64; 2], 2>::new(48000).set_non_finite(NonFinite::Skip);
+    }
+
+    #[test]
+    fn matches_reference_biquads() {
+        use sampara::biquad::Biquad;
+
+        // The filter sections are computed in transposed direct form II, which
+        // rounds differently from the reference biquads. The difference is
+        // measured in ulps of the peak output level of each channel, since
+        // relative error is meaningless near the zero crossings.
+        const SAMPLE_RATE: u32 = 48000;
+
+        let mut k_filter = KWeightFilter::<[f64; 6], 6>::new(SAMPLE_RATE);
+        let mut ref_shelving = Biquad::<[f64; 6], 6>::from(Kind::Shelving.coefficients(SAMPLE_RATE));
+        let mut ref_highpass = Biquad::<[f64; 6], 6>::from(Kind::HighPass.coefficients(SAMPLE_RATE));
+
+        let mut peaks = [0.0f64; 6];
+        let mut max_diffs = [0.0f64; 6];
+
+        for i in 0..48000 {
+            let t = i as f64 / SAMPLE_RATE as f64;
+            let input = [
+                (2.0 * PI * 997.0 * t).sin(),
+                (2.0 * PI * 60.0 * t).sin(),
+                (2.0 * PI * 5000.0 * t).sin() * 0.5,
+                (2.0 * PI * 20.0 * t).sin() * 0.25,
+                if i == 0 { 1.0 } else { 0.0 },
+                0.0,
+            ];
+
+            let produced = k_filter.process(input);
+            let expected = ref_highpass.process(ref_shelving.process(input));
+
+            for (ch, (e, p)) in expected.iter().zip(produced.iter()).enumerate() {
+                peaks[ch] = peaks[ch].max(e.abs());
+                max_diffs[ch] = max_diffs[ch].max((e - p).abs());
+            }
+        }
+
+        for ch in 0..6 {
+            if peaks[ch] == 0.0 {
+                assert_eq!(max_diffs[ch], 0.0);
+                continue;
+            }
+
+            let ulps = max_diffs[ch] / (peaks[ch] * f64::EPSILON);
+            assert!(
+                ulps <= biquad::MAX_ULPS,
+                "channel {} differs by {} ulps of its peak level", ch, ulps,
+            );
+        }
+    }
+}
+
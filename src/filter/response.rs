@@ -0,0 +1,250 @@
+//! Frequency response analysis of weighting filters.
+
+use core::f64::consts::PI;
+use core::ops::{Div, Mul};
+
+use sampara::biquad::Params;
+
+use crate::math;
+
+use super::{check_weighting_sample_rate, Kind, UnsupportedSampleRate, Weighting, MAX_SECTIONS};
+
+/// A complex number, for evaluating transfer functions.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    const ONE: Self = Self { re: 1.0, im: 0.0 };
+
+    fn new(re: f64, im: f64) -> Self {
+        Self { re, im }
+    }
+
+    pub(crate) fn norm_sq(self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+
+    fn arg(self) -> f64 {
+        math::atan2(self.im, self.re)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Self;
+
+    fn div(self, other: Self) -> Self {
+        let den = other.norm_sq();
+
+        Self::new(
+            (self.re * other.re + self.im * other.im) / den,
+            (self.im * other.re - self.re * other.im) / den,
+        )
+    }
+}
+
+/// Evaluates the transfer function of a single biquad section at `freq` Hz.
+pub(crate) fn section_response(params: &Params<f64>, freq: f64, sample_rate: u32) -> Complex {
+    // Each delay of one sample is a rotation of `-w` radians.
+    let w = 2.0 * PI * freq / sample_rate as f64;
+    let z_1 = Complex::new(math::cos(w), -math::sin(w));
+    let z_2 = Complex::new(math::cos(2.0 * w), -math::sin(2.0 * w));
+
+    let num = Complex::new(
+        params.b0 + params.b1 * z_1.re + params.b2 * z_2.re,
+        params.b1 * z_1.im + params.b2 * z_2.im,
+    );
+    let den = Complex::new(
+        1.0 + params.a1 * z_1.re + params.a2 * z_2.re,
+        params.a1 * z_1.im + params.a2 * z_2.im,
+    );
+
+    num / den
+}
+
+/// The response of a filter at a single frequency.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Response {
+    /// The frequency, in Hz.
+    pub freq: f64,
+
+    /// The gain, in dB.
+    pub magnitude: f64,
+
+    /// The phase shift, in radians, from -π to π.
+    pub phase: f64,
+}
+
+impl Response {
+    fn from_complex(freq: f64, h: Complex) -> Self {
+        Self {
+            freq,
+            magnitude: 10.0 * math::log10(h.norm_sq()),
+            phase: h.arg(),
+        }
+    }
+}
+
+/// Calculates the frequency response of a weighting filter at a given sample
+/// rate, by evaluating the transfer function of its cascaded biquad sections.
+#[derive(Debug, Clone)]
+pub struct FrequencyResponse {
+    sections: [Option<Params<f64>>; MAX_SECTIONS],
+    sample_rate: u32,
+}
+
+impl FrequencyResponse {
+    /// Calculates the response of a weighting at the given sample rate.
+    ///
+    /// Panics if the sample rate is not supported by the weighting.
+    pub fn new<W>(sample_rate: u32, weighting: &W) -> Self
+    where
+        W: Weighting + ?Sized,
+    {
+        match Self::try_new(sample_rate, weighting) {
+            Ok(response) => response,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Calculates the response of a weighting at the given sample rate, or
+    /// returns an error if the sample rate is not supported by the weighting.
+    pub fn try_new<W>(sample_rate: u32, weighting: &W) -> Result<Self, UnsupportedSampleRate>
+    where
+        W: Weighting + ?Sized,
+    {
+        check_weighting_sample_rate(sample_rate, weighting)?;
+
+        Ok(Self::from_sections(sample_rate, weighting.sections(sample_rate)))
+    }
+
+    pub fn from_sections(sample_rate: u32, sections: [Option<Params<f64>>; MAX_SECTIONS]) -> Self {
+        Self { sections, sample_rate }
+    }
+
+    /// The response at `freq` Hz, which should be below the Nyquist
+    /// frequency.
+    pub fn at(&self, freq: f64) -> Response {
+        let h = self.sections.iter()
+            .flatten()
+            .fold(Complex::ONE, |h, p| h * section_response(p, freq, self.sample_rate));
+
+        Response::from_complex(freq, h)
+    }
+
+    /// The response at each frequency of a grid, such as one from
+    /// [`log_frequencies`].
+    pub fn over<'a, I>(&'a self, freqs: I) -> impl Iterator<Item = Response> + 'a
+    where
+        I: IntoIterator<Item = f64>,
+        I::IntoIter: 'a,
+    {
+        freqs.into_iter().map(move |f| self.at(f))
+    }
+
+    /// Compares the magnitude response against the analog K-weighting curve
+    /// from [`k_weighting_target`] over a grid of frequencies, and returns the
+    /// largest difference, in dB.
+    pub fn max_deviation_from_k<I>(&self, freqs: I) -> f64
+    where
+        I: IntoIterator<Item = f64>,
+    {
+        freqs.into_iter()
+            .map(|f| (self.at(f).magnitude - k_weighting_target(f).magnitude).abs())
+            .fold(0.0, f64::max)
+    }
+}
+
+/// The response of the analog K-weighting curve at `freq` Hz, which the
+/// digital filters approximate at every sample rate.
+pub fn k_weighting_target(freq: f64) -> Response {
+    // Each analog stage is evaluated in terms of the frequency relative to
+    // its corner frequency.
+    let stage = |kind: Kind, num: &dyn Fn(f64, f64) -> Complex| {
+        let (f0, q) = kind.prototype();
+        let x = freq / f0;
+
+        num(x, q) / Complex::new(1.0 - x * x, x / q)
+    };
+
+    let (vh, vb) = Kind::shelf_gains();
+
+    let shelving = stage(Kind::Shelving, &|x, q| Complex::new(1.0 - vh * x * x, vb * x / q));
+    let high_pass = stage(Kind::HighPass, &|x, _| Complex::new(-x * x, 0.0));
+
+    Response::from_complex(freq, shelving * high_pass)
+}
+
+/// Returns `count` frequencies spaced evenly on a logarithmic scale, from
+/// `start` to `end` Hz inclusive.
+pub fn log_frequencies(start: f64, end: f64, count: usize) -> impl Iterator<Item = f64> {
+    assert!(start > 0.0 && end > start, "invalid frequency range");
+    assert!(count >= 2, "at least two frequencies are needed");
+
+    let ratio = end / start;
+    let steps = (count - 1) as f64;
+
+    (0..count).map(move |i| start * math::powf(ratio, i as f64 / steps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::filter::{CoefficientSource, KWeighting, StandardWeighting};
+
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn k_weighting() {
+        // The deviation comes from the bilinear transform, and shrinks as the
+        // sample rate goes up.
+        let expected = [(44100, 0.06), (48000, 0.06), (96000, 0.03), (192000, 0.015)];
+
+        for &(sample_rate, max_deviation) in expected.iter() {
+            let response = FrequencyResponse::new(sample_rate, &StandardWeighting::K);
+            let deviation = response.max_deviation_from_k(log_frequencies(20.0, 20000.0, 200));
+
+            assert!(deviation < max_deviation, "{} Hz: {} dB", sample_rate, deviation);
+        }
+
+        let response = FrequencyResponse::new(48000, &KWeighting(CoefficientSource::SpecExact));
+        assert!(response.max_deviation_from_k(log_frequencies(20.0, 20000.0, 200)) < 0.06);
+
+        // The shelving stage boosts high frequencies by about 4 dB, and the
+        // high pass stage cuts low frequencies.
+        let produced = response.at(997.0);
+        let expected = k_weighting_target(997.0);
+        assert_abs_diff_eq!(produced.magnitude, expected.magnitude, epsilon = 0.05);
+        assert_abs_diff_eq!(produced.phase, expected.phase, epsilon = 0.01);
+
+        assert!(response.at(10000.0).magnitude > 3.5);
+        assert!(response.at(20.0).magnitude < -10.0);
+    }
+
+    #[test]
+    fn flat() {
+        let response = FrequencyResponse::new(48000, &StandardWeighting::Z);
+
+        for r in response.over(log_frequencies(10.0, 20000.0, 50)) {
+            assert_eq!(r.magnitude, 0.0);
+            assert_eq!(r.phase, 0.0);
+        }
+
+        let freqs = log_frequencies(10.0, 1000.0, 3).collect::<Vec<_>>();
+        assert_abs_diff_eq!(freqs[1], 100.0, epsilon = 1e-9);
+    }
+}
@@ -0,0 +1,110 @@
+//! Multichannel state variable filter sections.
+//!
+//! These have the same transfer function as a biquad designed with the
+//! prewarped bilinear transform, but are built from two trapezoidal
+//! integrators instead of a direct form. When the corner frequency is tiny
+//! compared to the sample rate, a biquad's poles crowd in on the unit circle
+//! and its state has to cancel out large values, while the integrator states
+//! here stay close to the size of the signal.
+
+use crate::util::Util;
+
+use super::Prototype;
+
+/// A second-order section in state variable form, with state for each of `N`
+/// channels. The output is a mix of the high pass, band pass and low pass
+/// responses.
+#[derive(Clone, Debug)]
+pub(crate) struct SvfSection<const N: usize> {
+    a1: f64,
+    a2: f64,
+    a3: f64,
+
+    m_high: f64,
+    m_band: f64,
+    m_low: f64,
+
+    ic1: [f64; N],
+    ic2: [f64; N],
+}
+
+impl<const N: usize> From<Prototype> for SvfSection<N> {
+    fn from(prototype: Prototype) -> Self {
+        Self::new(prototype.g, prototype.k, prototype.numerator)
+    }
+}
+
+impl<const N: usize> SvfSection<N> {
+    /// Creates a section with a prewarped corner frequency of `g` (the
+    /// tangent of the corner frequency over the sample rate, times π) and a
+    /// damping of `k` (the reciprocal of Q). The numerator is given as the
+    /// coefficients of `s^2`, `s` and `1` in the analog prototype, whose
+    /// denominator is `s^2 + k s + 1`.
+    fn new(g: f64, k: f64, numerator: [f64; 3]) -> Self {
+        let [c2, c1, c0] = numerator;
+
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        Self {
+            a1,
+            a2,
+            a3,
+
+            // The input itself is the sum of the three responses, with the
+            // band pass scaled by `k`.
+            m_high: c2,
+            m_band: c1 - c2 * k,
+            m_low: c0 - c2,
+
+            ic1: [0.0; N],
+            ic2: [0.0; N],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.ic1 = [0.0; N];
+        self.ic2 = [0.0; N];
+    }
+
+    /// Returns the per-channel integrator state.
+    #[cfg(feature = "alloc")]
+    pub fn state(&self) -> [[f64; N]; 2] {
+        [self.ic1, self.ic2]
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn set_state(&mut self, state: [[f64; N]; 2]) {
+        let [ic1, ic2] = state;
+        self.ic1 = ic1;
+        self.ic2 = ic2;
+    }
+
+    /// Flushes state values that are too small to affect the output to zero,
+    /// so that they never decay into subnormal numbers.
+    #[inline]
+    pub fn flush_denormals(&mut self) {
+        for s in self.ic1.iter_mut().chain(self.ic2.iter_mut()) {
+            *s = Util::den(*s);
+        }
+    }
+
+    /// Filters one frame in place.
+    #[inline]
+    pub fn process(&mut self, x: &mut [f64; N]) {
+        let Self { a1, a2, a3, m_high, m_band, m_low, .. } = *self;
+
+        for ((x, ic1), ic2) in x.iter_mut().zip(self.ic1.iter_mut()).zip(self.ic2.iter_mut()) {
+            let v0 = *x;
+            let v3 = v0 - *ic2;
+            let v1 = a1 * *ic1 + a2 * v3;
+            let v2 = *ic2 + a2 * *ic1 + a3 * v3;
+
+            *ic1 = 2.0 * v1 - *ic1;
+            *ic2 = 2.0 * v2 - *ic2;
+
+            *x = m_high * v0 + m_band * v1 + m_low * v2;
+        }
+    }
+}
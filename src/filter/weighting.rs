@@ -0,0 +1,386 @@
+//! Frequency weightings other than K-weighting, and a trait for choosing
+//! between them.
+
+use core::f64::consts::PI;
+
+use sampara::biquad::Params;
+
+use crate::math;
+
+use super::{section_response, CoefficientSource, Kind, MIN_SAMPLE_RATE};
+
+/// The largest number of biquad sections that any weighting filter is made
+/// up of.
+pub const MAX_SECTIONS: usize = 3;
+
+// The pole frequencies of the A- and C-weighting curves, as given in
+// IEC 61672-1.
+const POLE_1: f64 = 20.598997;
+const POLE_2: f64 = 107.65265;
+const POLE_3: f64 = 737.86223;
+const POLE_4: f64 = 12194.217;
+
+// The poles of the ITU-R 468 curve, found from the roots of its analog
+// transfer function. There are two real poles, given in Hz, and two complex
+// conjugate pairs, given in Hz along with their Q factors.
+const M_REAL_POLES: (f64, f64) = (4122.702066134755, 9975.063123930307);
+const M_COMPLEX_POLES: [(f64, f64); 2] = [
+    (6902.979916595886, 0.9183086810302059),
+    (10378.805120137564, 1.7395656512262312),
+];
+
+// The lowest whole sample rate that is more than twice the frequency of the
+// highest pole of the ITU-R 468 curve.
+const M_MIN_SAMPLE_RATE: u32 = 20758;
+
+// The gain of M-weighting at the normalization frequency, in dB. The ITU-R 468
+// curve has unity gain at 1 kHz, and M-weighting lowers it so that it is close
+// to unity at 2 kHz instead.
+const M_OFFSET: f64 = -5.6;
+
+// A-, C- and M-weighting are normalized at this frequency.
+const NORM_FREQ: f64 = 1000.0;
+
+/// The analog prototype of a second-order section, which its coefficients
+/// are designed from with the prewarped bilinear transform. The transfer
+/// function is `(c2 s^2 + c1 s + c0) / (s^2 + k s + 1)`, with `s` relative to
+/// the prewarped corner frequency.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Prototype {
+    /// The prewarped corner frequency, which is `tan(π f0 / fs)`.
+    pub g: f64,
+
+    /// The damping, which is the reciprocal of Q.
+    pub k: f64,
+
+    /// The numerator, as `[c2, c1, c0]`.
+    pub numerator: [f64; 3],
+}
+
+/// A frequency weighting, made up of a cascade of biquad sections whose
+/// coefficients depend on the sample rate.
+pub trait Weighting {
+    /// Calculates the coefficients of each section, in the order that they
+    /// should be applied. Unused sections are `None`.
+    fn sections(&self, sample_rate: u32) -> [Option<Params<f64>>; MAX_SECTIONS];
+
+    /// The analog prototype of each section at a sample rate, in the same
+    /// order as [`Self::sections`], for weightings whose sections are
+    /// designed with the prewarped bilinear transform. Above 192 kHz, filters
+    /// build state variable sections straight from these, which keep their
+    /// precision where biquads lose it. Weightings that return `None`, as
+    /// they do by default, use biquads at every sample rate.
+    fn prototypes(&self, sample_rate: u32) -> Option<[Option<Prototype>; MAX_SECTIONS]> {
+        let _ = sample_rate;
+        None
+    }
+
+    /// The lowest sample rate that the weighting can be used at. This is
+    /// [`MIN_SAMPLE_RATE`] unless part of the curve lies too close to the
+    /// Nyquist frequency at lower rates, and [`Self::sections`] may panic
+    /// below it.
+    fn min_sample_rate(&self) -> u32 {
+        MIN_SAMPLE_RATE
+    }
+}
+
+/// K-weighting with a choice of coefficient source. This is the same as
+/// [`StandardWeighting::K`] when using derived coefficients.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct KWeighting(pub CoefficientSource);
+
+impl Weighting for KWeighting {
+    fn sections(&self, sample_rate: u32) -> [Option<Params<f64>>; MAX_SECTIONS] {
+        [
+            Some(Kind::Shelving.coefficients_from(sample_rate, self.0)),
+            Some(Kind::HighPass.coefficients_from(sample_rate, self.0)),
+            None,
+        ]
+    }
+
+    fn prototypes(&self, sample_rate: u32) -> Option<[Option<Prototype>; MAX_SECTIONS]> {
+        Some([
+            Some(Kind::Shelving.analog(sample_rate)),
+            Some(Kind::HighPass.analog(sample_rate)),
+            None,
+        ])
+    }
+}
+
+/// The weightings provided by this crate, which can be selected at runtime.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StandardWeighting {
+    /// K-weighting, as described in ITU BS.1770.
+    K,
+
+    /// A-weighting, as described in IEC 61672-1.
+    A,
+
+    /// C-weighting, as described in IEC 61672-1.
+    C,
+
+    /// Z-weighting, which leaves the signal unchanged.
+    Z,
+
+    /// The revised low-frequency B-curve (RLB) on its own, which is the
+    /// weighting used by ITU BS.1770-1.
+    Rlb,
+
+    /// M-weighting, which is the ITU-R 468 curve lowered by 5.6 dB, as used
+    /// for Leq(m). This needs a sample rate of at least 20758 Hz, just above
+    /// twice the frequency of its highest pole. At 44.1 and 48 kHz, it falls off
+    /// faster than the analog curve above about 7 kHz.
+    M,
+}
+
+impl Default for StandardWeighting {
+    fn default() -> Self {
+        Self::K
+    }
+}
+
+impl StandardWeighting {
+    // Designs each section, along with the analog prototype that it comes
+    // from.
+    fn designs(&self, sample_rate: u32) -> [Option<Design>; MAX_SECTIONS] {
+        match self {
+            Self::K => [
+                Some(Kind::Shelving.design(sample_rate)),
+                Some(Kind::HighPass.design(sample_rate)),
+                None,
+            ],
+            Self::A => normalize([
+                Some(double_pole(POLE_1, sample_rate, true)),
+                Some(pole_pair(POLE_2, POLE_3, sample_rate)),
+                low_pass(sample_rate),
+            ], sample_rate, 0.0),
+            Self::C => normalize([
+                Some(double_pole(POLE_1, sample_rate, true)),
+                low_pass(sample_rate),
+                None,
+            ], sample_rate, 0.0),
+            Self::Z => [None, None, None],
+            Self::Rlb => [
+                Some(Kind::HighPass.design(sample_rate)),
+                None,
+                None,
+            ],
+            Self::M => {
+                let [(f1, q1), (f2, q2)] = M_COMPLEX_POLES;
+
+                assert!(
+                    sample_rate >= M_MIN_SAMPLE_RATE && 2.0 * f2 < sample_rate as f64,
+                    "M-weighting needs a sample rate of at least {} Hz", M_MIN_SAMPLE_RATE,
+                );
+
+                // The single zero at DC goes with the real poles, and the
+                // zeros at infinity become zeros at the Nyquist frequency.
+                let (r1, r2) = M_REAL_POLES;
+                let (a0, a1, a2, g, k) = real_poles(r1, r2, sample_rate);
+                let band_pass = (
+                    Params { a1, a2, b0: 1.0 / a0, b1: 0.0, b2: -1.0 / a0, },
+                    Prototype { g, k, numerator: [0.0, 1.0 / g, 0.0] },
+                );
+
+                normalize([
+                    Some(band_pass),
+                    Some(low_pass_pair(f1, q1, sample_rate)),
+                    Some(low_pass_pair(f2, q2, sample_rate)),
+                ], sample_rate, M_OFFSET)
+            },
+        }
+    }
+}
+
+impl Weighting for StandardWeighting {
+    fn sections(&self, sample_rate: u32) -> [Option<Params<f64>>; MAX_SECTIONS] {
+        self.designs(sample_rate).map(|d| d.map(|(params, _)| params))
+    }
+
+    fn prototypes(&self, sample_rate: u32) -> Option<[Option<Prototype>; MAX_SECTIONS]> {
+        Some(self.designs(sample_rate).map(|d| d.map(|(_, prototype)| prototype)))
+    }
+
+    fn min_sample_rate(&self) -> u32 {
+        match self {
+            Self::M => M_MIN_SAMPLE_RATE,
+            _ => MIN_SAMPLE_RATE,
+        }
+    }
+}
+
+// The coefficients of a section, along with its analog prototype.
+pub(super) type Design = (Params<f64>, Prototype);
+
+// The denominator of a second-order section with the given corner frequency
+// and Q, using the same prewarped bilinear transform as `Kind::coefficients`.
+// Returns the prewarped frequency, along with `a0`, `a1` and `a2`.
+fn denominator(f0: f64, q: f64, sample_rate: u32) -> (f64, f64, f64, f64) {
+    let k = math::tan(PI * f0 / sample_rate as f64);
+    let k_by_q = k / q;
+    let k_sq = k * k;
+
+    let a0 = 1.0 + k_by_q + k_sq;
+    let a1 = 2.0 * (k_sq - 1.0) / a0;
+    let a2 = (1.0 - k_by_q + k_sq) / a0;
+
+    (k, a0, a1, a2)
+}
+
+// Two coincident real poles at `f0`, as either a high pass or a low pass.
+fn double_pole(f0: f64, sample_rate: u32, high_pass: bool) -> Design {
+    if !high_pass {
+        return low_pass_pair(f0, 0.5, sample_rate);
+    }
+
+    let (g, a0, a1, a2) = denominator(f0, 0.5, sample_rate);
+
+    (
+        Params { a1, a2, b0: 1.0 / a0, b1: -2.0 / a0, b2: 1.0 / a0, },
+        Prototype { g, k: 2.0, numerator: [1.0, 0.0, 0.0] },
+    )
+}
+
+// A second-order low pass with the given corner frequency and Q.
+fn low_pass_pair(f0: f64, q: f64, sample_rate: u32) -> Design {
+    let (k, a0, a1, a2) = denominator(f0, q, sample_rate);
+    let k_sq = k * k;
+
+    (
+        Params { a1, a2, b0: k_sq / a0, b1: 2.0 * k_sq / a0, b2: k_sq / a0, },
+        Prototype { g: k, k: 1.0 / q, numerator: [0.0, 0.0, 1.0] },
+    )
+}
+
+// The double low pass pole of A- and C-weighting. This is left out if it lies
+// above the Nyquist frequency, since the bilinear transform cannot place it
+// there.
+fn low_pass(sample_rate: u32) -> Option<Design> {
+    if 2.0 * POLE_4 < sample_rate as f64 { Some(double_pole(POLE_4, sample_rate, false)) }
+    else { None }
+}
+
+// The denominator of a section with two real poles at `f1` and `f2`. Returns
+// `a0`, `a1` and `a2`, along with the prewarped corner frequency and damping
+// of the combined prototype, whose corner lies between the two poles.
+fn real_poles(f1: f64, f2: f64, sample_rate: u32) -> (f64, f64, f64, f64, f64) {
+    let k1 = math::tan(PI * f1 / sample_rate as f64);
+    let k2 = math::tan(PI * f2 / sample_rate as f64);
+
+    let a0 = (1.0 + k1) * (1.0 + k2);
+    let a1 = ((k1 - 1.0) * (1.0 + k2) + (k2 - 1.0) * (1.0 + k1)) / a0;
+    let a2 = (k1 - 1.0) * (k2 - 1.0) / a0;
+
+    let g = math::powf(k1 * k2, 0.5);
+
+    (a0, a1, a2, g, (k1 + k2) / g)
+}
+
+// Two first-order high passes at `f1` and `f2`, combined into one section.
+fn pole_pair(f1: f64, f2: f64, sample_rate: u32) -> Design {
+    let (a0, a1, a2, g, k) = real_poles(f1, f2, sample_rate);
+
+    (
+        Params { a1, a2, b0: 1.0 / a0, b1: -2.0 / a0, b2: 1.0 / a0, },
+        Prototype { g, k, numerator: [1.0, 0.0, 0.0] },
+    )
+}
+
+// Calculates the magnitude response of a single section at `freq` Hz.
+fn magnitude(params: &Params<f64>, freq: f64, sample_rate: u32) -> f64 {
+    math::powf(section_response(params, freq, sample_rate).norm_sq(), 0.5)
+}
+
+// Scales the first section so that the whole cascade has a gain of `gain_db`
+// at the normalization frequency.
+fn normalize(
+    mut sections: [Option<Design>; MAX_SECTIONS],
+    sample_rate: u32,
+    gain_db: f64,
+) -> [Option<Design>; MAX_SECTIONS]
+{
+    let gain = sections.iter()
+        .flatten()
+        .map(|(p, _)| magnitude(p, NORM_FREQ, sample_rate))
+        .product::<f64>()
+        / math::powf(10.0, gain_db / 20.0);
+
+    if let Some((first, prototype)) = sections[0].as_mut() {
+        first.b0 /= gain;
+        first.b1 /= gain;
+        first.b2 /= gain;
+
+        for c in prototype.numerator.iter_mut() {
+            *c /= gain;
+        }
+    }
+
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use approx::assert_abs_diff_eq;
+
+    fn response_db(weighting: StandardWeighting, freq: f64, sample_rate: u32) -> f64 {
+        let gain = weighting.sections(sample_rate)
+            .iter()
+            .flatten()
+            .map(|p| magnitude(p, freq, sample_rate))
+            .product::<f64>();
+
+        20.0 * math::log10(gain)
+    }
+
+    #[test]
+    fn nominal_responses() {
+        // Nominal values from IEC 61672-1. The bilinear transform compresses
+        // the response towards the Nyquist frequency, so the higher
+        // frequencies are off by up to about 0.75 dB, which is well within
+        // the class 1 tolerances.
+        let expected = [
+            (31.5, -39.4, -3.0),
+            (100.0, -19.1, -0.3),
+            (1000.0, 0.0, 0.0),
+            (4000.0, 1.0, -0.8),
+            (10000.0, -2.5, -4.4),
+        ];
+
+        for &sample_rate in &[44100, 48000, 96000] {
+            for &(freq, a, c) in expected.iter() {
+                assert_abs_diff_eq!(response_db(StandardWeighting::A, freq, sample_rate), a, epsilon = 0.8);
+                assert_abs_diff_eq!(response_db(StandardWeighting::C, freq, sample_rate), c, epsilon = 0.8);
+            }
+
+            assert_abs_diff_eq!(response_db(StandardWeighting::A, 1000.0, sample_rate), 0.0, epsilon = 1e-9);
+            assert_abs_diff_eq!(response_db(StandardWeighting::Z, 50.0, sample_rate), 0.0, epsilon = 1e-9);
+        }
+
+        // The RLB curve cuts low frequencies, without the high frequency
+        // boost of K-weighting.
+        assert!(response_db(StandardWeighting::Rlb, 20.0, 48000) < -10.0);
+        assert_abs_diff_eq!(response_db(StandardWeighting::Rlb, 5000.0, 48000), 0.0, epsilon = 0.1);
+        assert!(response_db(StandardWeighting::K, 5000.0, 48000) > 3.0);
+    }
+
+    #[test]
+    fn m_weighting() {
+        // Nominal values of the ITU-R 468 curve, up to its peak.
+        let expected = [
+            (31.5, -29.9), (100.0, -19.8), (400.0, -7.8), (1000.0, 0.0),
+            (2000.0, 5.6), (4000.0, 10.5), (6300.0, 12.2),
+        ];
+
+        for &sample_rate in &[44100, 48000, 96000] {
+            for &(freq, gain) in expected.iter() {
+                assert_abs_diff_eq!(
+                    response_db(StandardWeighting::M, freq, sample_rate),
+                    gain + M_OFFSET,
+                    epsilon = 0.1,
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Fixed-point gated block powers, for use with the output of
+//! [`Q31KWeightFilter`](crate::filter::Q31KWeightFilter).
+//!
+//! Squared samples are rounded to Q40 and summed in 64-bit unsigned
+//! accumulators, first per sub-block and then per gated block, so no precision
+//! is lost once a sample has been squared. A signal at -70 LKFS still has over
+//! 10^5 quanta of power per sample, so the rounding of each squared sample
+//! changes the loudness of a block by far less than 0.001 LU.
+//!
+//! Each frame of full scale samples adds about 2^40 to a sum, so the
+//! accumulators have room for gates of up to 2^24 frames, or 2^20 frames if
+//! the samples use all of the Q29 headroom. That still covers the short-term
+//! gate at 192 kHz, and sums saturate instead of wrapping beyond it.
+//!
+//! The lengths of the gate and its sub-blocks are worked out in floating
+//! point, but only once, when [`Q29GatedPowers`] is created. On targets without
+//! a floating point unit this goes through software floating point, and
+//! processing itself only uses integer arithmetic.
+
+use sampara::{Processor, StatefulProcessor};
+
+use crate::filter::{check_sample_rate, UnsupportedSampleRate, Q29_SHIFT};
+use crate::gated_loudness::{FractionalStep, Frames, Gating};
+
+/// The number of fractional bits in the powers produced by
+/// [`Q29GatedPowers`].
+pub const Q40_SHIFT: u32 = 40;
+
+// The shift from the Q58 square of a Q29 sample down to Q40.
+const SQUARE_SHIFT: u32 = 2 * Q29_SHIFT - Q40_SHIFT;
+
+/// A fixed-point version of [`FixedGatedPowers`](crate::FixedGatedPowers),
+/// which takes frames of Q29 samples, such as the output of
+/// [`Q31KWeightFilter`](crate::filter::Q31KWeightFilter), and produces the
+/// powers of each gated block in Q40. Like [`FixedGatedPowers`], it never
+/// allocates, and its ring buffer holds up to `BLOCKS` sub-blocks.
+///
+/// [`FixedGatedPowers`]: crate::FixedGatedPowers
+#[derive(Clone, Debug)]
+pub struct Q29GatedPowers<const N: usize, const BLOCKS: usize> {
+    sub_block: FractionalStep,
+    count: usize,
+    sum: [u64; N],
+
+    ring: [[u64; N]; BLOCKS],
+    len: usize,
+    pos: usize,
+    filled: usize,
+
+    step: FractionalStep,
+    since: usize,
+
+    gate_len: u64,
+    current: Option<[u64; N]>,
+}
+
+impl<const N: usize, const BLOCKS: usize> Q29GatedPowers<N, BLOCKS> {
+    /// Creates a new instance. This panics if the gate needs more than
+    /// `BLOCKS` sub-blocks, or if the sample rate is not supported.
+    pub fn new(sample_rate: u32, gating: Gating) -> Self {
+        match Self::try_new(sample_rate, gating) {
+            Ok(powers) => powers,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates a new instance, or returns an error if the sample rate is not
+    /// supported. This still panics if the gate needs more than `BLOCKS`
+    /// sub-blocks.
+    pub fn try_new(sample_rate: u32, gating: Gating) -> Result<Self, UnsupportedSampleRate> {
+        check_sample_rate(sample_rate)?;
+
+        let (gate_len, step) = gating.frames(sample_rate);
+        let sub_block = gating.sub_block(sample_rate);
+
+        let len = Frames::from(gate_len).count(sub_block) as usize;
+
+        // The step is only a fractional number of sub-blocks when sub-blocks
+        // are a single frame long.
+        let step = if sub_block == Frames::whole(1) { step }
+        else { Frames::whole(step.count(sub_block)) };
+
+        assert!(len <= BLOCKS, "ring buffer too small for gate");
+
+        Ok(Self {
+            sub_block: FractionalStep::new(sub_block),
+            count: 0,
+            sum: [0; N],
+            ring: [[0; N]; BLOCKS],
+            len,
+            pos: 0,
+            filled: 0,
+            step: FractionalStep::new(step),
+            since: 0,
+            gate_len: gate_len as u64,
+            current: None,
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.sub_block.reset();
+        self.count = 0;
+        self.sum = [0; N];
+        self.ring = [[0; N]; BLOCKS];
+        self.pos = 0;
+        self.filled = 0;
+        self.since = 0;
+        self.step.reset();
+        self.current = None;
+    }
+
+    pub fn momentary(sample_rate: u32) -> Self {
+        Self::new(sample_rate, Gating::Momentary)
+    }
+
+    pub fn shortterm(sample_rate: u32) -> Self {
+        Self::new(sample_rate, Gating::Shortterm)
+    }
+
+    pub fn process(&mut self, input: [i32; N]) -> Option<[u64; N]> {
+        Processor::process(self, input)
+    }
+
+    /// Converts Q40 powers to floating point, for use with
+    /// [`Loudness`](crate::Loudness) or [`Util::lufs`](crate::util::Util::lufs).
+    pub fn to_float(powers: [u64; N]) -> [f64; N] {
+        powers.map(|p| p as f64 / (1u64 << Q40_SHIFT) as f64)
+    }
+
+    fn push_sub_block(&mut self, sub_block_sum: [u64; N]) -> Option<[u64; N]> {
+        self.ring[self.pos] = sub_block_sum;
+        self.pos = (self.pos + 1) % self.len;
+
+        if self.filled < self.len {
+            // The first gated block is emitted as soon as the gate is full.
+            self.filled += 1;
+
+            if self.filled < self.len {
+                return None;
+            }
+        }
+        else {
+            self.since += 1;
+
+            if (self.since as u64) < self.step.current_len() {
+                return None;
+            }
+
+            self.step.advance();
+        }
+
+        self.since = 0;
+
+        let mut total = [0u64; N];
+        for s in self.ring[..self.len].iter() {
+            for (t, x) in total.iter_mut().zip(s.iter()) {
+                *t = t.saturating_add(*x);
+            }
+        }
+
+        // Round to the nearest power, instead of truncating.
+        Some(total.map(|t| t.saturating_add(self.gate_len / 2) / self.gate_len))
+    }
+}
+
+impl<const N: usize, const BLOCKS: usize> StatefulProcessor for Q29GatedPowers<N, BLOCKS> {
+    type Input = [i32; N];
+    type Output = Option<[u64; N]>;
+
+    fn advance(&mut self, input: Self::Input) {
+        for (s, x) in self.sum.iter_mut().zip(input.iter()) {
+            let x = *x as i64;
+            let sq = ((x * x) as u64 + (1 << (SQUARE_SHIFT - 1))) >> SQUARE_SHIFT;
+            *s = s.saturating_add(sq);
+        }
+
+        self.count += 1;
+
+        self.current =
+            if self.count as u64 == self.sub_block.current_len() {
+                let sum = self.sum;
+                self.sum = [0; N];
+                self.count = 0;
+                self.sub_block.advance();
+
+                self.push_sub_block(sum)
+            }
+            else {
+                None
+            }
+        ;
+    }
+
+    fn current(&self) -> Self::Output {
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::f64::consts::PI;
+
+    use crate::filter::{KWeightFilter, Q31KWeightFilter};
+    use crate::gated_loudness::{GatedPowers, Loudness};
+
+    #[test]
+    fn matches_float_path() {
+        const SECS: usize = 10;
+
+        // Both gatings fit in four sub-blocks: momentary blocks are four
+        // 100 ms sub-blocks, and short-term blocks are three 1 s sub-blocks.
+        for &gating in &[Gating::Momentary, Gating::Shortterm] {
+            for &sample_rate in &[44100, 48000, 88200, 96000, 176400, 192000] {
+                let mut float_filter = KWeightFilter::<[f64; 2], 2>::new(sample_rate);
+                let mut float_powers = GatedPowers::<[f64; 2], 2>::new(sample_rate, gating);
+                let mut float_loudness = Loudness::<[f64; 2], 2>::new([1.0, 1.0]);
+
+                let mut fixed_filter = Q31KWeightFilter::<2>::new(sample_rate);
+                let mut fixed_powers = Q29GatedPowers::<2, 4>::new(sample_rate, gating);
+                let mut fixed_loudness = Loudness::<[f64; 2], 2>::new([1.0, 1.0]);
+
+                let num_frames = sample_rate as usize * SECS;
+
+                for i in 0..num_frames {
+                    let t = i as f64 / sample_rate as f64;
+
+                    // A slowly swelling tone pair, with a quiet stretch in the
+                    // middle that exercises the relative gate.
+                    let envelope =
+                        if (4.0..6.0).contains(&t) { 0.001 }
+                        else { 0.2 + 0.6 * (2.0 * PI * 0.3 * t).sin().abs() }
+                    ;
+                    let input = [
+                        envelope * (2.0 * PI * 997.0 * t).sin(),
+                        envelope * 0.5 * (2.0 * PI * 60.0 * t).sin()
+                            + envelope * 0.3 * (2.0 * PI * 5000.0 * t).sin(),
+                    ];
+
+                    let fixed_input = input.map(|x| (x * (1u64 << 31) as f64).round() as i32);
+                    let float_input = fixed_input.map(|x| x as f64 / (1u64 << 31) as f64);
+
+                    if let Some(gp) = float_powers.process(float_filter.process(float_input)) {
+                        float_loudness.push(gp);
+                    }
+
+                    if let Some(gp) = fixed_powers.process(fixed_filter.process(fixed_input)) {
+                        fixed_loudness.push(Q29GatedPowers::<2, 4>::to_float(gp));
+                    }
+                }
+
+                let expected = float_loudness.calculate().unwrap();
+                let produced = fixed_loudness.calculate().unwrap();
+
+                assert!(
+                    (expected - produced).abs() < 0.1,
+                    "{:?} at {} Hz: expected {}, produced {}", gating, sample_rate, expected, produced,
+                );
+            }
+        }
+    }
+}
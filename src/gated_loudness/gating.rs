@@ -1,7 +1,16 @@
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
+use alloc::vec;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
 use sampara::{Frame, StatefulProcessor, Processor};
-use sampara::stats::BufferedMovingMs;
-use sampara::sample::FloatSample;
+use sampara::sample::{FloatSample, Sample};
 
+#[cfg(feature = "alloc")]
+use crate::checkpoint;
+use crate::gated_loudness::{shared_sub_block, FractionalStep, Frames, Length, SubBlockPowers, SubBlockGate};
 use crate::util::Util;
 
 const MOMENTARY_GATE_MS: u64 = 400;
@@ -9,59 +18,364 @@ const MOMENTARY_DELTA_MS: u64 = 100;
 const SHORTTERM_GATE_MS: u64 = 3000;
 const SHORTTERM_DELTA_MS: u64 = 1000;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Gating {
     Momentary,
     Shortterm,
     Custom { gate_len_ms: u64, delta_len_ms: u64 },
+
+    /// A gate and step of any exact length, in samples or in time. The gate
+    /// is rounded to the nearest whole frame, while the step may be a
+    /// fractional number of frames, in which case block starts fall on the
+    /// last frame before their exact position.
+    Lengths { gate: Length, step: Length },
 }
 
-pub struct GatedPowers<F, const N: usize>
+impl Gating {
+    /// Returns the gate length and the delta (step) length.
+    pub fn lengths(&self) -> (Length, Length) {
+        match *self {
+            Self::Momentary => (Length::ms(MOMENTARY_GATE_MS), Length::ms(MOMENTARY_DELTA_MS)),
+            Self::Shortterm => (Length::ms(SHORTTERM_GATE_MS), Length::ms(SHORTTERM_DELTA_MS)),
+            Self::Custom { gate_len_ms: g, delta_len_ms: d } => (Length::ms(g), Length::ms(d)),
+            Self::Lengths { gate, step } => (gate, step),
+        }
+    }
+
+    /// Returns the gate length and the delta (step) length, in milliseconds,
+    /// or `None` for [`Gating::Lengths`] that are not whole numbers of
+    /// milliseconds.
+    pub fn lengths_ms(&self) -> Option<(u64, u64)> {
+        let (gate, step) = self.lengths();
+
+        Some((gate.as_ms()?, step.as_ms()?))
+    }
+
+    /// Returns the gate length in whole frames, and the exact step length in
+    /// frames, at a sample rate.
+    pub fn frames(&self, sample_rate: u32) -> (usize, Frames) {
+        let (gate, step) = self.lengths();
+
+        let gate_len = gate.frames(sample_rate).round() as usize;
+        let step = step.frames(sample_rate);
+
+        assert!(gate_len > 0, "gate is shorter than one frame");
+        assert!(step.num() >= step.den(), "step is shorter than one frame");
+
+        (gate_len, step)
+    }
+
+    /// Returns the longest sub-block that evenly divides both the gate and the
+    /// step, at a sample rate. If that would be shorter than one frame, this
+    /// is a single frame. See [`shared_sub_block`].
+    pub fn sub_block(&self, sample_rate: u32) -> Frames {
+        shared_sub_block(Some(self.frames(sample_rate)))
+    }
+}
+
+/// Divides a weighted signal into the blocks that loudness is measured over.
+/// Each completed block is reported as the mean square of each channel over
+/// the block, and the blocks from every scheme are then gated and averaged in
+/// the same way, as described in ITU BS.1770.
+///
+/// [`GatedPowers`] and [`FixedGatedPowers`] generate the rectangular,
+/// overlapping blocks described by a [`Gating`]. Other schemes, such as
+/// exponentially weighted windows, can be used by implementing this trait.
+pub trait BlockGenerator<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Takes the next frame, and returns the mean square of each channel over
+    /// a block if this frame completes one.
+    fn push(&mut self, frame: F) -> Option<F>;
+
+    /// Clears all state, as if no frames had been pushed.
+    fn reset(&mut self);
+
+    /// Returns the gating and sample rate that the blocks are laid out by, if
+    /// they are the evenly spaced blocks of a [`Gating`]. A pipeline can then
+    /// assemble the same blocks, up to rounding, from sub-blocks that it
+    /// shares with every other gating, instead of pushing frames to this
+    /// generator. By default, this returns `None`.
+    fn gating(&self) -> Option<(Gating, u32)> {
+        None
+    }
+
+    /// Returns the current state as a list of numbers, so that it can be
+    /// saved in a [`Checkpoint`](crate::checkpoint::Checkpoint) and restored
+    /// with [`Self::set_state`]. By default, this returns `None`, and a
+    /// pipeline that uses the generator cannot be resumed.
+    #[cfg(feature = "alloc")]
+    fn state(&self) -> Option<Vec<f64>> {
+        None
+    }
+
+    /// Restores a state returned by [`Self::state`], returning `false` if it
+    /// is not valid for this generator.
+    #[cfg(feature = "alloc")]
+    fn set_state(&mut self, state: &[f64]) -> bool {
+        let _ = state;
+        false
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<F, B, const N: usize> BlockGenerator<F, N> for Box<B>
+where
+    F: Frame<N, Sample = f64>,
+    B: BlockGenerator<F, N> + ?Sized,
+{
+    fn push(&mut self, frame: F) -> Option<F> {
+        (**self).push(frame)
+    }
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+
+    fn gating(&self) -> Option<(Gating, u32)> {
+        (**self).gating()
+    }
+
+    fn state(&self) -> Option<Vec<f64>> {
+        (**self).state()
+    }
+
+    fn set_state(&mut self, state: &[f64]) -> bool {
+        (**self).set_state(state)
+    }
+}
+
+/// How the mean squares of each gated block are accumulated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Accumulation {
+    /// A moving sum that adds each new squared sample and subtracts the
+    /// oldest one. This is the cheapest option, but rounding error builds up
+    /// over very long inputs, and can bias quiet passages after loud ones.
+    Running,
+
+    /// Squared samples are summed from scratch within each sub-block, and the
+    /// sub-block sums are added up from scratch for each gated block. Rounding
+    /// error never carries over from one gated block to the next.
+    Exact,
+}
+
+impl Default for Accumulation {
+    fn default() -> Self {
+        Self::Running
+    }
+}
+
+// A moving mean square over the last `len` frames, which adds each new squared
+// frame to a running sum and subtracts the one that falls out of the window.
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+struct MovingMs<F, const N: usize>
 where
     F: Frame<N>,
     F::Sample: FloatSample,
 {
-    ms_state: BufferedMovingMs<Vec<F>, N>,
-    i: usize,
-    delta: usize,
+    squares: Vec<F>,
+    sum: F,
+    pos: usize,
+    filled: usize,
 }
 
-impl<F, const N: usize> GatedPowers<F, N>
+#[cfg(feature = "alloc")]
+impl<F, const N: usize> MovingMs<F, N>
 where
     F: Frame<N>,
     F::Sample: FloatSample,
 {
-    pub fn new(sample_rate: u32, gating: Gating) -> Self {
-        let (gate_len_ms, delta_len_ms) = match gating {
-            Gating::Momentary => (MOMENTARY_GATE_MS, MOMENTARY_DELTA_MS),
-            Gating::Shortterm => (SHORTTERM_GATE_MS, SHORTTERM_DELTA_MS),
-            Gating::Custom { gate_len_ms: g, delta_len_ms: d } => (g, d),
-        };
+    fn new(len: usize) -> Self {
+        Self {
+            squares: vec![Frame::EQUILIBRIUM; len],
+            sum: Frame::EQUILIBRIUM,
+            pos: 0,
+            filled: 0,
+        }
+    }
 
-        // Calculate the gate length, in frames.
-        // This will in turn determine the length of the mean squares buffer.
-        let gate_buffer_len = Util::ms_to_samples(gate_len_ms, sample_rate) as usize;
+    fn reset(&mut self) {
+        self.squares.fill(Frame::EQUILIBRIUM);
+        self.sum = Frame::EQUILIBRIUM;
+        self.pos = 0;
+        self.filled = 0;
+    }
 
-        // Number of frames to add at a time for each iteration after the first.
-        // This is the number of steps to advance the mean squares iterator for
-        // each iteration (i.e. the "step-by" amount).
-        let frames_per_delta = Util::ms_to_samples(delta_len_ms, sample_rate) as usize;
+    // Whether the window has been filled, so that there is a mean square.
+    fn is_active(&self) -> bool {
+        self.filled == self.squares.len()
+    }
 
-        assert!(frames_per_delta > 0);
+    fn advance(&mut self, input: F) {
+        let mut square = input;
+        for x in square.channels_mut() {
+            *x = *x * *x;
+        }
 
-        let buffer = vec![Frame::EQUILIBRIUM; gate_buffer_len];
+        self.sum.zip_transform(self.squares[self.pos], |s, x| s - x);
+        self.sum.zip_transform(square, |s, x| s + x);
 
-        let ms_state = BufferedMovingMs::from(buffer);
+        self.squares[self.pos] = square;
+        self.pos = (self.pos + 1) % self.squares.len();
 
-        Self {
-            ms_state,
-            i: usize::MAX,
-            delta: frames_per_delta,
+        if self.filled < self.squares.len() {
+            self.filled += 1;
+        }
+    }
+
+    fn current(&self) -> Option<F> {
+        if !self.is_active() {
+            return None;
+        }
+
+        let len = F::Sample::from_sample(self.squares.len() as f64);
+
+        let mut mean_sq = self.sum;
+        for x in mean_sq.channels_mut() {
+            *x = *x / len;
         }
+
+        Some(mean_sq)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<F, const N: usize> MovingMs<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    // Appends the position and the number of filled frames, followed by the
+    // running sum and the squared frames, to a list of numbers.
+    fn save(&self, state: &mut Vec<f64>) {
+        state.push(self.pos as f64);
+        state.push(self.filled as f64);
+        state.extend(self.sum.into_channels());
+        state.extend(checkpoint::flatten(self.squares.iter().copied()));
+    }
+
+    // Restores a state appended by `save`, or returns `false` without changing
+    // anything if it could not have come from a window of the same length.
+    fn load(&mut self, state: &[f64]) -> bool {
+        let len = self.squares.len();
+
+        if state.len() != 2 + (len + 1) * N {
+            return false;
+        }
+
+        let (pos, filled) = match (checkpoint::whole(state[0]), checkpoint::whole(state[1])) {
+            (Some(pos), Some(filled)) => (pos as usize, filled as usize),
+            _ => return false,
+        };
+
+        // The window fills up from the start of the buffer.
+        if pos >= len || filled > len || (filled < len && pos != filled) {
+            return false;
+        }
+
+        let mut frames = checkpoint::unflatten(&state[2..]);
+
+        if let Some(sum) = frames.next() {
+            self.sum = sum;
+        }
+
+        for (square, saved) in self.squares.iter_mut().zip(frames) {
+            *square = saved;
+        }
+
+        self.pos = pos;
+        self.filled = filled;
+
+        true
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+enum State<F, const N: usize>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+{
+    Running {
+        ms_state: MovingMs<F, N>,
+        i: usize,
+        step: FractionalStep,
+    },
+    Exact {
+        sub_blocks: SubBlockPowers<F, N>,
+        gate: SubBlockGate<F, N, Vec<F>>,
+        current: Option<F>,
+    },
+}
+
+#[cfg(feature = "alloc")]
+#[derive(Clone)]
+pub struct GatedPowers<F, const N: usize>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+{
+    state: State<F, N>,
+    gating: Gating,
+    sample_rate: u32,
+}
+
+#[cfg(feature = "alloc")]
+impl<F, const N: usize> GatedPowers<F, N>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+{
+    pub fn new(sample_rate: u32, gating: Gating) -> Self {
+        Self::with_accumulation(sample_rate, gating, Accumulation::default())
+    }
+
+    pub fn with_accumulation(sample_rate: u32, gating: Gating, accumulation: Accumulation) -> Self {
+        // The gate length in frames determines the length of the mean squares
+        // buffer. The step is the exact number of frames to advance the mean
+        // squares iterator for each iteration after the first, which may be
+        // fractional.
+        let (gate_buffer_len, step) = gating.frames(sample_rate);
+
+        let state = match accumulation {
+            Accumulation::Running => {
+                State::Running {
+                    ms_state: MovingMs::new(gate_buffer_len),
+                    i: usize::MAX,
+                    step: FractionalStep::new(step),
+                }
+            },
+            Accumulation::Exact => {
+                // Sub-blocks need to evenly divide both the gate and the delta.
+                let sub_block = gating.sub_block(sample_rate);
+
+                State::Exact {
+                    sub_blocks: SubBlockPowers::new(sub_block),
+                    gate: SubBlockGate::new(sub_block, gate_buffer_len, step),
+                    current: None,
+                }
+            },
+        };
+
+        Self { state, gating, sample_rate }
     }
 
     pub fn reset(&mut self) {
-        self.ms_state.reset();
+        match &mut self.state {
+            State::Running { ms_state, i, step } => {
+                ms_state.reset();
+                *i = usize::MAX;
+                step.reset();
+            },
+            State::Exact { sub_blocks, gate, current } => {
+                sub_blocks.reset();
+                gate.reset();
+                *current = None;
+            },
+        }
     }
 
     pub fn momentary(sample_rate: u32) -> Self {
@@ -81,6 +395,149 @@ where
     }
 }
 
+#[cfg(feature = "alloc")]
+impl<F, const N: usize> GatedPowers<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Processes a buffer of interleaved samples, calling `on_block` with the
+    /// powers of each gated block that is completed. The buffer length must be
+    /// a multiple of the number of channels.
+    pub fn process_interleaved<G>(&mut self, samples: &[f64], mut on_block: G)
+    where
+        G: FnMut(F),
+    {
+        assert_eq!(samples.len() % N, 0, "incomplete frame in interleaved buffer");
+
+        for chunk in samples.chunks_exact(N) {
+            let mut frame: F = Frame::EQUILIBRIUM;
+            for (c, x) in frame.channels_mut().zip(chunk.iter()) {
+                *c = *x;
+            }
+
+            if let Some(gated_powers) = self.process(frame) {
+                on_block(gated_powers);
+            }
+        }
+    }
+
+    /// Processes a set of planar (one buffer per channel) samples, calling
+    /// `on_block` with the powers of each gated block that is completed. All
+    /// channel buffers must have the same length.
+    pub fn process_planar<G>(&mut self, channels: &[&[f64]], mut on_block: G)
+    where
+        G: FnMut(F),
+    {
+        let len = Util::planar_len(channels.iter().map(|c| c.len()), N);
+
+        for i in 0..len {
+            let mut frame: F = Frame::EQUILIBRIUM;
+            for (c, x) in frame.channels_mut().zip(channels.iter()) {
+                *c = x[i];
+            }
+
+            if let Some(gated_powers) = self.process(frame) {
+                on_block(gated_powers);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<F, const N: usize> BlockGenerator<F, N> for GatedPowers<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    fn push(&mut self, frame: F) -> Option<F> {
+        self.process(frame)
+    }
+
+    fn reset(&mut self) {
+        self.reset()
+    }
+
+    fn gating(&self) -> Option<(Gating, u32)> {
+        Some((self.gating, self.sample_rate))
+    }
+
+    /// The state starts with 0 for [`Accumulation::Running`] and 1 for
+    /// [`Accumulation::Exact`], and is only valid for the same accumulation.
+    fn state(&self) -> Option<Vec<f64>> {
+        let mut state = Vec::new();
+
+        match &self.state {
+            State::Running { ms_state, i, step } => {
+                // The block counter is not started until the window is full.
+                let i = if *i == usize::MAX { -1.0 } else { *i as f64 };
+
+                state.extend([0.0, i, step.phase() as f64].iter());
+                ms_state.save(&mut state);
+            },
+            State::Exact { sub_blocks, gate, .. } => {
+                state.push(1.0);
+                sub_blocks.save(&mut state);
+                gate.save(&mut state);
+            },
+        }
+
+        Some(state)
+    }
+
+    fn set_state(&mut self, state: &[f64]) -> bool {
+        // Everything is restored into a copy first, so that nothing changes
+        // unless the whole state is valid.
+        match (&mut self.state, state.split_first()) {
+            (State::Running { ms_state, i, step }, Some((&tag, rest))) if tag == 0.0 && rest.len() >= 2 => {
+                let saved_i = if rest[0] == -1.0 { Some(usize::MAX) }
+                    else { checkpoint::whole(rest[0]).map(|i| i as usize) };
+
+                let (saved_i, phase) = match (saved_i, checkpoint::whole(rest[1])) {
+                    (Some(saved_i), Some(phase)) if step.is_valid_phase(phase) => (saved_i, phase),
+                    _ => return false,
+                };
+
+                let mut new_ms = ms_state.clone();
+                let mut new_step = *step;
+                new_step.set_phase(phase);
+
+                // The block counter only runs once the window is full.
+                let is_consistent = if saved_i == usize::MAX { true }
+                    else { (saved_i as u64) < new_step.current_len() };
+
+                if !is_consistent || !new_ms.load(&rest[2..]) || new_ms.is_active() == (saved_i == usize::MAX) {
+                    return false;
+                }
+
+                *ms_state = new_ms;
+                *i = saved_i;
+                *step = new_step;
+            },
+            (State::Exact { sub_blocks, gate, current }, Some((&tag, rest))) if tag == 1.0 => {
+                if rest.len() < SubBlockPowers::<F, N>::SAVED_LEN {
+                    return false;
+                }
+
+                let (saved_sub_blocks, saved_gate) = rest.split_at(SubBlockPowers::<F, N>::SAVED_LEN);
+
+                let mut new_sub_blocks = sub_blocks.clone();
+                let mut new_gate = gate.clone();
+
+                if !new_sub_blocks.load(saved_sub_blocks) || !new_gate.load(saved_gate) {
+                    return false;
+                }
+
+                *sub_blocks = new_sub_blocks;
+                *gate = new_gate;
+                *current = None;
+            },
+            _ => return false,
+        }
+
+        true
+    }
+}
+
+#[cfg(feature = "alloc")]
 impl<F, const N: usize> StatefulProcessor for GatedPowers<F, N>
 where
     F: Frame<N>,
@@ -90,35 +547,502 @@ where
     type Output = Option<F>;
 
     fn advance(&mut self, input: Self::Input) {
-        let was_active = self.ms_state.is_active();
-        self.ms_state.advance(input);
-        let now_active = self.ms_state.is_active();
+        match &mut self.state {
+            State::Running { ms_state, i, step } => {
+                let was_active = ms_state.is_active();
+                ms_state.advance(input);
+                let now_active = ms_state.is_active();
 
-        if now_active {
-            if was_active {
-                self.i = (self.i + 1) % self.delta;
-            }
-            else {
-                self.i = 0;
-            }
+                if now_active {
+                    if was_active {
+                        *i += 1;
+
+                        if *i as u64 == step.current_len() {
+                            *i = 0;
+                            step.advance();
+                        }
+                    }
+                    else {
+                        *i = 0;
+                    }
+                }
+            },
+            State::Exact { sub_blocks, gate, current } => {
+                *current = sub_blocks.push(input).and_then(|sum| gate.push(sum));
+            },
         }
     }
 
     fn current(&self) -> Self::Output {
-        // We only want to output the frame when a new delta is starting.
-        if self.i == 0 {
-            self.ms_state.current()
+        match &self.state {
+            State::Running { ms_state, i, .. } => {
+                // We only want to output the frame when a new delta is starting.
+                if *i == 0 {
+                    ms_state.current()
+                }
+                else {
+                    None
+                }
+            },
+            State::Exact { current, .. } => *current,
+        }
+    }
+}
+
+/// A variant of [`GatedPowers`] that needs no heap allocation, and always uses
+/// [`Accumulation::Exact`]. Its sub-block sums are kept in a fixed-capacity
+/// ring buffer that holds up to `BLOCKS` sub-blocks, where the sub-block
+/// length is the largest length that evenly divides both the gate and the
+/// delta, as given by [`Gating::sub_block`]. Momentary gating needs 4
+/// sub-blocks, and short-term gating needs 3.
+#[derive(Clone)]
+pub struct FixedGatedPowers<F, const N: usize, const BLOCKS: usize>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+{
+    sub_blocks: SubBlockPowers<F, N>,
+    gate: SubBlockGate<F, N, [F; BLOCKS]>,
+    current: Option<F>,
+    gating: Gating,
+    sample_rate: u32,
+}
+
+impl<F, const N: usize, const BLOCKS: usize> FixedGatedPowers<F, N, BLOCKS>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+{
+    /// Creates a new instance. This panics if the gate needs more than
+    /// `BLOCKS` sub-blocks.
+    pub fn new(sample_rate: u32, gating: Gating) -> Self {
+        let (gate_len, step) = gating.frames(sample_rate);
+        let sub_block = gating.sub_block(sample_rate);
+        let ring = [F::EQUILIBRIUM; BLOCKS];
+
+        Self {
+            sub_blocks: SubBlockPowers::new(sub_block),
+            gate: SubBlockGate::with_ring(ring, sub_block, gate_len, step),
+            current: None,
+            gating,
+            sample_rate,
         }
-        else {
-            None
+    }
+
+    pub fn reset(&mut self) {
+        self.sub_blocks.reset();
+        self.gate.reset();
+        self.current = None;
+    }
+
+    pub fn momentary(sample_rate: u32) -> Self {
+        Self::new(sample_rate, Gating::Momentary)
+    }
+
+    pub fn shortterm(sample_rate: u32) -> Self {
+        Self::new(sample_rate, Gating::Shortterm)
+    }
+
+    pub fn process(&mut self, input: F) -> Option<F> {
+        Processor::process(self, input)
+    }
+}
+
+impl<F, const N: usize, const BLOCKS: usize> BlockGenerator<F, N> for FixedGatedPowers<F, N, BLOCKS>
+where
+    F: Frame<N, Sample = f64>,
+{
+    fn push(&mut self, frame: F) -> Option<F> {
+        self.process(frame)
+    }
+
+    fn reset(&mut self) {
+        self.reset()
+    }
+
+    fn gating(&self) -> Option<(Gating, u32)> {
+        Some((self.gating, self.sample_rate))
+    }
+
+    #[cfg(feature = "alloc")]
+    fn state(&self) -> Option<Vec<f64>> {
+        let mut state = Vec::new();
+
+        self.sub_blocks.save(&mut state);
+        self.gate.save(&mut state);
+
+        Some(state)
+    }
+
+    #[cfg(feature = "alloc")]
+    fn set_state(&mut self, state: &[f64]) -> bool {
+        if state.len() < SubBlockPowers::<F, N>::SAVED_LEN {
+            return false;
         }
+
+        let (saved_sub_blocks, saved_gate) = state.split_at(SubBlockPowers::<F, N>::SAVED_LEN);
+
+        // Restore into copies, so that nothing changes unless the whole state
+        // is valid.
+        let mut sub_blocks = self.sub_blocks.clone();
+        let mut gate = self.gate.clone();
+
+        if !sub_blocks.load(saved_sub_blocks) || !gate.load(saved_gate) {
+            return false;
+        }
+
+        self.sub_blocks = sub_blocks;
+        self.gate = gate;
+        self.current = None;
+
+        true
     }
 }
 
+impl<F, const N: usize, const BLOCKS: usize> StatefulProcessor for FixedGatedPowers<F, N, BLOCKS>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+{
+    type Input = F;
+    type Output = Option<F>;
+
+    fn advance(&mut self, input: Self::Input) {
+        self.current = self.sub_blocks.push(input).and_then(|sum| self.gate.push(sum));
+    }
+
+    fn current(&self) -> Self::Output {
+        self.current
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    use sampara::Calculator;
+
+    use crate::gated_loudness::GatedLoudness;
+
+    use approx::assert_abs_diff_eq;
+
+    // A user-defined scheme of back-to-back blocks, which sums each block from
+    // scratch.
+    #[derive(Clone)]
+    struct Consecutive {
+        len: usize,
+        count: usize,
+        sum: f64,
+    }
+
+    impl BlockGenerator<f64, 1> for Consecutive {
+        fn push(&mut self, frame: f64) -> Option<f64> {
+            self.sum += frame * frame;
+            self.count += 1;
+
+            if self.count < self.len {
+                return None;
+            }
+
+            let mean_sq = self.sum / self.len as f64;
+            self.reset();
+
+            Some(mean_sq)
+        }
+
+        fn reset(&mut self) {
+            self.count = 0;
+            self.sum = 0.0;
+        }
+    }
+
+    #[test]
+    fn custom_block_generator() {
+        const SAMPLE_RATE: u32 = 48000;
+
+        // Back-to-back 400 ms blocks are the same as a custom gating whose
+        // step is as long as its gate, and get the same gating.
+        let mut custom = GatedLoudness::<f64, 1, _>::with_blocks(Consecutive { len: 19200, count: 0, sum: 0.0 }, 1.0);
+        let mut standard = GatedLoudness::<f64, 1>::custom(SAMPLE_RATE, 1.0, 400, 400);
+
+        for i in 0..(SAMPLE_RATE as usize * 20) {
+            let t = i as f64 / SAMPLE_RATE as f64;
+
+            // Quiet stretches that fall below the relative gate.
+            let amp = if (i / SAMPLE_RATE as usize) % 4 == 0 { 0.001 } else { 0.5 };
+            let x = amp * (2.0 * core::f64::consts::PI * 997.0 * t).sin();
+
+            custom.push(x);
+            standard.push(x);
+        }
+
+        assert_abs_diff_eq!(custom.calculate().unwrap(), standard.calculate().unwrap(), epsilon = 1e-9);
+    }
+
+    // Pushes frames into a generator and one restored from its state halfway
+    // through, and checks that both produce the same blocks.
+    fn check_resumed<B>(mut original: B, inputs: &[f64]) -> B
+    where
+        B: BlockGenerator<f64, 1> + Clone,
+    {
+        let (first, rest) = inputs.split_at(inputs.len() / 2);
+
+        for &x in first.iter() {
+            original.push(x);
+        }
+
+        let mut resumed = original.clone();
+        resumed.reset();
+        assert!(resumed.set_state(&original.state().unwrap()));
+
+        let mut num_blocks = 0;
+
+        for &x in rest.iter() {
+            let expected = original.push(x);
+            assert_eq!(expected, resumed.push(x));
+
+            num_blocks += expected.is_some() as usize;
+        }
+
+        assert!(num_blocks > 0);
+
+        resumed
+    }
+
+    #[test]
+    fn built_in_generator_state() {
+        const SAMPLE_RATE: u32 = 48000;
+
+        let inputs = (0..(SAMPLE_RATE as usize * 5))
+            .map(|i| ((i as f64) * 0.0123).sin())
+            .collect::<Vec<_>>();
+
+        let running = GatedPowers::<f64, 1>::with_accumulation(SAMPLE_RATE, Gating::Momentary, Accumulation::Running);
+        let exact = GatedPowers::<f64, 1>::with_accumulation(SAMPLE_RATE, Gating::Momentary, Accumulation::Exact);
+        let fixed = FixedGatedPowers::<f64, 1, 4>::momentary(SAMPLE_RATE);
+
+        let mut running = check_resumed(running, &inputs);
+        let exact = check_resumed(exact, &inputs);
+        let fixed = check_resumed(fixed, &inputs);
+
+        assert_eq!(fixed.gating(), Some((Gating::Momentary, SAMPLE_RATE)));
+
+        // A state is only valid for the same accumulation and lengths.
+        assert!(!running.set_state(&exact.state().unwrap()));
+        assert!(!running.set_state(&GatedPowers::<f64, 1>::shortterm(SAMPLE_RATE).state().unwrap()));
+        assert!(!running.set_state(&[0.0, 2.5]));
+        assert!(!FixedGatedPowers::<f64, 1, 30>::shortterm(SAMPLE_RATE).set_state(&fixed.state().unwrap()));
+    }
+
+    #[test]
+    fn fractional_steps() {
+        // A 100 ms step at 11025 Hz is 1102.5 frames, so block starts fall on
+        // floor(k * 1102.5) instead of drifting by half a frame per step.
+        const SAMPLE_RATE: u32 = 11025;
+        const GATE_LEN: usize = 4410;
+
+        let inputs = (0..(SAMPLE_RATE as usize * 30))
+            .map(|i| ((i as f64) * 0.0123).sin() * (1.0 + ((i as f64) * 0.00011).cos()))
+            .collect::<Vec<_>>();
+
+        for &accumulation in [Accumulation::Running, Accumulation::Exact].iter() {
+            let mut gated_powers = GatedPowers::<f64, 1>::with_accumulation(
+                SAMPLE_RATE, Gating::Momentary, accumulation,
+            );
+
+            let mut k = 0;
+
+            for (i, &x) in inputs.iter().enumerate() {
+                let produced = match gated_powers.process(x) {
+                    Some(p) => p,
+                    None => continue,
+                };
+
+                let start = k * 2205 / 2;
+                assert_eq!(i + 1, start + GATE_LEN);
+
+                let expected = inputs[start..(start + GATE_LEN)].iter().map(|x| x * x).sum::<f64>() / GATE_LEN as f64;
+                assert_abs_diff_eq!(produced, expected, epsilon = 1e-9);
+
+                k += 1;
+            }
+
+            assert_eq!(k, 297);
+        }
+
+        // Lengths in samples or in time give the same blocks as the standard
+        // gatings they describe.
+        let in_samples = Gating::Lengths { gate: Length::samples(19200), step: Length::samples(4800) };
+        let in_time = Gating::Lengths { gate: Length::secs_f64(0.4), step: Length::secs(1, 10) };
+
+        let mut standard = GatedPowers::<f64, 1>::new(48000, Gating::Momentary);
+        let mut in_samples = GatedPowers::<f64, 1>::new(48000, in_samples);
+        let mut in_time = GatedPowers::<f64, 1>::new(48000, in_time);
+
+        for &x in inputs.iter() {
+            let expected = standard.process(x);
+            assert_eq!(in_samples.process(x), expected);
+            assert_eq!(in_time.process(x), expected);
+        }
+
+        // Sub-millisecond lengths are kept exact: a 10 ms gate with a 1.25 ms
+        // step at 44.1 kHz has sub-blocks of 55.125 frames, and both ways of
+        // accumulating give the same blocks.
+        let gating = Gating::Lengths { gate: Length::samples(441), step: Length::secs(1, 800) };
+        assert_eq!(gating.frames(44100), (441, Frames::new(441, 8)));
+        assert_eq!(gating.sub_block(44100), Frames::new(441, 8));
+
+        let mut running = GatedPowers::<f64, 1>::with_accumulation(44100, gating, Accumulation::Running);
+        let mut exact = GatedPowers::<f64, 1>::with_accumulation(44100, gating, Accumulation::Exact);
+
+        for &x in inputs.iter() {
+            match (running.process(x), exact.process(x)) {
+                (Some(r), Some(e)) => assert_abs_diff_eq!(r, e, epsilon = 1e-9),
+                (r, e) => assert_eq!(r.is_some(), e.is_some()),
+            }
+        }
+
+        // A gate and step with no common sub-block of at least one frame are
+        // accumulated in single frames instead.
+        let gating = Gating::Custom { gate_len_ms: 401, delta_len_ms: 100 };
+        assert_eq!(gating.sub_block(11025), Frames::whole(1));
+
+        let mut running = GatedPowers::<f64, 1>::with_accumulation(11025, gating, Accumulation::Running);
+        let mut exact = GatedPowers::<f64, 1>::with_accumulation(11025, gating, Accumulation::Exact);
+
+        for &x in inputs.iter() {
+            match (running.process(x), exact.process(x)) {
+                (Some(r), Some(e)) => assert_abs_diff_eq!(r, e, epsilon = 1e-9),
+                (r, e) => assert_eq!(r.is_some(), e.is_some()),
+            }
+        }
+
+        assert_eq!(Gating::Momentary.lengths_ms(), Some((400, 100)));
+        assert_eq!(Gating::Lengths { gate: Length::samples(4410), step: Length::secs(1, 30) }.lengths_ms(), None);
+    }
+
+    #[test]
+    fn single_precision_frames() {
+        const SAMPLE_RATE: u32 = 48000;
+
+        for &accumulation in [Accumulation::Running, Accumulation::Exact].iter() {
+            let mut single = GatedPowers::<[f32; 2], 2>::with_accumulation(SAMPLE_RATE, Gating::Momentary, accumulation);
+            let mut double = GatedPowers::<[f64; 2], 2>::with_accumulation(SAMPLE_RATE, Gating::Momentary, accumulation);
+
+            let mut num_checked = 0;
+
+            for i in 0..(SAMPLE_RATE as usize * 10) {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                let frame = [(2.0 * core::f64::consts::PI * 997.0 * t).sin() as f32, 0.25];
+
+                let produced = single.process(frame);
+                let expected = double.process([frame[0] as f64, frame[1] as f64]);
+
+                assert_eq!(produced.is_some(), expected.is_some());
+
+                if let (Some(p), Some(e)) = (produced, expected) {
+                    for (p, e) in p.iter().zip(e.iter()) {
+                        assert!(((*p as f64 - e) / e).abs() < 1.0e-3, "expected {}, produced {}", e, p);
+                    }
+
+                    num_checked += 1;
+                }
+            }
+
+            assert!(num_checked > 0);
+        }
+    }
+
+    // Feeds `total_secs` of loud noise at 48 kHz into exact accumulation,
+    // ending with a minute of quiet noise, and checks each quiet block against
+    // a sum taken from scratch. This is where any error carried over from the
+    // loud section would be most visible.
+    fn check_exact_accumulation(total_secs: usize) {
+        const SAMPLE_RATE: u32 = 48000;
+        const QUIET_FRAMES: usize = 60 * SAMPLE_RATE as usize;
+
+        let total_frames = total_secs * SAMPLE_RATE as usize;
+
+        let gating = Gating::Momentary;
+        let (gate_len_ms, _) = gating.lengths_ms().unwrap();
+        let gate_len = Util::ms_to_samples(gate_len_ms, SAMPLE_RATE) as usize;
+
+        let mut gated_powers = GatedPowers::<f64, 1>::with_accumulation(
+            SAMPLE_RATE, gating, Accumulation::Exact,
+        );
+
+        // A simple xorshift generator for deterministic noise.
+        let mut rng_state = 0x2545_f491_4f6c_dd1du64;
+        let mut noise = move || {
+            rng_state ^= rng_state << 13;
+            rng_state ^= rng_state >> 7;
+            rng_state ^= rng_state << 17;
+            (rng_state >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+        };
+
+        let mut recent = VecDeque::with_capacity(gate_len);
+        let mut num_checked = 0;
+
+        for i in 0..total_frames {
+            let is_quiet = i >= total_frames - QUIET_FRAMES;
+            let x = noise() * if is_quiet { 1.0e-5 } else { 1.0 };
+
+            // The window only needs to be tracked for the quiet section.
+            if i + gate_len >= total_frames - QUIET_FRAMES {
+                if recent.len() == gate_len {
+                    recent.pop_front();
+                }
+                recent.push_back(x);
+            }
+
+            let produced = gated_powers.process(x);
+
+            if let (Some(produced), true) = (produced, is_quiet) {
+                let expected = recent.iter().map(|x| x * x).sum::<f64>() / gate_len as f64;
+                let rel_error = ((produced - expected) / expected).abs();
+
+                assert!(rel_error < 1.0e-12, "relative error too large: {}", rel_error);
+                num_checked += 1;
+            }
+        }
+
+        assert!(num_checked > 0);
+    }
+
+    #[test]
+    fn exact_accumulation() {
+        // Three minutes is over 8 million frames at 48 kHz.
+        check_exact_accumulation(3 * 60);
+    }
+
+    #[test]
+    #[ignore = "slow; run with --ignored"]
+    fn exact_accumulation_over_24_hours() {
+        check_exact_accumulation(24 * 60 * 60);
+    }
+
+    #[test]
+    fn fixed_matches_exact() {
+        const SAMPLE_RATE: u32 = 44100;
+
+        for &gating in &[Gating::Momentary, Gating::Shortterm] {
+            let mut expected_powers = GatedPowers::<[f64; 2], 2>::with_accumulation(
+                SAMPLE_RATE, gating, Accumulation::Exact,
+            );
+            // Momentary gating needs 4 sub-blocks and short-term gating needs
+            // 3, so the smallest ring that fits both is enough.
+            let mut produced_powers = FixedGatedPowers::<[f64; 2], 2, 4>::new(SAMPLE_RATE, gating);
+
+            for i in 0..(SAMPLE_RATE as usize * 10) {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                let frame = [(t * 2000.0).sin(), (t * 300.0).cos() * 0.5];
+
+                assert_eq!(expected_powers.process(frame), produced_powers.process(frame));
+            }
+        }
+    }
 
     // #[test]
     // fn gated_power_iter() {
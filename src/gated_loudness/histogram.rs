@@ -0,0 +1,316 @@
+//! Fixed-size loudness histograms, for calculating gated loudness values with
+//! bounded memory.
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+
+use sampara::{Frame, Calculator};
+
+use crate::math;
+use crate::util::Util;
+
+const MIN_LOUDNESS: f64 = -70.0;
+const MAX_LOUDNESS: f64 = 10.0;
+const REL_LOUDNESS_THRESH: f64 = -10.0;
+const RANGE_REL_THRESH: f64 = -20.0;
+const RANGE_LOW_PERCENTILE: f64 = 0.10;
+const RANGE_HIGH_PERCENTILE: f64 = 0.95;
+
+/// The default number of bins in a histogram, which makes each bin 0.01 LU
+/// wide.
+pub const DEFAULT_NUM_BINS: usize = 8000;
+
+/// The default number of bins in a [`FixedLoudness`], which makes each bin
+/// 0.1 LU wide. This keeps it to about 13 KB, so that it fits comfortably on
+/// the stack of small embedded targets.
+pub const DEFAULT_FIXED_NUM_BINS: usize = 800;
+
+#[derive(Debug, Copy, Clone)]
+struct Bin {
+    count: u64,
+    power: f64,
+}
+
+impl Bin {
+    const EMPTY: Self = Self { count: 0, power: 0.0 };
+
+    fn loudness(&self) -> f64 {
+        Util::lufs(self.power / self.count as f64)
+    }
+}
+
+/// Sorts block loudness values into `NUM_BINS` equally-wide bins, covering the
+/// range from the absolute loudness threshold (-70 LKFS) to +10 LKFS. Blocks
+/// below that range are discarded, and blocks above it are put in the top bin.
+///
+/// Each bin keeps the total weighted power of its blocks, so that gated
+/// averages are exact for every bin that lies fully above a threshold. Only
+/// the single bin that a relative threshold falls into is approximated, by
+/// including or excluding it as a whole. Results are therefore accurate to
+/// within one bin width, which is 0.01 LU by default. Smaller histograms can
+/// be used where memory is tight, such as on embedded targets.
+#[derive(Debug, Clone)]
+pub struct Histogram<const NUM_BINS: usize = DEFAULT_NUM_BINS> {
+    bins: [Bin; NUM_BINS],
+    count: u64,
+    power: f64,
+}
+
+impl<const NUM_BINS: usize> Default for Histogram<NUM_BINS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const NUM_BINS: usize> Histogram<NUM_BINS> {
+    const BINS_PER_LU: f64 = NUM_BINS as f64 / (MAX_LOUDNESS - MIN_LOUDNESS);
+
+    pub fn new() -> Self {
+        Self {
+            bins: [Bin::EMPTY; NUM_BINS],
+            count: 0,
+            power: 0.0,
+        }
+    }
+
+    /// Creates an empty histogram directly on the heap. At the default size,
+    /// a histogram takes up 128 KB, which is too much to build on the stack
+    /// and then move.
+    #[cfg(feature = "alloc")]
+    pub fn boxed() -> Box<Self> {
+        // SAFETY: every field of an empty histogram is a zero count or a zero
+        // power, and both are all zero bits.
+        unsafe { Box::<Self>::new_zeroed().assume_init() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn reset(&mut self) {
+        self.bins.fill(Bin::EMPTY);
+        self.count = 0;
+        self.power = 0.0;
+    }
+
+    fn bin_index(loudness: f64) -> usize {
+        let index = ((loudness - MIN_LOUDNESS) * Self::BINS_PER_LU) as usize;
+        index.min(NUM_BINS.saturating_sub(1))
+    }
+
+    /// Adds a block, given its loudness and its weighted power.
+    pub fn push(&mut self, loudness: f64, power: f64) {
+        if loudness.is_nan() || loudness <= MIN_LOUDNESS {
+            return;
+        }
+
+        if let Some(bin) = self.bins.get_mut(Self::bin_index(loudness)) {
+            bin.count += 1;
+            bin.power += power;
+
+            self.count += 1;
+            self.power += power;
+        }
+    }
+
+    /// Removes a block that was previously added with the same loudness and
+    /// weighted power, such as when it expires from a sliding window.
+    pub fn remove(&mut self, loudness: f64, power: f64) {
+        if loudness.is_nan() || loudness <= MIN_LOUDNESS {
+            return;
+        }
+
+        if let Some(bin) = self.bins.get_mut(Self::bin_index(loudness)) {
+            debug_assert!(bin.count > 0, "removing a block that was never added");
+
+            bin.count -= 1;
+            bin.power -= power;
+
+            // Clear out any rounding error once a bin is empty, so that it
+            // cannot build up as blocks come and go.
+            if bin.count == 0 {
+                bin.power = 0.0;
+            }
+
+            self.count -= 1;
+            self.power -= power;
+
+            if self.count == 0 {
+                self.power = 0.0;
+            }
+        }
+    }
+
+    /// The loudness of all blocks above the absolute loudness threshold.
+    fn abs_loudness(&self) -> Option<f64> {
+        if self.count == 0 { None }
+        else { Some(Util::lufs(self.power / self.count as f64)) }
+    }
+
+    /// Iterates over all non-empty bins whose blocks are above the given
+    /// threshold.
+    fn bins_above(&self, threshold: f64) -> impl Iterator<Item = &Bin> {
+        let start = if threshold > MIN_LOUDNESS { Self::bin_index(threshold) } else { 0 };
+
+        self.bins.iter()
+            .skip(start)
+            .filter(move |bin| bin.count > 0 && bin.loudness() > threshold)
+    }
+
+    /// Calculates the integrated (relative gated) loudness of all blocks, as
+    /// described in the ITU BS.1770 tech spec.
+    pub fn integrated(&self) -> Option<f64> {
+        let rel_threshold = self.abs_loudness()? + REL_LOUDNESS_THRESH;
+
+        let (count, power) = self.bins_above(rel_threshold)
+            .fold((0, 0.0), |(c, p), bin| (c + bin.count, p + bin.power));
+
+        if count == 0 { None }
+        else { Some(Util::lufs(power / count as f64)) }
+    }
+
+    /// Calculates the loudness range (LRA) of all blocks, as described in EBU
+    /// Tech 3342. This is intended to be used with short-term blocks.
+    pub fn range(&self) -> Option<f64> {
+        let rel_threshold = self.abs_loudness()? + RANGE_REL_THRESH;
+
+        let count = self.bins_above(rel_threshold).map(|bin| bin.count).sum::<u64>();
+
+        if count == 0 {
+            return None;
+        }
+
+        let percentile = |p: f64| {
+            let rank = math::round((count - 1) as f64 * p) as u64;
+            let mut seen = 0;
+
+            self.bins_above(rel_threshold)
+                .find(|bin| {
+                    seen += bin.count;
+                    seen > rank
+                })
+                .map(Bin::loudness)
+        };
+
+        Some(percentile(RANGE_HIGH_PERCENTILE)? - percentile(RANGE_LOW_PERCENTILE)?)
+    }
+}
+
+/// A variant of [`Loudness`](crate::gated_loudness::Loudness) that needs no
+/// heap allocation, by keeping its blocks in a [`Histogram`].
+///
+/// By default, readings are accurate to within 0.1 LU. Use
+/// [`DEFAULT_NUM_BINS`] for 0.01 LU, at ten times the size.
+#[derive(Debug, Clone)]
+pub struct FixedLoudness<F, const N: usize, const NUM_BINS: usize = DEFAULT_FIXED_NUM_BINS>
+where
+    F: Frame<N, Sample = f64>,
+{
+    histogram: Histogram<NUM_BINS>,
+    maximum: Option<f64>,
+    g_weights: F,
+}
+
+impl<F, const N: usize, const NUM_BINS: usize> FixedLoudness<F, N, NUM_BINS>
+where
+    F: Frame<N, Sample = f64>,
+{
+    pub fn new(g_weights: F) -> Self {
+        Self {
+            histogram: Histogram::new(),
+            maximum: None,
+            g_weights,
+        }
+    }
+
+    pub fn push(&mut self, gated_powers: F) {
+        let power = Util::weighted_power(gated_powers, self.g_weights);
+        let loudness = Util::lufs(power);
+
+        self.histogram.push(loudness, power);
+        self.maximum = Some(self.maximum.map_or(loudness, |m| m.max(loudness)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.histogram.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        self.histogram.reset();
+        self.maximum = None;
+    }
+
+    /// Returns the highest loudness of any single block seen so far.
+    pub fn maximum(&self) -> Option<f64> {
+        self.maximum
+    }
+
+    pub fn integrated(&self) -> Option<f64> {
+        self.histogram.integrated()
+    }
+
+    pub fn range(&self) -> Option<f64> {
+        self.histogram.range()
+    }
+
+    pub fn calculate(self) -> Option<f64> {
+        self.integrated()
+    }
+}
+
+impl<F, const N: usize, const NUM_BINS: usize> Calculator for FixedLoudness<F, N, NUM_BINS>
+where
+    F: Frame<N, Sample = f64>,
+{
+    type Input = F;
+    type Output = Option<f64>;
+
+    fn push(&mut self, gated_powers: Self::Input) {
+        self.push(gated_powers)
+    }
+
+    fn calculate(self) -> Self::Output {
+        self.calculate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gated_loudness::Loudness;
+
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn matches_loudness() {
+        let mut fixed_loudness = FixedLoudness::<f64, 1, DEFAULT_NUM_BINS>::new(1.0);
+        let mut coarse_loudness = FixedLoudness::<f64, 1>::new(1.0);
+        let mut loudness = Loudness::<f64, 1>::new(1.0);
+
+        for i in 0..1000 {
+            let block_loudness = -80.0 + 70.0 * ((i as f64) * 0.37).sin().abs();
+            let power = 10.0f64.powf((block_loudness + 0.691) / 10.0);
+
+            fixed_loudness.push(power);
+            coarse_loudness.push(power);
+            loudness.push(power);
+        }
+
+        let expected = loudness.evaluate(0.0).unwrap().integrated;
+
+        assert_abs_diff_eq!(expected, fixed_loudness.integrated().unwrap(), epsilon = 0.01);
+        assert_abs_diff_eq!(expected, coarse_loudness.integrated().unwrap(), epsilon = 0.1);
+
+        let expected = loudness.range().unwrap();
+
+        assert_abs_diff_eq!(expected, fixed_loudness.range().unwrap(), epsilon = 0.02);
+        assert_abs_diff_eq!(expected, coarse_loudness.range().unwrap(), epsilon = 0.2);
+
+        assert_eq!(loudness.maximum(), fixed_loudness.maximum());
+
+        fixed_loudness.reset();
+        assert!(fixed_loudness.is_empty());
+        assert_eq!(fixed_loudness.integrated(), None);
+    }
+}
@@ -0,0 +1,270 @@
+//! Exact gate and step lengths.
+//!
+//! Lengths given in time rarely come out to a whole number of frames, such as
+//! a 100 ms step at 11025 Hz, which is 1102.5 frames. Rounding each step would
+//! make block boundaries drift away from the nominal timeline, so lengths are
+//! kept as exact ratios of frames instead, and stepped through with a
+//! fractional accumulator.
+
+use crate::math;
+use crate::util::Util;
+
+/// The length of a gate or a step, either as a number of samples or as an
+/// exact span of time. Lengths in time are always kept in lowest terms, so
+/// that equal lengths compare equal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Length(Repr);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum Repr {
+    Samples(u64),
+    Seconds { num: u64, den: u64 },
+}
+
+impl Length {
+    /// A whole number of samples, whatever the sample rate.
+    pub fn samples(samples: u64) -> Self {
+        Self(Repr::Samples(samples))
+    }
+
+    /// A number of milliseconds.
+    pub fn ms(ms: u64) -> Self {
+        Self::secs(ms, 1000)
+    }
+
+    /// A number of seconds, given as the ratio `num / den`.
+    pub fn secs(num: u64, den: u64) -> Self {
+        assert!(den > 0, "length has a zero denominator");
+
+        let d = Util::gcd(num, den).max(1);
+        Self(Repr::Seconds { num: num / d, den: den / d })
+    }
+
+    /// A number of seconds, rounded to the nearest nanosecond.
+    pub fn secs_f64(secs: f64) -> Self {
+        assert!(secs >= 0.0 && secs.is_finite(), "length must be finite and non-negative");
+
+        Self::secs(math::round(secs * 1.0e9) as u64, 1_000_000_000)
+    }
+
+    /// Returns the length in samples, if it was given as a number of them.
+    pub fn as_samples(&self) -> Option<u64> {
+        match self.0 {
+            Repr::Samples(n) => Some(n),
+            Repr::Seconds { .. } => None,
+        }
+    }
+
+    /// Returns the length in seconds as the ratio `(num, den)` in lowest
+    /// terms, if it was given as a span of time.
+    pub fn as_secs(&self) -> Option<(u64, u64)> {
+        match self.0 {
+            Repr::Samples(_) => None,
+            Repr::Seconds { num, den } => Some((num, den)),
+        }
+    }
+
+    /// Returns the length in milliseconds, if it is a whole number of them.
+    pub fn as_ms(&self) -> Option<u64> {
+        let (num, den) = self.as_secs()?;
+
+        if (num * 1000) % den == 0 { Some(num * 1000 / den) }
+        else { None }
+    }
+
+    /// Returns the exact number of frames in this length, at a sample rate.
+    pub fn frames(&self, sample_rate: u32) -> Frames {
+        match self.0 {
+            Repr::Samples(n) => Frames::whole(n),
+            Repr::Seconds { num, den } => Frames::new(num * sample_rate as u64, den),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Length {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serde::Serialize::serialize(&self.0, serializer)
+    }
+}
+
+// Deserialized lengths go through the same checks and reduction as
+// `Length::secs`.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Length {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        match <Repr as serde::Deserialize>::deserialize(deserializer)? {
+            Repr::Samples(n) => Ok(Self::samples(n)),
+            Repr::Seconds { den: 0, .. } => Err(serde::de::Error::custom("length has a zero denominator")),
+            Repr::Seconds { num, den } => Ok(Self::secs(num, den)),
+        }
+    }
+}
+
+/// An exact, possibly fractional, number of frames.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Frames {
+    num: u64,
+    den: u64,
+}
+
+impl Frames {
+    /// The ratio `num / den` of frames.
+    pub fn new(num: u64, den: u64) -> Self {
+        assert!(den > 0, "number of frames has a zero denominator");
+
+        let d = Util::gcd(num, den).max(1);
+        Self { num: num / d, den: den / d }
+    }
+
+    pub fn whole(frames: u64) -> Self {
+        Self { num: frames, den: 1 }
+    }
+
+    pub fn num(&self) -> u64 {
+        self.num
+    }
+
+    pub fn den(&self) -> u64 {
+        self.den
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.num == 0
+    }
+
+    /// Rounds to the nearest whole number of frames, with halves rounded up.
+    pub fn round(&self) -> u64 {
+        (2 * self.num + self.den) / (2 * self.den)
+    }
+
+    /// The largest length that evenly divides both this length and `other`.
+    /// A zero length is divided by anything, so it is ignored.
+    pub fn gcd(&self, other: Self) -> Self {
+        if self.is_zero() { return other; }
+        if other.is_zero() { return *self; }
+
+        let lcm = self.den / Util::gcd(self.den, other.den) * other.den;
+        Self::new(Util::gcd(self.num, other.num), lcm)
+    }
+
+    /// Returns how many times `unit` fits into this length, which must be an
+    /// exact multiple of it.
+    pub fn count(&self, unit: Self) -> u64 {
+        assert!(!unit.is_zero(), "counting in a zero length");
+
+        let num = self.num * unit.den;
+        let den = self.den * unit.num;
+
+        assert!(num % den == 0, "length is not a multiple of the unit");
+
+        num / den
+    }
+}
+
+impl From<usize> for Frames {
+    fn from(frames: usize) -> Self {
+        Self::whole(frames as u64)
+    }
+}
+
+/// Steps through a timeline in steps of a fractional number of frames. Each
+/// step ends on the last whole frame before its exact end, so step lengths
+/// vary by at most one frame, and never drift from the exact timeline.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FractionalStep {
+    step: Frames,
+
+    // The fractional part of the exact position at the start of the current
+    // step, in units of `1 / step.den` frames.
+    phase: u64,
+}
+
+impl FractionalStep {
+    pub fn new(step: Frames) -> Self {
+        assert!(step.num >= step.den, "step is shorter than one frame");
+
+        Self { step, phase: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0;
+    }
+
+    /// The length of the current step, in whole frames.
+    #[inline]
+    pub fn current_len(&self) -> u64 {
+        (self.step.num + self.phase) / self.step.den
+    }
+
+    /// Moves on to the next step.
+    #[inline]
+    pub fn advance(&mut self) {
+        self.phase = (self.step.num + self.phase) % self.step.den;
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn phase(&self) -> u64 {
+        self.phase
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn is_valid_phase(&self, phase: u64) -> bool {
+        phase < self.step.den
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn set_phase(&mut self, phase: u64) {
+        assert!(self.is_valid_phase(phase));
+
+        self.phase = phase;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractional_step() {
+        // A 100 ms step at 11025 Hz is 1102.5 frames.
+        let step = Length::ms(100).frames(11025);
+        assert_eq!(step, Frames::new(2205, 2));
+
+        let mut fractional = FractionalStep::new(step);
+        let mut pos = 0;
+
+        for k in 1..=1000 {
+            pos += fractional.current_len();
+            fractional.advance();
+
+            // Each step ends on the last frame before its exact end.
+            assert_eq!(pos, k * 2205 / 2);
+        }
+
+        // The sub-block shared by momentary and short-term gating at that
+        // rate is the 100 ms step itself.
+        let gate = Frames::whole(Length::ms(400).frames(11025).round());
+        let sub_block = gate.gcd(step).gcd(Length::ms(1000).frames(11025));
+
+        assert_eq!(sub_block, step);
+        assert_eq!(gate.count(sub_block), 4);
+
+        assert_eq!(Length::secs_f64(0.1), Length::ms(100));
+        assert_eq!(Length::ms(2500).as_ms(), Some(2500));
+        assert_eq!(Length::secs(1, 3).as_ms(), None);
+        assert_eq!(Length::samples(480).frames(48000), Frames::whole(480));
+
+        // Lengths in time are kept in lowest terms.
+        assert_eq!(Length::secs(2, 20), Length::secs(1, 10));
+        assert_eq!(Length::secs(2, 20).as_secs(), Some((1, 10)));
+        assert_eq!(Length::samples(480).as_secs(), None);
+    }
+}
@@ -1,17 +1,75 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
 use sampara::{Frame, Calculator};
 use sampara::stats::CumulativeMean;
 
+use crate::balance::{Balance, Channel};
+use crate::gated_loudness::Histogram;
+use crate::math;
 use crate::util::Util;
 
 const ABS_LOUDNESS_THRESH: f64 = -70.0;
+const RANGE_REL_THRESH: f64 = -20.0;
+const RANGE_LOW_PERCENTILE: f64 = 0.10;
+const RANGE_HIGH_PERCENTILE: f64 = 0.95;
+const MAX_GAIN_ITERATIONS: usize = 32;
+const GAIN_TOLERANCE: f64 = 1.0e-9;
+
+/// The result of evaluating the gating and integrated loudness of a set of
+/// blocks, optionally after applying a hypothetical gain.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Evaluation {
+    /// The gain that was applied to the blocks, in dB.
+    pub gain: f64,
+
+    /// The integrated (relative gated) loudness, in LKFS.
+    pub integrated: f64,
+
+    /// The relative loudness threshold that was used for gating, in LKFS.
+    pub rel_threshold: f64,
+
+    /// The number of blocks above the absolute loudness threshold.
+    pub abs_gated: usize,
+
+    /// The number of blocks above both the absolute and relative thresholds.
+    pub rel_gated: usize,
+}
+
+/// A set of readings taken from a [`Loudness`] without consuming it, so that
+/// they can be shown while blocks are still being pushed.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    /// The loudness of the most recent block, in LKFS. With momentary or
+    /// short-term gating, this is the current momentary or short-term
+    /// loudness.
+    pub latest: Option<f64>,
+
+    /// The integrated loudness of all blocks so far, in LKFS.
+    pub integrated: Option<f64>,
+
+    /// The highest loudness of any single block so far, in LKFS.
+    pub maximum: Option<f64>,
+
+    /// The loudness range (LRA) of all blocks so far, in LU.
+    pub range: Option<f64>,
+}
 
 pub struct Loudness<F, const N: usize>
 where
     F: Frame<N, Sample = f64>,
 {
     abs_averager: CumulativeMean<F, N>,
-    abs_loud_frames: Vec<(f64, F)>,
+
+    // All blocks are kept, even those that are below the absolute loudness
+    // threshold, since a positive gain might lift them above it.
+    blocks: Vec<(f64, F)>,
     g_weights: F,
+
+    // Running readings, so that snapshots do not need to scan every block.
+    // The histogram is only kept once snapshots are tracked.
+    histogram: Option<Box<Histogram>>,
+    maximum: Option<f64>,
 }
 
 impl<F, const N: usize> Loudness<F, N>
@@ -21,21 +79,48 @@ where
     pub fn new(g_weights: F) -> Self {
         Self {
             abs_averager: CumulativeMean::default(),
-            abs_loud_frames: Vec::new(),
+            blocks: Vec::new(),
             g_weights,
+            histogram: None,
+            maximum: None,
+        }
+    }
+
+    /// Keeps a [`Histogram`] of block loudness up to date from now on,
+    /// starting with the blocks seen so far, so that [`Self::snapshot`] takes
+    /// time proportional to the number of histogram bins instead of the
+    /// number of blocks. The histogram takes up 128 KB.
+    pub fn track_snapshots(&mut self) {
+        if self.histogram.is_some() {
+            return;
+        }
+
+        let mut histogram = Histogram::boxed();
+        for (frame_loudness, gated_powers) in self.blocks.iter() {
+            histogram.push(*frame_loudness, Util::weighted_power(*gated_powers, self.g_weights));
         }
+
+        self.histogram = Some(histogram);
     }
 
     pub fn push(&mut self, gated_powers: F) {
-        let frame_loudness = Util::loudness(gated_powers, self.g_weights);
+        let power = Util::weighted_power(gated_powers, self.g_weights);
+        let frame_loudness = Util::lufs(power);
+
+        if let Some(histogram) = self.histogram.as_mut() {
+            histogram.push(frame_loudness, power);
+        }
+
+        self.maximum = Some(self.maximum.map_or(frame_loudness, |m| m.max(frame_loudness)));
 
         // If the frame loudness is greater than the absolute loudness
-        // threshold (i.e. it is "not silence"), save the frame and its
-        // loudness.
+        // threshold (i.e. it is "not silence"), include it in the running
+        // average.
         if frame_loudness > ABS_LOUDNESS_THRESH {
             self.abs_averager.advance(gated_powers);
-            self.abs_loud_frames.push((frame_loudness, gated_powers))
         }
+
+        self.blocks.push((frame_loudness, gated_powers))
     }
 
     pub fn is_empty(&self) -> bool {
@@ -43,49 +128,215 @@ where
     }
 
     pub fn reset(&mut self) {
-        *self = Self::new(self.g_weights)
+        self.abs_averager = CumulativeMean::default();
+        self.blocks.clear();
+        self.maximum = None;
+
+        if let Some(histogram) = self.histogram.as_mut() {
+            histogram.reset();
+        }
     }
 
-    pub fn calculate(self) -> Option<f64> {
-        let Self { abs_averager, abs_loud_frames, g_weights, .. } = self;
+    /// Iterates over the powers of every block pushed so far, in order.
+    /// Pushing them into a new instance recreates this one exactly.
+    pub(crate) fn block_powers(&self) -> impl Iterator<Item = F> + '_ {
+        self.blocks.iter().map(|(_, p)| *p)
+    }
 
-        println!("Num gates processed: {}", abs_averager.count());
+    /// Returns the highest loudness of any single block seen so far.
+    pub fn maximum(&self) -> Option<f64> {
+        self.maximum
+    }
+
+    /// Takes a snapshot of the current readings, without disturbing any
+    /// state. By default, the integrated loudness and range are calculated
+    /// from every block, exactly as by [`Self::evaluate`] and [`Self::range`].
+    /// After [`Self::track_snapshots`], they are read from a histogram
+    /// instead, which is much faster once there are many blocks, and accurate
+    /// to within 0.01 LU.
+    pub fn snapshot(&self) -> Snapshot {
+        let (integrated, range) = match self.histogram.as_ref() {
+            Some(histogram) => (histogram.integrated(), histogram.range()),
+            None => (self.evaluate(0.0).map(|e| e.integrated), self.range()),
+        };
 
-        // This performs the calculation done in equation #5 in the ITU BS.1770
-        // tech spec. This is the loudness of the average of the per-channel
-        // power of frames that were marked as "loud" (i.e. frames with
-        // loudness above the absolute loudness threshold) during the initial
-        // pass.
+        Snapshot {
+            latest: self.blocks.last().map(|(l, _)| *l),
+            integrated,
+            maximum: self.maximum,
+            range,
+        }
+    }
+
+    /// Re-evaluates the gating and integrated loudness of all blocks seen so
+    /// far, as if a gain of `gain` dB had been applied to the input signal.
+    /// Since the absolute and relative thresholds are fixed, a gain can cause
+    /// blocks to move across them, so this is not simply an offset of the
+    /// ungained result.
+    pub fn evaluate(&self, gain: f64) -> Option<Evaluation> {
+        self.gate(gain).map(|(eval, _)| eval)
+    }
+
+    // Applies the absolute and relative gates as if a gain of `gain` dB had
+    // been applied, returning the evaluation along with the mean channel
+    // powers of the blocks that passed both gates, before the gain.
+    fn gate(&self, gain: f64) -> Option<(Evaluation, F)> {
+        // Scaling a signal by a gain in dB scales its powers by the same
+        // amount, which in turn offsets each block loudness by that gain.
+        let mut abs_averager = CumulativeMean::default();
+
+        for (frame_loudness, channel_powers) in self.blocks.iter() {
+            if frame_loudness + gain > ABS_LOUDNESS_THRESH {
+                abs_averager.advance(*channel_powers);
+            }
+        }
+
+        let abs_gated = abs_averager.count() as usize;
         let abs_avg_gated_power = abs_averager.try_current()?;
-        let abs_loudness = Util::loudness(abs_avg_gated_power, g_weights);
-        println!("Absolute loudness: {} LKFS", abs_loudness);
-
-        // This performs the calculation done in equation #6 in the ITU BS.1770
-        // tech spec. The relative loudness threshold is the absolute loudness
-        // minus 10.0.
-        let rel_loudness_thresh = abs_loudness - 10.0;
-        println!("Relative threshold: {} LKFS", rel_loudness_thresh);
-
-        // This performs the calculation done in equation #7 in the ITU BS.1770
-        // tech spec. From the collection of saved frames that were marked as
-        // "absolutely loud", only those that exceed the relative loudness
-        // threshold need to be selected and averaged.
+        let abs_loudness = Util::loudness(abs_avg_gated_power, self.g_weights) + gain;
+
+        let rel_threshold = abs_loudness - 10.0;
+
         let mut rel_averager = CumulativeMean::default();
 
-        for (frame_loudness, channel_powers) in abs_loud_frames {
-            // These frames are already known to be above the absolute loudness
-            // threshold. However, for this calculation they also need to be
-            // above the relative loudness threshold.
-            if frame_loudness > rel_loudness_thresh {
-                rel_averager.advance(channel_powers)
+        for (frame_loudness, channel_powers) in self.blocks.iter() {
+            let gained_loudness = frame_loudness + gain;
+
+            if gained_loudness > ABS_LOUDNESS_THRESH && gained_loudness > rel_threshold {
+                rel_averager.advance(*channel_powers);
             }
         }
 
+        let rel_gated = rel_averager.count() as usize;
         let rel_avg_gated_power = rel_averager.try_current()?;
-        let rel_loudness = Util::loudness(rel_avg_gated_power, g_weights);
-        println!("Relative loudness: {} LKFS", rel_loudness);
+        let integrated = Util::loudness(rel_avg_gated_power, self.g_weights) + gain;
+
+        let eval = Evaluation {
+            gain,
+            integrated,
+            rel_threshold,
+            abs_gated,
+            rel_gated,
+        };
+
+        Some((eval, rel_avg_gated_power))
+    }
+
+    /// Calculates the integrated loudness of each channel on its own, in
+    /// LKFS, using the same gated blocks as the programme loudness from
+    /// [`Self::evaluate`]. Each channel has its weight applied, so the powers
+    /// of all channels add up to that of the programme. A channel that is
+    /// silent in every gated block reads as negative infinity.
+    pub fn channel_loudness(&self) -> Option<[f64; N]> {
+        let (_, powers) = self.gate(0.0)?;
+        let weighted: F = powers.mul_frame(self.g_weights.into_float_frame());
+
+        let mut loudness = Util::frame_to_array(weighted);
+        for l in loudness.iter_mut() {
+            *l = Util::lufs(*l);
+        }
+
+        Some(loudness)
+    }
+
+    /// Compares the per-channel loudness of the gated blocks, given the role
+    /// of each channel.
+    pub fn balance(&self, roles: &[Channel; N]) -> Option<Balance> {
+        self.channel_loudness().map(|loudness| Balance::new(&loudness, roles))
+    }
+
+    /// Finds the gain, in dB, that would need to be applied to the input
+    /// signal for its integrated loudness to equal `target`.
+    ///
+    /// Within a fixed set of gated blocks, the integrated loudness moves in
+    /// lockstep with the gain. The gain is refined until the set of gated
+    /// blocks stops changing, which makes the result exact. If the target
+    /// falls into a discontinuity caused by blocks crossing a threshold, the
+    /// gain with the closest result is returned instead.
+    pub fn gain_for_target(&self, target: f64) -> Option<f64> {
+        let mut gain = match self.evaluate(0.0) {
+            Some(eval) => target - eval.integrated,
+
+            // Nothing is above the absolute threshold as-is, so start by
+            // lifting the loudest block up to the target.
+            None => {
+                let max_loudness = self.blocks.iter()
+                    .map(|(l, _)| *l)
+                    .fold(f64::NEG_INFINITY, f64::max);
+
+                if max_loudness.is_finite() { target - max_loudness }
+                else { return None }
+            },
+        };
+
+        let mut best: Option<(f64, f64)> = None;
+
+        for _ in 0..MAX_GAIN_ITERATIONS {
+            let error = match self.evaluate(gain) {
+                Some(eval) => target - eval.integrated,
+                None => break,
+            };
+
+            if best.map_or(true, |(_, e)| error.abs() < e.abs()) {
+                best = Some((gain, error));
+            }
+
+            if error.abs() <= GAIN_TOLERANCE {
+                break;
+            }
+
+            gain += error;
+        }
 
-        Some(rel_loudness)
+        best.map(|(g, _)| g)
+    }
+
+    /// Calculates the loudness range (LRA) of all blocks seen so far, as
+    /// described in EBU Tech 3342. This is intended to be used with
+    /// short-term blocks.
+    pub fn range(&self) -> Option<f64> {
+        self.range_with_gain(0.0)
+    }
+
+    /// Calculates the loudness range (LRA) as if a gain of `gain` dB had been
+    /// applied to the input signal. The range itself is not affected by the
+    /// gain, but the set of blocks that pass the absolute threshold is.
+    pub fn range_with_gain(&self, gain: f64) -> Option<f64> {
+        let mut abs_averager = CumulativeMean::default();
+
+        for (frame_loudness, channel_powers) in self.blocks.iter() {
+            if frame_loudness + gain > ABS_LOUDNESS_THRESH {
+                abs_averager.advance(*channel_powers);
+            }
+        }
+
+        let abs_avg_gated_power = abs_averager.try_current()?;
+        let rel_threshold =
+            Util::loudness(abs_avg_gated_power, self.g_weights) + gain + RANGE_REL_THRESH;
+
+        let mut gated = self.blocks.iter()
+            .map(|(l, _)| l + gain)
+            .filter(|l| *l > ABS_LOUDNESS_THRESH && *l > rel_threshold)
+            .collect::<Vec<_>>();
+
+        if gated.is_empty() {
+            return None;
+        }
+
+        gated.sort_by(f64::total_cmp);
+
+        let percentile = |p: f64| {
+            let index = math::round((gated.len() - 1) as f64 * p) as usize;
+            gated[index]
+        };
+
+        Some(percentile(RANGE_HIGH_PERCENTILE) - percentile(RANGE_LOW_PERCENTILE))
+    }
+
+    pub fn calculate(self) -> Option<f64> {
+        // This performs the calculations done in equations #5, #6, and #7 in
+        // the ITU BS.1770 tech spec, with no gain applied.
+        self.evaluate(0.0).map(|eval| eval.integrated)
     }
 }
 
@@ -107,4 +358,134 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    use approx::assert_abs_diff_eq;
+
+    // Produces a mono block power with the given loudness.
+    fn block(loudness: f64) -> f64 {
+        10.0f64.powf((loudness + 0.691) / 10.0)
+    }
+
+    #[test]
+    fn evaluate() {
+        let mut loudness = Loudness::<f64, 1>::new(1.0);
+
+        for &l in &[-75.0, -20.0, -20.0, -25.0] {
+            loudness.push(block(l));
+        }
+
+        // With no gain, the quiet block is below the absolute threshold.
+        let eval = loudness.evaluate(0.0).unwrap();
+        assert_eq!(eval.abs_gated, 3);
+        assert_eq!(eval.rel_gated, 3);
+
+        // A small gain offsets the result exactly, since no blocks cross any
+        // thresholds.
+        let gained = loudness.evaluate(3.0).unwrap();
+        assert_eq!(gained.abs_gated, 3);
+        assert_abs_diff_eq!(gained.integrated, eval.integrated + 3.0, epsilon = 1e-9);
+
+        // With a large enough gain, the quiet block is lifted above the
+        // absolute threshold, but is still excluded by the relative one.
+        let gained = loudness.evaluate(10.0).unwrap();
+        assert_eq!(gained.abs_gated, 4);
+        assert_eq!(gained.rel_gated, 3);
+
+        // A large negative gain pushes everything into silence.
+        assert_eq!(loudness.evaluate(-60.0), None);
+
+        assert_eq!(loudness.calculate(), Some(eval.integrated));
+    }
+
+    #[test]
+    fn gain_for_target() {
+        let mut loudness = Loudness::<f64, 1>::new(1.0);
+
+        for &l in &[-72.0, -68.0, -30.0, -31.0, -45.0] {
+            loudness.push(block(l));
+        }
+
+        for &target in &[-23.0, -31.0, -60.0] {
+            let gain = loudness.gain_for_target(target).unwrap();
+            let eval = loudness.evaluate(gain).unwrap();
+
+            assert_abs_diff_eq!(eval.integrated, target, epsilon = 1e-6);
+        }
+
+        let empty = Loudness::<f64, 1>::new(1.0);
+        assert_eq!(empty.gain_for_target(-23.0), None);
+    }
+
+    #[test]
+    fn range() {
+        let mut loudness = Loudness::<f64, 1>::new(1.0);
+
+        for i in 0..=100 {
+            loudness.push(block(-30.0 + (i as f64) * 0.1));
+        }
+
+        // Percentiles are taken from the nearest rank.
+        assert_abs_diff_eq!(loudness.range().unwrap(), 8.5, epsilon = 1e-9);
+        assert_abs_diff_eq!(loudness.range_with_gain(6.0).unwrap(), 8.5, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn channel_loudness() {
+        let mut loudness = Loudness::<[f64; 3], 3>::new([1.0, 1.0, 1.41]);
+
+        // The third channel only has sound in a block that is gated out.
+        loudness.push([block(-20.0), block(-26.0), 0.0]);
+        loudness.push([block(-22.0), block(-28.0), 0.0]);
+        loudness.push([0.0, 0.0, block(-50.0)]);
+
+        let channels = loudness.channel_loudness().unwrap();
+
+        assert_abs_diff_eq!(channels[0], Util::lufs((block(-20.0) + block(-22.0)) / 2.0), epsilon = 1e-9);
+        assert_abs_diff_eq!(channels[1], Util::lufs((block(-26.0) + block(-28.0)) / 2.0), epsilon = 1e-9);
+        assert_eq!(channels[2], f64::NEG_INFINITY);
+
+        // The channel powers add up to the programme loudness.
+        let total = channels.iter().map(|l| 10.0f64.powf((l + 0.691) / 10.0)).sum::<f64>();
+        assert_abs_diff_eq!(Util::lufs(total), loudness.calculate().unwrap(), epsilon = 1e-9);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut loudness = Loudness::<f64, 1>::new(1.0);
+        let mut tracked = Loudness::<f64, 1>::new(1.0);
+
+        assert_eq!(loudness.snapshot(), Snapshot::default());
+
+        for i in 0..500 {
+            let l = -75.0 + 60.0 * ((i as f64) * 0.11).sin().abs();
+            loudness.push(block(l));
+            tracked.push(block(l));
+
+            // The histogram also covers the blocks from before it was made.
+            if i == 100 {
+                tracked.track_snapshots();
+            }
+
+            let snapshot = loudness.snapshot();
+
+            assert_abs_diff_eq!(snapshot.latest.unwrap(), l, epsilon = 1e-9);
+            assert_eq!(snapshot.maximum, loudness.blocks.iter().map(|(l, _)| *l).reduce(f64::max));
+            assert_eq!(snapshot.integrated, loudness.evaluate(0.0).map(|e| e.integrated));
+
+            match (tracked.snapshot().integrated, snapshot.integrated) {
+                (Some(t), Some(e)) => assert_abs_diff_eq!(t, e, epsilon = 0.01),
+                (t, e) => assert_eq!(t, e),
+            }
+        }
+
+        let range = loudness.range().unwrap();
+        assert_eq!(loudness.snapshot().range, Some(range));
+        assert_abs_diff_eq!(tracked.snapshot().range.unwrap(), range, epsilon = 0.02);
+
+        // Resetting keeps the histogram, but empties it.
+        tracked.reset();
+        assert!(tracked.histogram.as_ref().unwrap().is_empty());
+        assert_eq!(tracked.snapshot(), Snapshot::default());
+    }
 }
@@ -1,36 +1,47 @@
+pub mod fixed_point;
 pub mod gating;
+pub mod histogram;
+pub mod length;
+#[cfg(feature = "alloc")]
 pub mod loudness;
+#[cfg(feature = "alloc")]
+pub mod sliding;
+pub mod sub_block;
 
+pub use fixed_point::*;
 pub use gating::*;
+pub use histogram::*;
+pub use length::*;
+#[cfg(feature = "alloc")]
 pub use loudness::*;
+#[cfg(feature = "alloc")]
+pub use sliding::*;
+pub use sub_block::*;
 
+#[cfg(feature = "alloc")]
 use sampara::{Frame, Calculator};
 
-pub struct GatedLoudness<F, const N: usize>
+/// Measures integrated loudness in one pass, by gating and averaging the
+/// blocks from a [`BlockGenerator`]. The standard momentary, short-term and
+/// custom gatings use [`GatedPowers`], and any other generator gets the same
+/// absolute and relative gating.
+#[cfg(feature = "alloc")]
+pub struct GatedLoudness<F, const N: usize, B = GatedPowers<F, N>>
 where
     F: Frame<N, Sample = f64>,
+    B: BlockGenerator<F, N>,
 {
-    gated_powers: GatedPowers<F, N>,
+    blocks: B,
     loudness: Loudness<F, N>,
 }
 
+#[cfg(feature = "alloc")]
 impl<F, const N: usize> GatedLoudness<F, N>
 where
     F: Frame<N, Sample = f64>,
 {
     pub fn new(sample_rate: u32, g_weights: F, gating: Gating) -> Self {
-        let gated_powers = GatedPowers::new(sample_rate, gating);
-        let loudness = Loudness::new(g_weights);
-
-        Self {
-            gated_powers,
-            loudness,
-        }
-    }
-
-    pub fn reset(&mut self) {
-        self.gated_powers.reset();
-        self.loudness.reset();
+        Self::with_blocks(GatedPowers::new(sample_rate, gating), g_weights)
     }
 
     pub fn momentary(sample_rate: u32, g_weights: F) -> Self {
@@ -46,15 +57,37 @@ where
     }
 }
 
-impl<F, const N: usize> Calculator for GatedLoudness<F, N>
+#[cfg(feature = "alloc")]
+impl<F, const N: usize, B> GatedLoudness<F, N, B>
+where
+    F: Frame<N, Sample = f64>,
+    B: BlockGenerator<F, N>,
+{
+    /// Creates a meter that takes its blocks from any block generator.
+    pub fn with_blocks(blocks: B, g_weights: F) -> Self {
+        Self {
+            blocks,
+            loudness: Loudness::new(g_weights),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.blocks.reset();
+        self.loudness.reset();
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<F, const N: usize, B> Calculator for GatedLoudness<F, N, B>
 where
     F: Frame<N, Sample = f64>,
+    B: BlockGenerator<F, N>,
 {
     type Input = F;
     type Output = Option<f64>;
 
     fn push(&mut self, input: Self::Input) {
-        if let Some(gp) = self.gated_powers.process(input) {
+        if let Some(gp) = self.blocks.push(input) {
             self.loudness.push(gp)
         }
     }
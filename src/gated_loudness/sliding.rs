@@ -0,0 +1,186 @@
+//! Integrated loudness over a sliding window of recent blocks, for continuous
+//! monitoring where a programme has no end.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use sampara::{Frame, Calculator};
+
+use crate::gated_loudness::{Gating, Histogram, Length};
+use crate::util::Util;
+
+/// Calculates the gated integrated loudness of only the most recent blocks,
+/// covering a fixed window of time.
+///
+/// Blocks are sorted into a [`Histogram`] as they arrive and removed from it
+/// as they expire, so each block costs O(1) and a reading costs O(bins) no
+/// matter how long the window is. Readings are accurate to within 0.01 LU.
+#[derive(Debug, Clone)]
+pub struct SlidingLoudness<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    // The loudness and weighted power of each block in the window, oldest
+    // first.
+    blocks: VecDeque<(f64, f64)>,
+    window_blocks: usize,
+    histogram: Box<Histogram>,
+    g_weights: F,
+}
+
+impl<F, const N: usize> SlidingLoudness<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Creates a new instance for blocks with the given gating at a sample
+    /// rate, keeping only the blocks that lie entirely within the last
+    /// `window_ms`.
+    pub fn new(g_weights: F, gating: Gating, sample_rate: u32, window_ms: u64) -> Self {
+        let (gate_len, step) = gating.frames(sample_rate);
+        let window = Length::ms(window_ms).frames(sample_rate);
+
+        let gate = gate_len as u64 * window.den();
+
+        assert!(window.num() >= gate, "window is shorter than a block");
+
+        // The number of whole steps from the first block in the window to the
+        // last one.
+        let steps = (window.num() - gate) * step.den() / (window.den() * step.num());
+
+        Self::with_blocks(g_weights, steps as usize + 1)
+    }
+
+    /// Creates a new instance that keeps the last `window_blocks` blocks.
+    pub fn with_blocks(g_weights: F, window_blocks: usize) -> Self {
+        assert!(window_blocks > 0);
+
+        Self {
+            blocks: VecDeque::with_capacity(window_blocks),
+            window_blocks,
+            histogram: Histogram::boxed(),
+            g_weights,
+        }
+    }
+
+    pub fn push(&mut self, gated_powers: F) {
+        self.push_power(Util::weighted_power(gated_powers, self.g_weights));
+    }
+
+    /// Adds a block given its weighted power, such as one returned by
+    /// [`Self::block_powers`].
+    pub(crate) fn push_power(&mut self, power: f64) {
+        if self.blocks.len() == self.window_blocks {
+            if let Some((loudness, power)) = self.blocks.pop_front() {
+                self.histogram.remove(loudness, power);
+            }
+        }
+
+        let loudness = Util::lufs(power);
+
+        self.histogram.push(loudness, power);
+        self.blocks.push_back((loudness, power));
+    }
+
+    /// Iterates over the weighted power of each block in the window, oldest
+    /// first. Pushing them into a new instance recreates this one exactly.
+    pub(crate) fn block_powers(&self) -> impl Iterator<Item = f64> + '_ {
+        self.blocks.iter().map(|(_, p)| *p)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.histogram.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        self.blocks.clear();
+        self.histogram.reset();
+    }
+
+    /// The integrated loudness of the blocks in the window.
+    pub fn integrated(&self) -> Option<f64> {
+        self.histogram.integrated()
+    }
+
+    /// The loudness range (LRA) of the blocks in the window.
+    pub fn range(&self) -> Option<f64> {
+        self.histogram.range()
+    }
+
+    pub fn calculate(self) -> Option<f64> {
+        self.integrated()
+    }
+}
+
+impl<F, const N: usize> Calculator for SlidingLoudness<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    type Input = F;
+    type Output = Option<f64>;
+
+    fn push(&mut self, gated_powers: Self::Input) {
+        self.push(gated_powers)
+    }
+
+    fn calculate(self) -> Self::Output {
+        self.calculate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gated_loudness::Loudness;
+
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn matches_recent_blocks() {
+        // A 10 second window of momentary blocks.
+        let mut sliding = SlidingLoudness::<f64, 1>::new(1.0, Gating::Momentary, 48000, 10_000);
+        assert_eq!(sliding.window_blocks, 97);
+
+        // The same window holds the same blocks with a fractional step.
+        for &sample_rate in [11025, 44100].iter() {
+            let other = SlidingLoudness::<f64, 1>::new(1.0, Gating::Momentary, sample_rate, 10_000);
+            assert_eq!(other.window_blocks, 97);
+        }
+
+        let powers = (0..5000)
+            .map(|i| {
+                let loudness = -50.0 + 40.0 * ((i as f64) * 0.013).sin() * ((i as f64) * 0.0007).cos();
+                10.0f64.powf((loudness + 0.691) / 10.0)
+            })
+            .collect::<Vec<_>>();
+
+        for (i, &power) in powers.iter().enumerate() {
+            sliding.push(power);
+
+            if i % 50 != 0 {
+                continue;
+            }
+
+            let start = (i + 1).saturating_sub(sliding.window_blocks);
+            let mut expected = Loudness::<f64, 1>::new(1.0);
+            for &p in powers[start..=i].iter() {
+                expected.push(p);
+            }
+
+            match expected.evaluate(0.0) {
+                Some(eval) => {
+                    assert_abs_diff_eq!(sliding.integrated().unwrap(), eval.integrated, epsilon = 0.01);
+                },
+                None => assert_eq!(sliding.integrated(), None),
+            }
+        }
+
+        // Once only silence is left in the window, there is no reading.
+        for _ in 0..sliding.window_blocks {
+            sliding.push(0.0);
+        }
+
+        assert!(sliding.is_empty());
+        assert_eq!(sliding.integrated(), None);
+    }
+}
@@ -0,0 +1,419 @@
+//! Gated block powers assembled from shared, fixed-length sub-blocks.
+//!
+//! Every gating with a gate and step length that are both multiples of the
+//! sub-block length can be derived from the same stream of sub-block power
+//! sums, so the per-frame work only needs to be done once no matter how many
+//! gatings are being calculated. The sub-block length may be a fractional
+//! number of frames, in which case sub-blocks vary in length by one frame so
+//! that their boundaries stay on the exact timeline.
+//!
+//! Gatings that share no sub-block of at least one frame, such as a 401 ms
+//! gate with a 100 ms step at 11025 Hz, fall back to sub-blocks of a single
+//! frame. Their steps are then a fractional number of sub-blocks, and each
+//! gated block ends on the last frame before its exact end.
+
+use core::marker::PhantomData;
+
+#[cfg(feature = "alloc")]
+use alloc::vec;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use sampara::Frame;
+use sampara::sample::{FloatSample, Sample};
+
+#[cfg(feature = "alloc")]
+use crate::checkpoint;
+use crate::gated_loudness::{FractionalStep, Frames};
+
+/// Returns the longest sub-block that evenly divides the gate and step lengths
+/// of every gating given as `(gate_len, step)`, or a single frame if that
+/// would be shorter than one frame.
+pub fn shared_sub_block<I>(lengths: I) -> Frames
+where
+    I: IntoIterator<Item = (usize, Frames)>,
+{
+    let sub_block = lengths.into_iter()
+        .fold(Frames::whole(0), |acc, (gate_len, step)| acc.gcd(Frames::from(gate_len)).gcd(step));
+
+    if sub_block.num() < sub_block.den() { Frames::whole(1) }
+    else { sub_block }
+}
+
+/// Accumulates the per-channel sums of squares of an input signal, and emits
+/// them once every sub-block of frames.
+#[derive(Clone)]
+pub struct SubBlockPowers<F, const N: usize>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+{
+    sum: F,
+    len: FractionalStep,
+    count: usize,
+}
+
+impl<F, const N: usize> SubBlockPowers<F, N>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+{
+    /// Creates a new instance, with sub-blocks of the given exact length,
+    /// which must be at least one frame.
+    pub fn new(len: Frames) -> Self {
+        assert!(len.num() >= len.den(), "sub-block is shorter than one frame");
+
+        Self {
+            sum: Frame::EQUILIBRIUM,
+            len: FractionalStep::new(len),
+            count: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.sum = Frame::EQUILIBRIUM;
+        self.len.reset();
+        self.count = 0;
+    }
+
+    /// Returns the partial sum and frame count of the current sub-block, and
+    /// the fractional phase of its start.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn state(&self) -> (F, usize, u64) {
+        (self.sum, self.count, self.len.phase())
+    }
+
+    /// Checks whether a frame count and phase could have come from this
+    /// instance.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn is_valid_state(&self, count: usize, phase: u64) -> bool {
+        if !self.len.is_valid_phase(phase) {
+            return false;
+        }
+
+        let mut len = self.len;
+        len.set_phase(phase);
+
+        (count as u64) < len.current_len()
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn set_state(&mut self, sum: F, count: usize, phase: u64) {
+        assert!(self.is_valid_state(count, phase));
+
+        self.sum = sum;
+        self.count = count;
+        self.len.set_phase(phase);
+    }
+
+    /// Adds a frame to the current sub-block. If this completes the
+    /// sub-block, its per-channel sums of squares are returned.
+    pub fn push(&mut self, input: F) -> Option<F> {
+        self.sum.zip_transform(input, |s, x| s + x * x);
+        self.count += 1;
+
+        if self.count as u64 == self.len.current_len() {
+            let sum = self.sum;
+            self.sum = Frame::EQUILIBRIUM;
+            self.count = 0;
+            self.len.advance();
+            Some(sum)
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<F, const N: usize> SubBlockPowers<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// The number of values that [`Self::save`] appends.
+    pub(crate) const SAVED_LEN: usize = 2 + N;
+
+    /// Appends the frame count and phase of the current sub-block, followed
+    /// by its partial sum, to a list of numbers.
+    pub(crate) fn save(&self, state: &mut Vec<f64>) {
+        let (sum, count, phase) = self.state();
+
+        state.push(count as f64);
+        state.push(phase as f64);
+        state.extend(sum.into_channels());
+    }
+
+    /// Restores a state appended by [`Self::save`], or returns `false`
+    /// without changing anything if it could not have come from this
+    /// instance.
+    pub(crate) fn load(&mut self, state: &[f64]) -> bool {
+        if state.len() != Self::SAVED_LEN {
+            return false;
+        }
+
+        let (count, phase) = match (checkpoint::whole(state[0]), checkpoint::whole(state[1])) {
+            (Some(count), Some(phase)) => (count as usize, phase),
+            _ => return false,
+        };
+
+        if !self.is_valid_state(count, phase) {
+            return false;
+        }
+
+        match checkpoint::unflatten(&state[2..]).next() {
+            Some(sum) => self.set_state(sum, count, phase),
+            None => return false,
+        }
+
+        true
+    }
+}
+
+/// Combines consecutive sub-block sums into gated blocks, emitting the mean
+/// squares (powers) of each gated block. The sub-block sums are kept in a ring
+/// buffer of type `B`, which can be either heap-allocated or fixed-capacity.
+#[derive(Clone)]
+pub struct SubBlockGate<F, const N: usize, B>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+    B: AsRef<[F]> + AsMut<[F]>,
+{
+    ring: B,
+    len: usize,
+    pos: usize,
+    filled: usize,
+
+    // The number of sub-blocks to advance between gated blocks, which is only
+    // fractional with single-frame sub-blocks, and the number that have been
+    // seen since the last gated block was emitted.
+    step: FractionalStep,
+    since: usize,
+
+    // The total number of frames covered by a gated block.
+    gate_frames: f64,
+
+    _marker: PhantomData<F>,
+}
+
+#[cfg(feature = "alloc")]
+impl<F, const N: usize> SubBlockGate<F, N, Vec<F>>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+{
+    /// Creates a new gate, with the gate and step lengths given in frames.
+    /// The gate must be a non-zero multiple of the sub-block length, and so
+    /// must the step, unless sub-blocks are a single frame long.
+    pub fn new(sub_block: Frames, gate_len: usize, step: Frames) -> Self {
+        assert!(!sub_block.is_zero());
+
+        let ring = vec![Frame::EQUILIBRIUM; Frames::from(gate_len).count(sub_block) as usize];
+
+        Self::with_ring(ring, sub_block, gate_len, step)
+    }
+}
+
+impl<F, const N: usize, B> SubBlockGate<F, N, B>
+where
+    F: Frame<N>,
+    F::Sample: FloatSample,
+    B: AsRef<[F]> + AsMut<[F]>,
+{
+    /// Creates a new gate that keeps its sub-block sums in the given ring
+    /// buffer, which must have room for at least as many sub-blocks as there
+    /// are in the gate. The gate and step lengths are given in frames. The
+    /// gate must be a non-zero multiple of the sub-block length, and so must
+    /// the step, unless sub-blocks are a single frame long.
+    pub fn with_ring(ring: B, sub_block: Frames, gate_len: usize, step: Frames) -> Self {
+        assert!(!sub_block.is_zero());
+        assert!(gate_len > 0 && !step.is_zero());
+
+        // This panics unless the gate is a multiple of the sub-block.
+        let len = Frames::from(gate_len).count(sub_block) as usize;
+
+        let step = if sub_block == Frames::whole(1) { step }
+        else { Frames::whole(step.count(sub_block)) };
+
+        assert!(ring.as_ref().len() >= len, "ring buffer too small for gate");
+
+        let mut gate = Self {
+            ring,
+            len,
+            pos: 0,
+            filled: 0,
+            step: FractionalStep::new(step),
+            since: 0,
+            gate_frames: gate_len as f64,
+            _marker: PhantomData,
+        };
+
+        gate.reset();
+        gate
+    }
+
+    pub fn reset(&mut self) {
+        self.ring.as_mut().fill(Frame::EQUILIBRIUM);
+        self.pos = 0;
+        self.filled = 0;
+        self.since = 0;
+        self.step.reset();
+    }
+
+    /// Returns the sub-block sums in the ring buffer, along with the ring
+    /// position, the number of filled sub-blocks, the number of sub-blocks
+    /// since the last gated block and the fractional phase of the step.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn state(&self) -> (&[F], usize, usize, usize, u64) {
+        (&self.ring.as_ref()[..self.len], self.pos, self.filled, self.since, self.step.phase())
+    }
+
+    /// Checks whether a state could have come from a gate with the same
+    /// lengths as this one.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn is_valid_state(&self, ring: &[F], pos: usize, filled: usize, since: usize, phase: u64) -> bool {
+        if ring.len() != self.len || pos >= self.len || filled > self.len || !self.step.is_valid_phase(phase) {
+            return false;
+        }
+
+        let mut step = self.step;
+        step.set_phase(phase);
+
+        (since as u64) < step.current_len()
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn set_state(&mut self, ring: &[F], pos: usize, filled: usize, since: usize, phase: u64) {
+        assert!(self.is_valid_state(ring, pos, filled, since, phase));
+
+        self.ring.as_mut()[..self.len].copy_from_slice(ring);
+        self.pos = pos;
+        self.filled = filled;
+        self.since = since;
+        self.step.set_phase(phase);
+    }
+
+    /// Adds a sub-block sum. If this starts a new step, the powers of the
+    /// gated block ending with this sub-block are returned.
+    pub fn push(&mut self, sub_block_sum: F) -> Option<F> {
+        let ring = &mut self.ring.as_mut()[..self.len];
+
+        ring[self.pos] = sub_block_sum;
+        self.pos = (self.pos + 1) % self.len;
+
+        if self.filled < self.len {
+            // The first gated block is emitted as soon as the gate is full.
+            self.filled += 1;
+
+            if self.filled < self.len {
+                return None;
+            }
+        }
+        else {
+            self.since += 1;
+
+            if (self.since as u64) < self.step.current_len() {
+                return None;
+            }
+
+            self.step.advance();
+        }
+
+        self.since = 0;
+
+        let mut total: F = Frame::EQUILIBRIUM;
+        for s in self.ring.as_ref()[..self.len].iter() {
+            total.zip_transform(*s, |t, x| t + x);
+        }
+
+        let gate_frames = F::Sample::from_sample(self.gate_frames);
+        for t in total.channels_mut() {
+            *t = *t / gate_frames;
+        }
+
+        Some(total)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<F, const N: usize, B> SubBlockGate<F, N, B>
+where
+    F: Frame<N, Sample = f64>,
+    B: AsRef<[F]> + AsMut<[F]>,
+{
+    /// The number of values that [`Self::save`] appends.
+    pub(crate) fn saved_len(&self) -> usize {
+        4 + self.len * N
+    }
+
+    /// Appends the ring position, the number of filled sub-blocks, the number
+    /// of sub-blocks since the last gated block and the phase of the step,
+    /// followed by the sub-block sums in the ring buffer, to a list of
+    /// numbers.
+    pub(crate) fn save(&self, state: &mut Vec<f64>) {
+        let (ring, pos, filled, since, phase) = self.state();
+
+        state.extend([pos as f64, filled as f64, since as f64, phase as f64].iter());
+        state.extend(checkpoint::flatten(ring.iter().copied()));
+    }
+
+    /// Restores a state appended by [`Self::save`], or returns `false`
+    /// without changing anything if it could not have come from a gate with
+    /// the same lengths as this one.
+    pub(crate) fn load(&mut self, state: &[f64]) -> bool {
+        if state.len() != self.saved_len() {
+            return false;
+        }
+
+        let mut counts = state[..4].iter().map(|x| checkpoint::whole(*x));
+        let (pos, filled, since, phase) = match (counts.next(), counts.next(), counts.next(), counts.next()) {
+            (Some(Some(pos)), Some(Some(filled)), Some(Some(since)), Some(Some(phase))) => {
+                (pos as usize, filled as usize, since as usize, phase)
+            },
+            _ => return false,
+        };
+
+        let ring = checkpoint::unflatten(&state[4..]).collect::<Vec<F>>();
+
+        if !self.is_valid_state(&ring, pos, filled, since, phase) {
+            return false;
+        }
+
+        self.set_state(&ring, pos, filled, since, phase);
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn sub_block_gate() {
+        let mut sub_blocks = SubBlockPowers::<f64, 1>::new(Frames::whole(2));
+        let mut gate = SubBlockGate::<f64, 1, _>::with_ring([0.0; 4], Frames::whole(2), 6, Frames::whole(4));
+
+        let inputs = [1.0, 1.0, 2.0, 2.0, 3.0, 3.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0];
+        let mut produced = vec![];
+
+        for &x in inputs.iter() {
+            if let Some(sum) = sub_blocks.push(x) {
+                if let Some(p) = gate.push(sum) {
+                    produced.push(p);
+                }
+            }
+        }
+
+        // The first block is emitted after 6 frames, and the next one 4 frames
+        // after that.
+        let expected = [(2.0 + 8.0 + 18.0) / 6.0, (18.0 + 0.0 + 2.0) / 6.0];
+
+        assert_eq!(expected.len(), produced.len());
+        for (e, p) in expected.iter().zip(produced.iter()) {
+            assert_abs_diff_eq!(*e, *p);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Leq(m) measurement of cinema trailers and adverts, as used in ISO 21727 and
+//! SMPTE RP 200 workflows.
+
+use sampara::{Frame, Calculator};
+
+use crate::filter::{StandardWeighting, UnsupportedSampleRate, WeightingFilter};
+use crate::math;
+use crate::util::Util;
+
+// The reading of a full-scale RMS signal in a single channel, which puts a
+// single channel at -20 dBFS RMS at the reference level of 85.
+const CALIBRATION_OFFSET: f64 = 105.0;
+
+/// Channel weights for 5.1 programme in SMPTE order (L, R, C, LFE, Ls, Rs).
+/// All main channels are summed with equal weight, and the LFE channel is
+/// left out.
+pub const LEQ_M_WEIGHTS_5_1: [f64; 6] = [1.0, 1.0, 1.0, 0.0, 1.0, 1.0];
+
+/// Channel weights for 7.1 programme in SMPTE order (L, R, C, LFE, Lss, Rss,
+/// Lrs, Rrs).
+pub const LEQ_M_WEIGHTS_7_1: [f64; 8] = [1.0, 1.0, 1.0, 0.0, 1.0, 1.0, 1.0, 1.0];
+
+/// Calculates the Leq(m) of a programme, which is the M-weighted energy
+/// average of all channels over the whole programme, with no gating.
+///
+/// The energy of each channel is scaled by its weight and summed. The result
+/// is calibrated so that a 2 kHz tone at -20 dBFS RMS in a single channel
+/// reads close to 85.
+pub struct LeqM<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    filter: WeightingFilter<F, N>,
+    g_weights: [f64; N],
+    sum: f64,
+    frames: u64,
+}
+
+impl<F, const N: usize> LeqM<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Creates a new measurement.
+    ///
+    /// Panics if the sample rate is not supported by the weighting filters.
+    pub fn new(sample_rate: u32, g_weights: F) -> Self {
+        match Self::try_new(sample_rate, g_weights) {
+            Ok(leq) => leq,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates a new measurement, or returns an error if the sample rate is
+    /// not supported by the weighting filters.
+    pub fn try_new(sample_rate: u32, g_weights: F) -> Result<Self, UnsupportedSampleRate> {
+        Ok(Self {
+            filter: WeightingFilter::try_new(sample_rate, &StandardWeighting::M)?,
+            g_weights: Util::frame_to_array(g_weights),
+            sum: 0.0,
+            frames: 0,
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.filter.reset();
+        self.sum = 0.0;
+        self.frames = 0;
+    }
+
+    pub fn push(&mut self, input: F) {
+        let mut x = Util::frame_to_array(input);
+        self.filter.process_array(&mut x);
+
+        self.sum += x.iter().zip(self.g_weights.iter()).map(|(x, w)| w * x * x).sum::<f64>();
+        self.frames += 1;
+    }
+
+    pub fn feed<I>(&mut self, frames: I)
+    where
+        I: IntoIterator<Item = F>,
+    {
+        for frame in frames.into_iter() {
+            self.push(frame);
+        }
+    }
+
+    /// The Leq(m) of all frames so far, or `None` if no frames have been
+    /// pushed.
+    pub fn leq(&self) -> Option<f64> {
+        if self.frames == 0 { None }
+        else { Some(10.0 * math::log10(self.sum / self.frames as f64) + CALIBRATION_OFFSET) }
+    }
+
+    pub fn calculate(self) -> Option<f64> {
+        self.leq()
+    }
+}
+
+impl<F, const N: usize> Calculator for LeqM<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    type Input = F;
+    type Output = Option<f64>;
+
+    fn push(&mut self, input: Self::Input) {
+        self.push(input)
+    }
+
+    fn calculate(self) -> Self::Output {
+        self.calculate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use approx::assert_abs_diff_eq;
+
+    use crate::test_util::TestUtil;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    // A 2 kHz tone at -20 dBFS RMS, in each of the given channels.
+    fn tone(channels: &[usize]) -> impl Iterator<Item = [f64; 6]> + '_ {
+        let amp = 0.1 * 2.0f64.sqrt();
+
+        TestUtil::sine(SAMPLE_RATE, 2000.0, 10.0, move |_| amp).map(move |x| {
+            let mut frame = [0.0; 6];
+            for &c in channels {
+                frame[c] = x;
+            }
+            frame
+        })
+    }
+
+    #[test]
+    fn calibration() {
+        let mut leq = LeqM::new(SAMPLE_RATE, LEQ_M_WEIGHTS_5_1);
+        assert_eq!(leq.leq(), None);
+
+        leq.feed(tone(&[2]));
+        assert_abs_diff_eq!(leq.leq().unwrap(), 85.0, epsilon = 0.1);
+
+        // Channel energies add up, except for the LFE channel.
+        let mut leq = LeqM::new(SAMPLE_RATE, LEQ_M_WEIGHTS_5_1);
+        leq.feed(tone(&[0, 1, 2, 3, 4, 5]));
+        assert_abs_diff_eq!(leq.calculate().unwrap(), 85.0 + 10.0 * 5.0f64.log10(), epsilon = 0.1);
+    }
+}
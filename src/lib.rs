@@ -1,15 +1,38 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![feature(array_methods, array_zip, bool_to_option, box_into_inner, option_result_contains)]
+#![cfg_attr(feature = "simd", feature(portable_simd))]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
 pub mod filter;
 pub mod util;
 pub mod gated_loudness;
 pub mod peak;
+pub mod balance;
+pub mod leq;
+pub mod non_finite;
+#[cfg(feature = "alloc")]
+pub mod checkpoint;
+#[cfg(feature = "alloc")]
+pub mod events;
+#[cfg(feature = "alloc")]
 pub mod pipeline;
+#[cfg(feature = "alloc")]
+pub mod realtime;
+#[cfg(feature = "alloc")]
+pub mod segment_search;
+#[cfg(feature = "alloc")]
+pub mod silence;
+
+pub(crate) mod math;
 
 pub(crate) mod test_util;
 
 pub use filter::KWeightFilter;
-pub use gated_loudness::{GatedPowers, Loudness, Gating};
+pub use gated_loudness::{FixedGatedPowers, FixedLoudness, Gating};
+#[cfg(feature = "alloc")]
+pub use gated_loudness::{GatedPowers, Loudness};
 
 #[cfg(test)]
 mod tests {
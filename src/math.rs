@@ -0,0 +1,56 @@
+//! Floating point math functions that are provided by `std` when it is
+//! available, and by `libm` otherwise.
+
+#[cfg(not(any(feature = "std", feature = "libm")))]
+compile_error!("either the `std` or the `libm` feature must be enabled");
+
+#[cfg(feature = "std")]
+mod imp {
+    #[inline]
+    pub fn log10(x: f64) -> f64 { x.log10() }
+
+    #[inline]
+    pub fn powf(x: f64, y: f64) -> f64 { x.powf(y) }
+
+    #[inline]
+    pub fn tan(x: f64) -> f64 { x.tan() }
+
+    #[inline]
+    pub fn round(x: f64) -> f64 { x.round() }
+
+    #[inline]
+    pub fn sin(x: f64) -> f64 { x.sin() }
+
+    #[inline]
+    pub fn cos(x: f64) -> f64 { x.cos() }
+
+    #[inline]
+    pub fn atan2(y: f64, x: f64) -> f64 { y.atan2(x) }
+}
+
+#[cfg(all(not(feature = "std"), feature = "libm"))]
+mod imp {
+    #[inline]
+    pub fn log10(x: f64) -> f64 { libm::log10(x) }
+
+    #[inline]
+    pub fn powf(x: f64, y: f64) -> f64 { libm::pow(x, y) }
+
+    #[inline]
+    pub fn tan(x: f64) -> f64 { libm::tan(x) }
+
+    #[inline]
+    pub fn round(x: f64) -> f64 { libm::round(x) }
+
+    #[inline]
+    pub fn sin(x: f64) -> f64 { libm::sin(x) }
+
+    #[inline]
+    pub fn cos(x: f64) -> f64 { libm::cos(x) }
+
+    #[inline]
+    pub fn atan2(y: f64, x: f64) -> f64 { libm::atan2(y, x) }
+}
+
+#[cfg(any(feature = "std", feature = "libm"))]
+pub(crate) use imp::*;
@@ -0,0 +1,184 @@
+//! Handling of NaN and infinite input samples.
+//!
+//! A single non-finite sample, such as one from a broken decoder, would
+//! otherwise get stuck in the weighting filter state and poison every reading
+//! after it. Each stage that takes raw input checks it against a
+//! [`NonFinite`] policy, and counts the offending samples that it finds.
+
+use core::fmt;
+use core::ops::Range;
+
+/// What to do with input frames that contain NaN or infinite samples.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum NonFinite {
+    /// Refuses the frame, and returns an error from the `try_` processing
+    /// methods. The other processing methods panic instead. A refused frame
+    /// does not advance the position, so it can be fixed and pushed again.
+    Reject,
+
+    /// Replaces each non-finite sample with zero, and processes the frame as
+    /// usual.
+    Zero,
+
+    /// Drops the whole frame without measuring it. The frame still counts
+    /// towards the position, so that later frames keep their place on the
+    /// input timeline. Only a `Pipeline` can skip frames, since filters have
+    /// to output something for every frame.
+    Skip,
+}
+
+impl Default for NonFinite {
+    fn default() -> Self {
+        Self::Zero
+    }
+}
+
+/// The error returned when a frame is rejected for containing a non-finite
+/// sample.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NonFiniteError {
+    /// The position of the frame, counted from the start of the input.
+    pub frame: u64,
+
+    /// The first channel in the frame that holds a non-finite sample.
+    pub channel: usize,
+}
+
+impl fmt::Display for NonFiniteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "non-finite sample in channel {} of frame {}", self.channel, self.frame)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for NonFiniteError {}
+
+/// Running totals of the non-finite samples that have been found.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NonFiniteCounts {
+    /// The number of non-finite samples, across all channels.
+    pub samples: u64,
+
+    /// The number of frames holding at least one non-finite sample.
+    pub frames: u64,
+
+    /// The position of the first frame holding a non-finite sample.
+    pub first: Option<u64>,
+
+    /// The position of the last frame holding a non-finite sample.
+    pub last: Option<u64>,
+}
+
+/// A run of consecutive frames that held non-finite samples.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NonFiniteRun {
+    /// The frames covered by the run.
+    pub frames: Range<u64>,
+
+    /// The number of non-finite samples within the run, across all channels.
+    pub samples: u64,
+}
+
+/// Applies a [`NonFinite`] policy to input frames, and keeps count of the
+/// offending samples.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct NonFiniteGuard {
+    policy: NonFinite,
+    counts: NonFiniteCounts,
+}
+
+impl NonFiniteGuard {
+    pub fn new(policy: NonFinite) -> Self {
+        Self {
+            policy,
+            counts: NonFiniteCounts::default(),
+        }
+    }
+
+    pub fn policy(&self) -> NonFinite {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: NonFinite) {
+        self.policy = policy;
+    }
+
+    pub fn counts(&self) -> NonFiniteCounts {
+        self.counts
+    }
+
+    /// Replaces the running totals, such as when resuming from a checkpoint.
+    pub fn set_counts(&mut self, counts: NonFiniteCounts) {
+        self.counts = counts;
+    }
+
+    pub fn reset(&mut self) {
+        self.counts = NonFiniteCounts::default();
+    }
+
+    /// Checks a frame at the given position, applying the policy to it in
+    /// place. Returns whether the frame should be processed, or an error if
+    /// it is rejected. Rejected frames are not counted.
+    #[inline]
+    pub fn check<const N: usize>(&mut self, x: &mut [f64; N], frame: u64) -> Result<bool, NonFiniteError> {
+        let found = x.iter().filter(|s| !s.is_finite()).count();
+
+        if found == 0 {
+            return Ok(true);
+        }
+
+        if self.policy == NonFinite::Reject {
+            let channel = x.iter().position(|s| !s.is_finite()).unwrap_or(0);
+            return Err(NonFiniteError { frame, channel });
+        }
+
+        self.counts.samples += found as u64;
+        self.counts.frames += 1;
+        self.counts.first.get_or_insert(frame);
+        self.counts.last = Some(frame);
+
+        match self.policy {
+            NonFinite::Zero => {
+                for s in x.iter_mut().filter(|s| !s.is_finite()) {
+                    *s = 0.0;
+                }
+
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies() {
+        let mut guard = NonFiniteGuard::new(NonFinite::Zero);
+
+        let mut x = [1.0, f64::NAN, f64::NEG_INFINITY];
+        assert_eq!(guard.check(&mut x, 5), Ok(true));
+        assert_eq!(x, [1.0, 0.0, 0.0]);
+
+        let mut x = [0.5, 0.25, -0.5];
+        assert_eq!(guard.check(&mut x, 6), Ok(true));
+        assert_eq!(x, [0.5, 0.25, -0.5]);
+
+        guard.set_policy(NonFinite::Skip);
+
+        let mut x = [f64::INFINITY, 0.0, 0.0];
+        assert_eq!(guard.check(&mut x, 9), Ok(false));
+
+        assert_eq!(guard.counts(), NonFiniteCounts { samples: 3, frames: 2, first: Some(5), last: Some(9) });
+
+        guard.set_policy(NonFinite::Reject);
+
+        let mut x = [0.0, 0.0, f64::NAN];
+        assert_eq!(guard.check(&mut x, 10), Err(NonFiniteError { frame: 10, channel: 2 }));
+        assert_eq!(guard.counts().samples, 3);
+    }
+}
@@ -2,6 +2,11 @@
 
 use sampara::{Frame, Signal};
 
+use crate::math;
+
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
 /// Keeps a running absolute max of samples per channel that have been seen in
 /// a signal. Each channel is updated independently.
 pub struct RunningPeak<S, const N: usize>
@@ -27,6 +32,24 @@ where
             peaks: Frame::EQUILIBRIUM,
         }
     }
+
+    /// Returns the per-channel peaks seen so far.
+    pub fn peaks(&self) -> S::Frame {
+        self.peaks
+    }
+
+    /// Returns the per-channel peaks as if a gain of `gain` dB had been
+    /// applied to the input signal.
+    pub fn peaks_with_gain(&self, gain: f64) -> S::Frame {
+        let factor = math::powf(10.0, gain / 20.0);
+
+        let mut peaks = self.peaks;
+        for p in peaks.channels_mut() {
+            *p *= factor;
+        }
+
+        peaks
+    }
 }
 
 impl<S, const N: usize> Signal<N> for RunningPeak<S, N>
@@ -46,6 +69,94 @@ where
     }
 }
 
+// The interpolation filter from Annex 2 of BS.1770-4, split into its four
+// phases. Each phase is applied to the input samples, newest first.
+const COEFFS: [[f64; TAPS_PER_PHASE]; OVERSAMPLING] = [
+    [
+         0.0017089843750,  0.0109863281250, -0.0196533203125,  0.0332031250000,
+        -0.0594482421875,  0.1373291015625,  0.9721679687500, -0.1022949218750,
+         0.0476074218750, -0.0266113281250,  0.0148925781250, -0.0083007812500,
+    ],
+    [
+        -0.0291748046875,  0.0292968750000, -0.0517578125000,  0.0891113281250,
+        -0.1665039062500,  0.4650878906250,  0.7797851562500, -0.2003173828125,
+         0.1015625000000, -0.0582275390625,  0.0330810546875, -0.0189208984375,
+    ],
+    [
+        -0.0189208984375,  0.0330810546875, -0.0582275390625,  0.1015625000000,
+        -0.2003173828125,  0.7797851562500,  0.4650878906250, -0.1665039062500,
+         0.0891113281250, -0.0517578125000,  0.0292968750000, -0.0291748046875,
+    ],
+    [
+        -0.0083007812500,  0.0148925781250, -0.0266113281250,  0.0476074218750,
+        -0.1022949218750,  0.9721679687500,  0.1373291015625, -0.0594482421875,
+         0.0332031250000, -0.0196533203125,  0.0109863281250,  0.0017089843750,
+    ],
+];
+
+/// Estimates the true (inter-sample) peak of each channel, by oversampling by
+/// a factor of 4 as described in Annex 2 of the BS.1770 spec.
+///
+/// The interpolation filter is the 48-tap filter given in the spec, split
+/// into four phases of 12 taps each. Samples are processed in floating point,
+/// so the 12.04 dB of headroom that the spec reserves for integer
+/// implementations is not needed.
+#[derive(Clone, Debug)]
+pub struct TruePeak<const N: usize> {
+    // The most recent input samples for each channel, newest first.
+    history: [[f64; TAPS_PER_PHASE]; N],
+
+    peaks: [f64; N],
+}
+
+impl<const N: usize> Default for TruePeak<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> TruePeak<N> {
+    pub fn new() -> Self {
+        Self {
+            history: [[0.0; TAPS_PER_PHASE]; N],
+            peaks: [0.0; N],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.history = [[0.0; TAPS_PER_PHASE]; N];
+        self.peaks = [0.0; N];
+    }
+
+    /// Adds a frame, and returns the highest absolute true peak across all of
+    /// its channels, as a linear value.
+    pub fn push(&mut self, input: [f64; N]) -> f64 {
+        let mut frame_peak: f64 = 0.0;
+
+        for ((x, history), peak) in input.iter().zip(self.history.iter_mut()).zip(self.peaks.iter_mut()) {
+            history.copy_within(..TAPS_PER_PHASE - 1, 1);
+            history[0] = *x;
+
+            let mut channel_peak = x.abs();
+
+            for phase in COEFFS.iter() {
+                let y = phase.iter().zip(history.iter()).map(|(c, h)| c * h).sum::<f64>();
+                channel_peak = channel_peak.max(y.abs());
+            }
+
+            *peak = peak.max(channel_peak);
+            frame_peak = frame_peak.max(channel_peak);
+        }
+
+        frame_peak
+    }
+
+    /// Returns the per-channel true peaks seen so far, as linear values.
+    pub fn peaks(&self) -> [f64; N] {
+        self.peaks
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -104,5 +215,59 @@ mod tests {
 
         assert_eq!(running_peak.next(), None);
         assert_eq!(running_peak.peaks, 0.0);
+
+        let frames = [[0.5, -0.25]];
+        let mut running_peak = RunningPeak::new(
+            signal::from_frames(frames.iter().copied())
+        );
+
+        while running_peak.next().is_some() {}
+
+        let expected = [1.0, 0.5];
+        let produced = running_peak.peaks_with_gain(20.0 * 2.0f64.log10());
+        for (e, p) in expected.into_channels().zip(produced.into_channels()) {
+            assert_abs_diff_eq!(e, p, epsilon = 1e-12);
+        }
+    }
+
+    #[test]
+    fn true_peak() {
+        use std::f64::consts::PI;
+
+        // The true peak test signals 15 to 19 from EBU Tech 3341, at 48 kHz:
+        // sines given as (fraction of the sample rate, phase in degrees, peak
+        // in dBFS), which must read their peak level to within +0.2/-0.4 dB.
+        // A 10 ms fade in keeps the onset from ringing through the filter.
+        let cases = [
+            (1.0 / 4.0, 0.0, -6.0),
+            (1.0 / 4.0, 45.0, -6.0),
+            (1.0 / 6.0, 60.0, -6.0),
+            (1.0 / 8.0, 67.5, -6.0),
+            (1.0 / 4.0, 45.0, 3.0),
+        ];
+
+        for &(freq, phase, peak_db) in cases.iter() {
+            let amp = 10.0f64.powf(peak_db / 20.0);
+            let phase = phase * PI / 180.0;
+
+            let mut true_peak = TruePeak::<1>::new();
+            let mut sample_peak: f64 = 0.0;
+
+            for i in 0..4800 {
+                let fade = (i as f64 / 480.0).min(1.0);
+                let x = fade * amp * (2.0 * PI * freq * i as f64 + phase).sin();
+                sample_peak = sample_peak.max(x.abs());
+                true_peak.push([x]);
+            }
+
+            let produced = 20.0 * true_peak.peaks()[0].log10();
+            assert!(
+                produced <= peak_db + 0.2 && produced >= peak_db - 0.4,
+                "expected {} dBTP, produced {}", peak_db, produced,
+            );
+
+            // The sample peak can fall well short of the true peak.
+            assert!(true_peak.peaks()[0] >= sample_peak);
+        }
     }
 }
@@ -1,23 +1,445 @@
-use std::collections::{HashMap, HashSet};
+use core::fmt;
+use core::ops::Range;
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
 
 use sampara::{Frame, Calculator};
+use sampara::biquad::Params;
+
+use crate::balance::{Balance, Channel};
+use crate::checkpoint::{self, ChainCheckpoint, Checkpoint, CheckpointError, MeterCheckpoint, CHECKPOINT_VERSION};
+use crate::filter::{Denormals, StandardWeighting, UnsupportedSampleRate, Weighting, WeightingFilter, MAX_SECTIONS};
+use crate::gated_loudness::{
+    shared_sub_block, Accumulation, BlockGenerator, GatedPowers, Gating, Loudness, SlidingLoudness, Snapshot,
+    SubBlockPowers, SubBlockGate,
+};
+use crate::math;
+use crate::non_finite::{NonFinite, NonFiniteCounts, NonFiniteError, NonFiniteGuard, NonFiniteRun};
+use crate::util::Util;
+
+// How far ahead of a region to start decoding after a seek, so that the
+// K-weighting filter has settled by the time the region starts.
+const SEEK_PREROLL_MS: u64 = 500;
+
+// Sliding window measurements are keyed by their block source and window
+// length, which is in milliseconds for gatings and in blocks for schemes.
+type SlidingKey = (BlockSource, u64);
+
+// A block generator that can be cloned from behind a box, so that every chain
+// gets its own copy.
+trait CloneBlocks<F, const N: usize>: BlockGenerator<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    fn box_clone(&self) -> Box<dyn CloneBlocks<F, N>>;
+}
+
+impl<F, B, const N: usize> CloneBlocks<F, N> for B
+where
+    F: Frame<N, Sample = f64>,
+    B: BlockGenerator<F, N> + Clone + 'static,
+{
+    fn box_clone(&self) -> Box<dyn CloneBlocks<F, N>> {
+        Box::new(self.clone())
+    }
+}
+
+impl<F, const N: usize> Clone for Box<dyn CloneBlocks<F, N>>
+where
+    F: Frame<N, Sample = f64>,
+{
+    fn clone(&self) -> Self {
+        // The box itself is also a generator, so this has to go through the
+        // inner value to avoid cloning the box recursively.
+        (**self).box_clone()
+    }
+}
+
+impl<F, const N: usize> fmt::Debug for Box<dyn CloneBlocks<F, N>>
+where
+    F: Frame<N, Sample = f64>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BlockGenerator")
+    }
+}
+
+/// Where the blocks of a measurement come from. Every reading of a pipeline
+/// is keyed by its block source.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BlockSource {
+    /// The blocks of a standard or custom gating.
+    Gating(Gating),
+
+    /// The blocks of a user-defined scheme, added with
+    /// [`PipelineBuilder::scheme`] under this name.
+    Scheme(String),
+}
+
+impl From<Gating> for BlockSource {
+    fn from(gating: Gating) -> Self {
+        Self::Gating(gating)
+    }
+}
 
-use crate::filter::KWeightFilter;
-use crate::gated_loudness::{Gating, GatedLoudness};
+impl From<&str> for BlockSource {
+    fn from(name: &str) -> Self {
+        Self::Scheme(String::from(name))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Output {
-    pub averages: HashMap<Gating, Option<f64>>,
-    pub maximums: HashMap<Gating, Option<f64>>,
+    /// The integrated loudness for every gating added with
+    /// [`PipelineBuilder::average`], and for every block scheme.
+    pub averages: BTreeMap<BlockSource, Option<f64>>,
+
+    /// The integrated loudness for every gating added with
+    /// [`PipelineBuilder::maximum`], calculated in the same way as
+    /// [`GatedLoudness`](crate::gated_loudness::GatedLoudness).
+    pub maximums: BTreeMap<BlockSource, Option<f64>>,
+
+    /// The integrated loudness of each channel on its own, for every source
+    /// in `averages`, using the same gated blocks. See
+    /// [`Loudness::channel_loudness`] for details.
+    pub channel_averages: BTreeMap<BlockSource, Option<Vec<f64>>>,
+
+    /// The balance between the channels in `channel_averages`, for every
+    /// source in `averages`. This is empty unless the role of each channel
+    /// was given with [`PipelineBuilder::roles`].
+    pub balance: BTreeMap<BlockSource, Option<Balance>>,
+
+    /// The integrated loudness over the final window of each sliding window
+    /// measurement, keyed by its source and window length. The window length
+    /// is in milliseconds for gatings, and in blocks for schemes.
+    pub sliding_averages: BTreeMap<SlidingKey, Option<f64>>,
+
+    pub regions: Vec<RegionOutput>,
+
+    /// The runs of consecutive frames that held NaN or infinite samples, in
+    /// order, including any from before the pipeline was resumed from a
+    /// checkpoint.
+    pub non_finite: Vec<NonFiniteRun>,
+}
+
+/// The results for a single region, which are measured independently of the
+/// programme and of any other regions.
+#[derive(Debug, Clone)]
+pub struct RegionOutput {
+    /// The frames covered by the region.
+    pub range: Range<u64>,
+    pub averages: BTreeMap<BlockSource, Option<f64>>,
+    pub maximums: BTreeMap<BlockSource, Option<f64>>,
+    pub channel_averages: BTreeMap<BlockSource, Option<Vec<f64>>>,
+    pub balance: BTreeMap<BlockSource, Option<Balance>>,
+    pub sliding_averages: BTreeMap<SlidingKey, Option<f64>>,
+}
+
+/// The final readings of a single chain.
+#[derive(Default)]
+struct Results {
+    averages: BTreeMap<BlockSource, Option<f64>>,
+    maximums: BTreeMap<BlockSource, Option<f64>>,
+    channel_averages: BTreeMap<BlockSource, Option<Vec<f64>>>,
+    balance: BTreeMap<BlockSource, Option<Balance>>,
+    sliding_averages: BTreeMap<SlidingKey, Option<f64>>,
+}
+
+/// The loudness state that is fed from the blocks of a single source.
+struct BlockMeter<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    // Every block of the whole stream, which is only kept if it is needed for
+    // an average or a maximum.
+    loudness: Option<Loudness<F, N>>,
+
+    // Sliding windows over the same blocks, keyed by window length. This is
+    // in milliseconds for gatings, and in blocks for schemes.
+    sliding: BTreeMap<u64, SlidingLoudness<F, N>>,
+}
+
+impl<F, const N: usize> BlockMeter<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    fn reset(&mut self) {
+        if let Some(loudness) = self.loudness.as_mut() {
+            loudness.reset();
+        }
+
+        for sliding in self.sliding.values_mut() {
+            sliding.reset();
+        }
+    }
+
+    fn push(&mut self, gated_powers: F) {
+        if let Some(loudness) = self.loudness.as_mut() {
+            loudness.push(gated_powers);
+        }
+
+        for sliding in self.sliding.values_mut() {
+            sliding.push(gated_powers);
+        }
+    }
+
+    fn snapshot(&self) -> Option<Snapshot> {
+        self.loudness.as_ref().map(Loudness::snapshot)
+    }
+
+    fn loudness(&self) -> &Loudness<F, N> {
+        self.loudness.as_ref().expect("no whole-stream loudness is kept")
+    }
+
+    fn integrated(&self) -> Option<f64> {
+        self.loudness().evaluate(0.0).map(|e| e.integrated)
+    }
+
+    fn channel_loudness(&self) -> Option<Vec<f64>> {
+        self.loudness().channel_loudness().map(|c| c.to_vec())
+    }
+
+    fn balance(&self, roles: &[Channel; N]) -> Option<Balance> {
+        self.loudness().balance(roles)
+    }
+
+    // Returns the powers of every block of the whole stream, and the weighted
+    // power of every block within each sliding window.
+    fn save(&self) -> (Vec<f64>, Vec<Vec<f64>>) {
+        let blocks = checkpoint::flatten(self.loudness.iter().flat_map(|l| l.block_powers()));
+        let sliding = self.sliding.values().map(|s| s.block_powers().collect()).collect();
+
+        (blocks, sliding)
+    }
+
+    fn restore(&mut self, blocks: &[f64], sliding: &[Vec<f64>]) -> Result<(), CheckpointError> {
+        if sliding.len() != self.sliding.len() || (self.loudness.is_none() && !blocks.is_empty()) {
+            return Err(CheckpointError::Mismatch);
+        }
+
+        self.reset();
+
+        if let Some(loudness) = self.loudness.as_mut() {
+            for block in checkpoint::unflatten(blocks) {
+                loudness.push(block);
+            }
+        }
+
+        // Sliding windows only save the blocks within the window.
+        for (window, saved_powers) in self.sliding.values_mut().zip(sliding.iter()) {
+            for &power in saved_powers.iter() {
+                window.push_power(power);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Where a meter gets its blocks from.
+enum Gate<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Blocks laid out by a gating, which are assembled from the sub-block
+    /// sums that are shared across all such gatings.
+    Shared(SubBlockGate<F, N, Vec<F>>),
+
+    /// Any other blocks, from a generator that is fed every frame.
+    Generator(Box<dyn CloneBlocks<F, N>>),
+}
+
+impl<F, const N: usize> Gate<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    fn reset(&mut self) {
+        match self {
+            Self::Shared(gate) => gate.reset(),
+            Self::Generator(generator) => generator.reset(),
+        }
+    }
+
+    // Returns the state to save in a checkpoint, or `None` if the generator
+    // cannot save its state.
+    fn state(&self) -> Option<Vec<f64>> {
+        match self {
+            Self::Shared(gate) => {
+                let mut state = Vec::with_capacity(gate.saved_len());
+                gate.save(&mut state);
+                Some(state)
+            },
+            Self::Generator(generator) => generator.state(),
+        }
+    }
+
+    fn set_state(&mut self, state: &[f64]) -> bool {
+        match self {
+            Self::Shared(gate) => gate.load(state),
+            Self::Generator(generator) => generator.set_state(state),
+        }
+    }
+}
+
+/// The block source and loudness state for a single gating or block scheme.
+struct Meter<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    gate: Gate<F, N>,
+    blocks: BlockMeter<F, N>,
+}
+
+/// The shared sub-blocks and per-source meters for one measurement, either
+/// the whole programme or a single region.
+struct Chain<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    sub_blocks: SubBlockPowers<F, N>,
+    meters: BTreeMap<BlockSource, Meter<F, N>>,
+}
+
+impl<F, const N: usize> Chain<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    fn reset(&mut self) {
+        self.sub_blocks.reset();
+
+        for meter in self.meters.values_mut() {
+            meter.gate.reset();
+            meter.blocks.reset();
+        }
+    }
+
+    fn push(&mut self, filtered_frame: F) {
+        // Powers are only accumulated once, and then each gating assembles
+        // its blocks from the completed sub-blocks. Other generators are fed
+        // the frames themselves.
+        let sub_block_sum = self.sub_blocks.push(filtered_frame);
+
+        for meter in self.meters.values_mut() {
+            let gated_powers = match &mut meter.gate {
+                Gate::Shared(gate) => sub_block_sum.and_then(|s| gate.push(s)),
+                Gate::Generator(generator) => generator.push(filtered_frame),
+            };
+
+            if let Some(gated_powers) = gated_powers {
+                meter.blocks.push(gated_powers);
+            }
+        }
+    }
+
+    fn snapshot(&self) -> BTreeMap<BlockSource, Snapshot> {
+        self.meters.iter()
+            .filter_map(|(source, meter)| Some((source.clone(), meter.blocks.snapshot()?)))
+            .collect()
+    }
+
+    fn sliding_average(&self, source: &BlockSource, window: u64) -> Option<f64> {
+        self.meters.get(source)?.blocks.sliding.get(&window)?.integrated()
+    }
+
+    fn results(
+        &self,
+        avg_sources: &BTreeSet<BlockSource>,
+        max_sources: &BTreeSet<BlockSource>,
+        sliding: &BTreeSet<SlidingKey>,
+        roles: Option<&[Channel; N]>,
+    ) -> Results
+    {
+        let averages = avg_sources.iter()
+            .map(|source| (source.clone(), self.meters[source].blocks.integrated()))
+            .collect();
+
+        let maximums = max_sources.iter()
+            .map(|source| (source.clone(), self.meters[source].blocks.integrated()))
+            .collect();
+
+        let channel_averages = avg_sources.iter()
+            .map(|source| (source.clone(), self.meters[source].blocks.channel_loudness()))
+            .collect();
+
+        // Balance is only reported once the role of each channel is known.
+        let balance = roles.iter()
+            .flat_map(|roles| {
+                avg_sources.iter().map(move |source| (source.clone(), self.meters[source].blocks.balance(roles)))
+            })
+            .collect();
+
+        let sliding_averages = sliding.iter()
+            .map(|(source, window)| ((source.clone(), *window), self.sliding_average(source, *window)))
+            .collect();
+
+        Results { averages, maximums, channel_averages, balance, sliding_averages }
+    }
+
+    fn checkpoint(&self) -> ChainCheckpoint {
+        let mut sub_blocks = Vec::with_capacity(SubBlockPowers::<F, N>::SAVED_LEN);
+        self.sub_blocks.save(&mut sub_blocks);
+
+        let meters = self.meters.iter()
+            .map(|(source, meter)| {
+                let (blocks, sliding) = meter.blocks.save();
+
+                MeterCheckpoint {
+                    source: source.clone(),
+                    state: meter.gate.state(),
+                    blocks,
+                    sliding,
+                }
+            })
+            .collect();
+
+        ChainCheckpoint { sub_blocks, meters }
+    }
+
+    fn restore(&mut self, saved: &ChainCheckpoint) -> Result<(), CheckpointError> {
+        if !saved.meters.iter().map(|m| &m.source).eq(self.meters.keys()) {
+            return Err(CheckpointError::Mismatch);
+        }
+
+        if !self.sub_blocks.load(&saved.sub_blocks) {
+            return Err(CheckpointError::Invalid);
+        }
+
+        for (saved_meter, meter) in saved.meters.iter().zip(self.meters.values_mut()) {
+            // Generators that cannot save their state cannot be resumed.
+            let state = saved_meter.state.as_ref().ok_or(CheckpointError::Mismatch)?;
+
+            if !meter.gate.set_state(state) {
+                return Err(CheckpointError::Invalid);
+            }
+
+            meter.blocks.restore(&saved_meter.blocks, &saved_meter.sliding)?;
+        }
+
+        Ok(())
+    }
 }
 
 pub struct Pipeline<F, const N: usize>
 where
     F: Frame<N, Sample = f64>,
 {
-    k_filter: KWeightFilter<F, N>,
-    avg_gl_map: HashMap<Gating, GatedLoudness<F, N>>,
-    max_gl_map: HashMap<Gating, GatedLoudness<F, N>>,
+    sample_rate: u32,
+    frames: u64,
+    weighting: Vec<Option<[f64; 5]>>,
+    filter: WeightingFilter<F, N>,
+    guard: NonFiniteGuard,
+    non_finite: Vec<NonFiniteRun>,
+    program: Option<Chain<F, N>>,
+    exclusions: Vec<Range<u64>>,
+    regions: Vec<(Range<u64>, Chain<F, N>)>,
+    avg_sources: BTreeSet<BlockSource>,
+    max_sources: BTreeSet<BlockSource>,
+    sliding: BTreeSet<SlidingKey>,
+    roles: Option<[Channel; N]>,
 }
 
 impl<F, const N: usize> Pipeline<F, N>
@@ -25,21 +447,86 @@ where
     F: Frame<N, Sample = f64>,
 {
     pub fn reset(&mut self) {
-        self.k_filter.reset();
+        self.frames = 0;
+        self.filter.reset();
+        self.guard.reset();
+        self.non_finite.clear();
+
+        for chain in self.chains_mut() {
+            chain.reset();
+        }
+    }
+
+    pub fn is_noop(&self) -> bool {
+        self.avg_sources.is_empty() && self.max_sources.is_empty() && self.sliding.is_empty()
+    }
+
+    /// The number of frames processed so far, which is also the position of
+    /// the next frame to be processed.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Returns the position of the earliest frame that still needs to be
+    /// processed, or `None` if no more frames are needed. If this is ahead of
+    /// [`Self::frames`], the reader can seek to it and call [`Self::seek`]
+    /// instead of decoding the frames in between.
+    ///
+    /// This is only ever ahead of the current position if programme loudness
+    /// is disabled, or if the current position is within an exclusion range.
+    /// A short pre-roll is included before the start of each region and the
+    /// end of each exclusion, so that the weighting filter has settled by
+    /// the time the frames are measured.
+    pub fn next_needed(&self) -> Option<u64> {
+        let pos = self.frames;
+        let preroll = Util::ms_to_samples(SEEK_PREROLL_MS, self.sample_rate);
+
+        if self.program.is_some() && !self.is_excluded(pos) {
+            return Some(pos);
+        }
+
+        let mut next = None;
 
-        for avg_gl in self.avg_gl_map.values_mut() {
-            avg_gl.reset();
+        // Frames are needed again at the end of the current exclusion, or the
+        // start of the earliest region that has not finished yet.
+        if self.program.is_some() {
+            next = self.exclusions.iter()
+                .filter(|r| r.contains(&pos))
+                .map(|r| r.end.saturating_sub(preroll).max(pos))
+                .max();
         }
 
-        for max_gl in self.max_gl_map.values_mut() {
-            max_gl.reset();
+        for (range, _) in self.regions.iter().filter(|(r, _)| r.end > pos) {
+            let start = range.start.saturating_sub(preroll).max(pos);
+            next = Some(next.map_or(start, |n: u64| n.min(start)));
         }
+
+        next
     }
 
-    pub fn is_noop(&self) -> bool {
-        self.avg_gl_map.is_empty() && self.max_gl_map.is_empty()
+    /// Moves the position forward to `frame`, after the reader has been
+    /// seeked there. The skipped frames are treated as missing, so the filter
+    /// history is cleared. This panics if `frame` is behind the current
+    /// position.
+    pub fn seek(&mut self, frame: u64) {
+        assert!(frame >= self.frames, "cannot seek backwards");
+
+        if frame > self.frames {
+            self.frames = frame;
+            self.filter.reset();
+        }
+    }
+
+    /// Returns the number of NaN and infinite input samples seen so far.
+    pub fn non_finite_counts(&self) -> NonFiniteCounts {
+        self.guard.counts()
     }
 
+    /// Processes a sequence of frames.
+    ///
+    /// Panics if a frame is rejected for containing a non-finite sample,
+    /// which only happens under [`NonFinite::Reject`]. Use [`Self::try_feed`]
+    /// to handle rejected frames instead.
     pub fn feed<I>(&mut self, frames: I)
     where
         I: IntoIterator<Item = F>,
@@ -49,34 +536,245 @@ where
         }
     }
 
+    /// Processes a sequence of frames, stopping at the first frame that is
+    /// rejected for containing a non-finite sample.
+    pub fn try_feed<I>(&mut self, frames: I) -> Result<(), NonFiniteError>
+    where
+        I: IntoIterator<Item = F>,
+    {
+        for frame in frames.into_iter() {
+            self.try_push(frame)?;
+        }
+
+        Ok(())
+    }
+
+    /// Processes a single frame.
+    ///
+    /// Panics if the frame is rejected for containing a non-finite sample,
+    /// which only happens under [`NonFinite::Reject`]. Use [`Self::try_push`]
+    /// to handle rejected frames instead.
     pub fn push(&mut self, input: F) {
-        let filtered_frame = self.k_filter.process(input);
+        self.push_array(Util::frame_to_array(input))
+    }
+
+    /// Processes a single frame, or returns an error if it is rejected for
+    /// containing a non-finite sample.
+    pub fn try_push(&mut self, input: F) -> Result<(), NonFiniteError> {
+        self.try_push_array(Util::frame_to_array(input))
+    }
+
+    /// Processes a buffer of interleaved samples. The buffer length must be a
+    /// multiple of the number of channels.
+    ///
+    /// Panics if a frame is rejected for containing a non-finite sample,
+    /// which only happens under [`NonFinite::Reject`]. Use [`Self::try_push`]
+    /// to handle rejected frames instead.
+    pub fn process_interleaved<S>(&mut self, samples: &[S])
+    where
+        S: Copy + Into<f64>,
+    {
+        assert_eq!(samples.len() % N, 0, "incomplete frame in interleaved buffer");
+
+        for chunk in samples.chunks_exact(N) {
+            let mut x = [0.0; N];
+            for (x, s) in x.iter_mut().zip(chunk.iter()) {
+                *x = (*s).into();
+            }
+
+            self.push_array(x);
+        }
+    }
+
+    /// Processes a set of planar (one buffer per channel) samples. All channel
+    /// buffers must have the same length.
+    ///
+    /// Panics if a frame is rejected for containing a non-finite sample,
+    /// which only happens under [`NonFinite::Reject`]. Use [`Self::try_push`]
+    /// to handle rejected frames instead.
+    pub fn process_planar<S>(&mut self, channels: &[&[S]])
+    where
+        S: Copy + Into<f64>,
+    {
+        let len = Util::planar_len(channels.iter().map(|c| c.len()), N);
+
+        for i in 0..len {
+            let mut x = [0.0; N];
+            for (x, c) in x.iter_mut().zip(channels.iter()) {
+                *x = c[i].into();
+            }
+
+            self.push_array(x);
+        }
+    }
+
+    fn is_excluded(&self, pos: u64) -> bool {
+        self.exclusions.iter().any(|r| r.contains(&pos))
+    }
+
+    fn chains_mut(&mut self) -> impl Iterator<Item = &mut Chain<F, N>> {
+        self.program.iter_mut().chain(self.regions.iter_mut().map(|(_, c)| c))
+    }
+
+    fn push_array(&mut self, input: [f64; N]) {
+        if let Err(err) = self.try_push_array(input) {
+            panic!("{}", err);
+        }
+    }
+
+    // Adds the non-finite samples found in a frame to the list of runs,
+    // extending the latest run if it ends right before the frame.
+    fn record_non_finite(&mut self, pos: u64, samples: u64) {
+        match self.non_finite.last_mut() {
+            Some(run) if run.frames.end == pos => {
+                run.frames.end += 1;
+                run.samples += samples;
+            },
+            _ => self.non_finite.push(NonFiniteRun { frames: pos..(pos + 1), samples }),
+        }
+    }
+
+    fn try_push_array(&mut self, input: [f64; N]) -> Result<(), NonFiniteError> {
+        let pos = self.frames;
+
+        let mut x = input;
+        let seen = self.guard.counts().samples;
+        let keep = self.guard.check(&mut x, pos)?;
+        let found = self.guard.counts().samples - seen;
+
+        if found > 0 {
+            self.record_non_finite(pos, found);
+        }
+
+        self.frames += 1;
 
-        for gated_loudness in self.avg_gl_map.values_mut() {
-            gated_loudness.push(filtered_frame);
+        if !keep {
+            return Ok(());
         }
 
-        for gated_loudness in self.max_gl_map.values_mut() {
-            gated_loudness.push(filtered_frame);
+        self.filter.process_array(&mut x);
+        let filtered_frame: F = Util::array_to_frame(x);
+
+        // Excluded frames still go through the filter, so that its history
+        // stays continuous, but they are not counted towards the programme.
+        let is_excluded = self.is_excluded(pos);
+
+        if let Some(program) = self.program.as_mut() {
+            if !is_excluded {
+                program.push(filtered_frame);
+            }
+        }
+
+        for (range, chain) in self.regions.iter_mut() {
+            if range.contains(&pos) {
+                chain.push(filtered_frame);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes a snapshot of the current programme readings for every
+    /// configured gating and block scheme, without disturbing any state, so
+    /// that audio can continue to be fed afterwards. Unless
+    /// [`PipelineBuilder::track_snapshots`] is enabled, this takes time
+    /// proportional to the number of blocks so far. See
+    /// [`Loudness::snapshot`] for details on accuracy.
+    pub fn snapshot(&self) -> BTreeMap<BlockSource, Snapshot> {
+        self.program.as_ref().map(Chain::snapshot).unwrap_or_default()
+    }
+
+    /// Returns the current programme loudness over the last `window`, for a
+    /// sliding window measurement that was added to the builder. The window
+    /// is in milliseconds for a gating, and in blocks for a block scheme.
+    /// This is `None` if there is no such measurement, or if the window holds
+    /// no blocks above the gating thresholds.
+    pub fn sliding_average<S>(&self, source: S, window: u64) -> Option<f64>
+    where
+        S: Into<BlockSource>,
+    {
+        self.program.as_ref()?.sliding_average(&source.into(), window)
+    }
+
+    /// Takes a snapshot of the current readings for a single region, in the
+    /// order that regions were added to the builder, or returns `None` if
+    /// there is no such region.
+    pub fn region_snapshot(&self, index: usize) -> Option<BTreeMap<BlockSource, Snapshot>> {
+        self.regions.get(index).map(|(_, chain)| chain.snapshot())
+    }
+
+    /// Saves the current state, so that processing can be resumed later with
+    /// [`PipelineBuilder::resume`], starting from [`Checkpoint::frames`].
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            version: CHECKPOINT_VERSION,
+            sample_rate: self.sample_rate,
+            channels: N,
+            frames: self.frames,
+            weighting: self.weighting.clone(),
+            filter: self.filter.state().iter().flatten().flatten().copied().collect(),
+            program: self.program.as_ref().map(Chain::checkpoint),
+            regions: self.regions.iter().map(|(_, c)| c.checkpoint()).collect(),
+            non_finite: self.non_finite.clone(),
+            non_finite_counts: self.guard.counts(),
         }
     }
 
+    /// Restores state saved by [`Self::checkpoint`]. The checkpoint must have
+    /// been validated against this pipeline's configuration.
+    fn restore(&mut self, checkpoint: &Checkpoint) -> Result<(), CheckpointError> {
+        let mut filter = [[[0.0; N]; 2]; MAX_SECTIONS];
+        for (s, chunk) in filter.iter_mut().flatten().zip(checkpoint.filter.chunks_exact(N)) {
+            s.copy_from_slice(chunk);
+        }
+
+        if checkpoint.regions.len() != self.regions.len() {
+            return Err(CheckpointError::Mismatch);
+        }
+
+        match (self.program.as_mut(), checkpoint.program.as_ref()) {
+            (Some(chain), Some(saved)) => chain.restore(saved)?,
+            (None, None) => {},
+            _ => return Err(CheckpointError::Mismatch),
+        }
+
+        for ((_, chain), saved) in self.regions.iter_mut().zip(checkpoint.regions.iter()) {
+            chain.restore(saved)?;
+        }
+
+        self.frames = checkpoint.frames;
+        self.filter.set_state(filter);
+        self.non_finite = checkpoint.non_finite.clone();
+        self.guard.set_counts(checkpoint.non_finite_counts);
+
+        Ok(())
+    }
+
     pub fn calculate(self) -> Output {
-        let averages = self.avg_gl_map.into_iter()
-            .map(|(gating, gl)| {
-                (gating, gl.calculate())
-            })
-            .collect();
+        let Self { program, regions, avg_sources, max_sources, sliding, non_finite, roles, .. } = self;
+
+        let program = match program {
+            Some(chain) => chain.results(&avg_sources, &max_sources, &sliding, roles.as_ref()),
+            None => Results::default(),
+        };
 
-        let maximums = self.max_gl_map.into_iter()
-            .map(|(gating, gl)| {
-                (gating, gl.calculate())
+        let regions = regions.into_iter()
+            .map(|(range, chain)| {
+                let Results { averages, maximums, channel_averages, balance, sliding_averages } =
+                    chain.results(&avg_sources, &max_sources, &sliding, roles.as_ref());
+
+                RegionOutput { range, averages, maximums, channel_averages, balance, sliding_averages }
             })
             .collect();
 
         Output {
-            averages,
-            maximums,
+            averages: program.averages,
+            maximums: program.maximums,
+            channel_averages: program.channel_averages,
+            balance: program.balance,
+            sliding_averages: program.sliding_averages,
+            regions,
+            non_finite,
         }
     }
 }
@@ -104,73 +802,1157 @@ where
 {
     sample_rate: u32,
     g_weights: F,
-    avg_gatings: HashSet<Gating>,
-    max_gatings: HashSet<Gating>,
+    weighting: [Option<Params<f64>>; MAX_SECTIONS],
+    filter: WeightingFilter<F, N>,
+    denormals: Denormals,
+    non_finite: NonFinite,
+    generators: BTreeMap<BlockSource, Box<dyn CloneBlocks<F, N>>>,
+    avg_sources: BTreeSet<BlockSource>,
+    max_sources: BTreeSet<BlockSource>,
+    sliding: BTreeSet<SlidingKey>,
+    track_snapshots: bool,
+    program: bool,
+    exclusions: Vec<Range<u64>>,
+    regions: Vec<Range<u64>>,
+    roles: Option<[Channel; N]>,
 }
 
 impl<F, const N: usize> PipelineBuilder<F, N>
 where
     F: Frame<N, Sample = f64>,
 {
+    /// Creates a builder for a pipeline with the given sample rate and
+    /// channel weights.
+    ///
+    /// Panics if the sample rate is not supported by the weighting filters.
     pub fn new(sample_rate: u32, g_weights: F) -> Self {
-        Self {
-            sample_rate,
+        match Self::try_new(sample_rate, g_weights) {
+            Ok(builder) => builder,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates a builder for a pipeline with the given sample rate and
+    /// channel weights, or returns an error if the sample rate is not
+    /// supported by the weighting filters.
+    pub fn try_new(sample_rate: u32, g_weights: F) -> Result<Self, UnsupportedSampleRate> {
+        Ok(Self {
+            sample_rate,
             g_weights,
-            avg_gatings: HashSet::new(),
-            max_gatings: HashSet::new(),
+            weighting: StandardWeighting::K.sections(sample_rate),
+            filter: WeightingFilter::try_new(sample_rate, &StandardWeighting::K)?,
+            denormals: Denormals::default(),
+            non_finite: NonFinite::default(),
+            generators: BTreeMap::new(),
+            avg_sources: BTreeSet::new(),
+            max_sources: BTreeSet::new(),
+            sliding: BTreeSet::new(),
+            track_snapshots: false,
+            program: true,
+            exclusions: Vec::new(),
+            regions: Vec::new(),
+            roles: None,
+        })
+    }
+
+    /// Sets the frequency weighting that is applied to the input before it
+    /// is measured. This is K-weighting by default, and any other weighting
+    /// gives levels that are not loudness as defined in ITU BS.1770, such as
+    /// A-weighted levels for acoustic measurements.
+    ///
+    /// Panics if the sample rate is not supported by the weighting.
+    #[inline]
+    pub fn weighting<W>(&mut self, weighting: &W) -> &mut Self
+    where
+        W: Weighting + ?Sized,
+    {
+        match self.try_weighting(weighting) {
+            Ok(builder) => builder,
+            Err(err) => panic!("{}", err),
         }
     }
 
+    /// Sets the frequency weighting in the same way as [`Self::weighting`],
+    /// or returns an error if the sample rate is not supported by the
+    /// weighting.
     #[inline]
-    pub fn average(&mut self, gating: Gating) -> &mut Self {
-        self.avg_gatings.insert(gating);
+    pub fn try_weighting<W>(&mut self, weighting: &W) -> Result<&mut Self, UnsupportedSampleRate>
+    where
+        W: Weighting + ?Sized,
+    {
+        self.filter = WeightingFilter::try_new(self.sample_rate, weighting)?;
+        self.weighting = weighting.sections(self.sample_rate);
+        Ok(self)
+    }
+
+    /// Sets how the weighting filter state is kept from becoming subnormal
+    /// during long silences. By default, tiny state values are flushed to
+    /// zero.
+    #[inline]
+    pub fn denormals(&mut self, denormals: Denormals) -> &mut Self {
+        self.denormals = denormals;
+        self
+    }
+
+    /// Sets what to do with input frames that contain NaN or infinite
+    /// samples. By default, those samples are replaced with zero, and any
+    /// frames that are zeroed or skipped are listed in [`Output::non_finite`].
+    ///
+    /// With [`NonFinite::Reject`], input should be given to
+    /// [`Pipeline::try_push`] or [`Pipeline::try_feed`], since every other
+    /// processing method panics on a rejected frame.
+    #[inline]
+    pub fn non_finite(&mut self, policy: NonFinite) -> &mut Self {
+        self.non_finite = policy;
+        self
+    }
+
+    /// Sets the role of each channel, such as left or right surround, so that
+    /// the balance between channels is reported in [`Output::balance`].
+    #[inline]
+    pub fn roles(&mut self, roles: [Channel; N]) -> &mut Self {
+        self.roles = Some(roles);
+        self
+    }
+
+    #[inline]
+    pub fn average(&mut self, gating: Gating) -> &mut Self
+    where
+        F: 'static,
+    {
+        let source = self.add_gating(gating);
+        self.avg_sources.insert(source);
         self
     }
 
     #[inline]
     pub fn averages<I>(&mut self, gatings: I) -> &mut Self
     where
+        F: 'static,
         I: IntoIterator<Item = Gating>,
     {
         for gating in gatings {
-            self.avg_gatings.insert(gating);
+            self.average(gating);
         }
         self
     }
 
     #[inline]
-    pub fn maximum(&mut self, gating: Gating) -> &mut Self {
-        self.max_gatings.insert(gating);
+    pub fn maximum(&mut self, gating: Gating) -> &mut Self
+    where
+        F: 'static,
+    {
+        let source = self.add_gating(gating);
+        self.max_sources.insert(source);
         self
     }
 
     #[inline]
     pub fn maximums<I>(&mut self, gatings: I) -> &mut Self
     where
+        F: 'static,
         I: IntoIterator<Item = Gating>,
     {
         for gating in gatings {
-            self.max_gatings.insert(gating);
+            self.maximum(gating);
         }
         self
     }
 
+    /// Adds an integrated loudness measurement that only covers the blocks
+    /// within the last `window_ms`, such as the last 10 minutes of a 24/7
+    /// stream. Blocks that fall out of the window are expired as new ones
+    /// arrive, so time per block does not grow with the window. Unless the
+    /// same gating is also used for an average or a maximum, only the blocks
+    /// within the window are kept, so memory does not grow with the stream
+    /// either.
+    #[inline]
+    pub fn sliding_average(&mut self, gating: Gating, window_ms: u64) -> &mut Self
+    where
+        F: 'static,
+    {
+        let source = self.add_gating(gating);
+        self.sliding.insert((source, window_ms));
+        self
+    }
+
+    /// Adds a user-defined block scheme under the given name, replacing any
+    /// scheme with the same name. Its blocks are gated and averaged in the
+    /// same way as those of the standard gatings, and the results are listed
+    /// under [`BlockSource::Scheme`] in [`Output`].
+    ///
+    /// A generator that reports its [`BlockGenerator::gating`] at the
+    /// pipeline's sample rate has its blocks assembled from shared sub-blocks
+    /// instead. A pipeline with schemes can only be resumed from a checkpoint
+    /// if every other block generator saves its state, as described in
+    /// [`BlockGenerator::state`].
+    #[inline]
+    pub fn scheme<B>(&mut self, name: &str, blocks: B) -> &mut Self
+    where
+        B: BlockGenerator<F, N> + Clone + 'static,
+    {
+        let source = BlockSource::from(name);
+        self.generators.insert(source.clone(), Box::new(blocks));
+        self.avg_sources.insert(source);
+        self
+    }
+
+    /// Adds an integrated loudness measurement that only covers the last
+    /// `window_blocks` blocks of a scheme added with [`Self::scheme`], in the
+    /// same way as [`Self::sliding_average`]. The window is given in blocks,
+    /// since a scheme's blocks need not be evenly spaced in time.
+    #[inline]
+    pub fn scheme_sliding_average(&mut self, name: &str, window_blocks: usize) -> &mut Self {
+        self.sliding.insert((BlockSource::from(name), window_blocks as u64));
+        self
+    }
+
+    // Adds the block generator for a gating, unless it has already been
+    // added, and returns its source. These generators always report their
+    // gating, so the pipeline assembles their blocks from shared sub-blocks,
+    // which gives the same blocks as exact accumulation.
+    fn add_gating(&mut self, gating: Gating) -> BlockSource
+    where
+        F: 'static,
+    {
+        let sample_rate = self.sample_rate;
+        let source = BlockSource::Gating(gating);
+
+        self.generators.entry(source.clone()).or_insert_with(|| {
+            Box::new(GatedPowers::<F, N>::with_accumulation(sample_rate, gating, Accumulation::Exact))
+        });
+
+        source
+    }
+
+    /// Sets whether every gating and scheme keeps a histogram of its blocks,
+    /// so that [`Pipeline::snapshot`] stays fast however long the input is.
+    /// This is disabled by default, since each histogram takes up 128 KB. See
+    /// [`Loudness::track_snapshots`] for details.
+    #[inline]
+    pub fn track_snapshots(&mut self, enabled: bool) -> &mut Self {
+        self.track_snapshots = enabled;
+        self
+    }
+
+    /// Sets whether the loudness of the whole programme is measured. This is
+    /// enabled by default. Disabling it when only regions are needed allows
+    /// the frames outside of all regions to be skipped.
+    #[inline]
+    pub fn program(&mut self, enabled: bool) -> &mut Self {
+        self.program = enabled;
+        self
+    }
+
+    /// Adds a range of frames to leave out of the programme loudness, such as
+    /// a slate or bars-and-tone. Regions are not affected by exclusions.
+    #[inline]
+    pub fn exclude(&mut self, range: Range<u64>) -> &mut Self {
+        self.exclusions.push(range);
+        self
+    }
+
+    /// Adds an exclusion range given in seconds.
+    #[inline]
+    pub fn exclude_secs(&mut self, range: Range<f64>) -> &mut Self {
+        let range = self.secs_to_frames(range);
+        self.exclude(range)
+    }
+
+    /// Adds a range of frames to be measured on its own, with the same
+    /// gatings as the programme. Regions may overlap each other and any
+    /// exclusion ranges.
+    #[inline]
+    pub fn region(&mut self, range: Range<u64>) -> &mut Self {
+        self.regions.push(range);
+        self
+    }
+
+    /// Adds a region given in seconds.
+    #[inline]
+    pub fn region_secs(&mut self, range: Range<f64>) -> &mut Self {
+        let range = self.secs_to_frames(range);
+        self.region(range)
+    }
+
+    fn secs_to_frames(&self, range: Range<f64>) -> Range<u64> {
+        let to_frames = |secs: f64| math::round(secs * self.sample_rate as f64) as u64;
+        to_frames(range.start)..to_frames(range.end)
+    }
+
     pub fn build(&self) -> Pipeline<F, N> {
-        let Self { sample_rate, g_weights, avg_gatings, max_gatings } = self;
+        let Self {
+            sample_rate, g_weights, weighting, filter, denormals, non_finite, generators, avg_sources, max_sources,
+            sliding, track_snapshots, program, exclusions, regions, roles,
+        } = self;
 
-        let k_filter = KWeightFilter::new(*sample_rate);
+        let mut filter = filter.clone();
+        filter.set_denormals(*denormals);
 
-        let avg_gl_map = avg_gatings.iter()
-            .map(|&g| (g, GatedLoudness::new(*sample_rate, *g_weights, g)))
-            .collect();
-        let max_gl_map = max_gatings.iter()
-            .map(|&g| (g, GatedLoudness::new(*sample_rate, *g_weights, g)))
-            .collect();
+        // Generators that lay out their blocks by a gating at this sample rate
+        // share their sub-blocks, so convert the gate and step lengths to
+        // frames. The sub-block length is the largest length that evenly
+        // divides all of them, so that every gated block is made up of whole
+        // sub-blocks. It may be a fractional number of frames, such as for
+        // momentary gating at 11025 Hz, and falls back to a single frame if
+        // the gatings have no common sub-block at least that long.
+        let lengths = generators.iter()
+            .filter_map(|(source, generator)| match generator.gating() {
+                Some((gating, rate)) if rate == *sample_rate => Some((source, gating.frames(rate))),
+                _ => None,
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let sub_block = shared_sub_block(lengths.values().copied());
+
+        let new_loudness = || {
+            let mut loudness = Loudness::new(*g_weights);
+            if *track_snapshots {
+                loudness.track_snapshots();
+            }
+            loudness
+        };
+
+        let new_chain = || {
+            let meters = generators.iter()
+                .map(|(source, generator)| {
+                    // Windows over gatings are given in milliseconds, and
+                    // windows over schemes in blocks.
+                    let sliding = sliding.iter()
+                        .filter(|(s, _)| s == source)
+                        .map(|&(_, window)| {
+                            let window_loudness = match source {
+                                BlockSource::Gating(gating) => {
+                                    SlidingLoudness::new(*g_weights, *gating, *sample_rate, window)
+                                },
+                                BlockSource::Scheme(_) => SlidingLoudness::with_blocks(*g_weights, window as usize),
+                            };
+
+                            (window, window_loudness)
+                        })
+                        .collect();
+
+                    let gate = match lengths.get(source) {
+                        Some(&(gate_len, step)) => Gate::Shared(SubBlockGate::new(sub_block, gate_len, step)),
+                        None => Gate::Generator(generator.clone()),
+                    };
+
+                    // Sources that are only used for sliding windows do not
+                    // keep every block.
+                    let is_kept = avg_sources.contains(source) || max_sources.contains(source);
+
+                    let meter = Meter {
+                        gate,
+                        blocks: BlockMeter {
+                            loudness: is_kept.then(new_loudness),
+                            sliding,
+                        },
+                    };
+
+                    (source.clone(), meter)
+                })
+                .collect();
+
+            Chain {
+                sub_blocks: SubBlockPowers::new(sub_block),
+                meters,
+            }
+        };
 
         Pipeline {
-            k_filter,
-            avg_gl_map,
-            max_gl_map,
+            sample_rate: *sample_rate,
+            frames: 0,
+            weighting: checkpoint::weighting_coefficients(weighting),
+            filter,
+            guard: NonFiniteGuard::new(*non_finite),
+            non_finite: Vec::new(),
+            exclusions: exclusions.clone(),
+            regions: regions.iter().map(|r| (r.clone(), new_chain())).collect(),
+            program: program.then(new_chain),
+            avg_sources: avg_sources.clone(),
+            max_sources: max_sources.clone(),
+            sliding: sliding.clone(),
+            roles: *roles,
+        }
+    }
+
+    /// Builds a pipeline and restores the state saved in a checkpoint. The
+    /// builder must be configured the same way as the one that built the
+    /// checkpointed pipeline. Input should then be fed starting from frame
+    /// [`Checkpoint::frames`].
+    pub fn resume(&self, checkpoint: &Checkpoint) -> Result<Pipeline<F, N>, CheckpointError> {
+        checkpoint.validate(self.sample_rate, N, &checkpoint::weighting_coefficients(&self.weighting))?;
+
+        let mut pipeline = self.build();
+        pipeline.restore(checkpoint)?;
+
+        Ok(pipeline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f64::consts::PI;
+
+    use crate::filter::KWeightFilter;
+
+    use approx::assert_abs_diff_eq;
+
+    const G_WEIGHTS: [f64; 2] = [1.0, 1.0];
+
+    // Generates a stereo signal with a slowly changing amplitude, so that
+    // gating has an effect on the results.
+    fn test_frames(sample_rate: u32, secs: usize) -> impl Iterator<Item = [f64; 2]> {
+        let sr = sample_rate as f64;
+
+        (0..(sample_rate as usize * secs)).map(move |i| {
+            let t = i as f64 / sr;
+            let amp = 0.5 + 0.49 * (2.0 * PI * 0.1 * t).sin();
+
+            [
+                amp * (2.0 * PI * 997.0 * t).sin(),
+                amp * amp * (2.0 * PI * 220.0 * t).sin(),
+            ]
+        })
+    }
+
+    #[test]
+    fn shared_sub_blocks() {
+        // Momentary sub-blocks are 1102.5 frames long at 11025 Hz.
+        for &sample_rate in &[11025, 22050, 44100, 48000] {
+            let gatings = [Gating::Momentary, Gating::Shortterm];
+            let roles = [Channel::Left, Channel::Right];
+
+            let mut pipeline = PipelineBuilder::new(sample_rate, G_WEIGHTS)
+                .averages(gatings.iter().copied())
+                .maximums(gatings.iter().copied())
+                .roles(roles)
+                .build();
+
+            pipeline.feed(test_frames(sample_rate, 20));
+
+            let output = pipeline.calculate();
+
+            for gating in gatings.iter() {
+                let source = BlockSource::from(*gating);
+
+                let mut k_filter = KWeightFilter::new(sample_rate);
+                let mut gated_powers = GatedPowers::new(sample_rate, *gating);
+                let mut loudness = Loudness::new(G_WEIGHTS);
+
+                for frame in test_frames(sample_rate, 20) {
+                    if let Some(gp) = gated_powers.process(k_filter.process(frame)) {
+                        loudness.push(gp);
+                    }
+                }
+
+                let expected_channels = loudness.channel_loudness().unwrap();
+                let expected_avg = loudness.calculate().unwrap();
+
+                let produced_channels = output.channel_averages[&source].as_ref().unwrap();
+                assert_eq!(produced_channels.len(), 2);
+                for (e, p) in expected_channels.iter().zip(produced_channels.iter()) {
+                    assert_abs_diff_eq!(*e, *p, epsilon = 1e-9);
+                }
+
+                assert_abs_diff_eq!(expected_avg, output.averages[&source].unwrap(), epsilon = 1e-9);
+                assert_abs_diff_eq!(expected_avg, output.maximums[&source].unwrap(), epsilon = 1e-9);
+
+                // The first channel is louder, and there are no surrounds.
+                let expected_balance = loudness.balance(&roles).unwrap();
+                let produced_balance = output.balance[&source].unwrap();
+                assert!(produced_balance.left_right.unwrap() > 0.0);
+                assert_abs_diff_eq!(
+                    expected_balance.left_right.unwrap(),
+                    produced_balance.left_right.unwrap(),
+                    epsilon = 1e-9,
+                );
+                assert_eq!(produced_balance.surround_to_front, None);
+            }
         }
     }
+
+    #[test]
+    fn incommensurate_gatings() {
+        // At 11025 Hz, a 401 ms gate is 4421 frames and a 100 ms step is
+        // 1102.5 frames, which only share a half-frame sub-block. Both
+        // gatings fall back to single-frame sub-blocks with fractional steps.
+        const SAMPLE_RATE: u32 = 11025;
+
+        let gatings = [Gating::Momentary, Gating::Custom { gate_len_ms: 401, delta_len_ms: 100 }];
+
+        let builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .averages(gatings.iter().copied())
+            .clone();
+
+        let frames = test_frames(SAMPLE_RATE, 20).collect::<Vec<_>>();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(frames.iter().copied());
+        let output = pipeline.calculate();
+
+        // No balance is reported without channel roles.
+        assert!(output.balance.is_empty());
+
+        for gating in gatings.iter() {
+            let mut k_filter = KWeightFilter::new(SAMPLE_RATE);
+            let mut gated_powers = GatedPowers::new(SAMPLE_RATE, *gating);
+            let mut loudness = Loudness::new(G_WEIGHTS);
+
+            for frame in frames.iter() {
+                if let Some(gp) = gated_powers.process(k_filter.process(*frame)) {
+                    loudness.push(gp);
+                }
+            }
+
+            let source = BlockSource::from(*gating);
+            assert_abs_diff_eq!(loudness.calculate().unwrap(), output.averages[&source].unwrap(), epsilon = 1e-9);
+        }
+
+        // The fractional phase of each step is carried over in checkpoints.
+        let mut pipeline = builder.build();
+        pipeline.feed(frames[..12_345].iter().copied());
+        let checkpoint = pipeline.checkpoint();
+
+        let mut pipeline = builder.resume(&checkpoint).unwrap();
+        pipeline.feed(frames[12_345..].iter().copied());
+
+        assert_eq!(output.averages, pipeline.calculate().averages);
+    }
+
+    #[test]
+    fn snapshot() {
+        const SAMPLE_RATE: u32 = 48000;
+
+        let gatings = [Gating::Momentary, Gating::Shortterm];
+
+        let builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .averages(gatings.iter().copied())
+            .track_snapshots(true)
+            .clone();
+
+        let mut pipeline = builder.build();
+        let mut uninterrupted = builder.build();
+
+        let frames = test_frames(SAMPLE_RATE, 20).collect::<Vec<_>>();
+
+        for chunk in frames.chunks(SAMPLE_RATE as usize) {
+            pipeline.feed(chunk.iter().copied());
+            let snapshot = pipeline.snapshot();
+
+            for gating in gatings.iter() {
+                let source = BlockSource::from(*gating);
+                let loudness = pipeline.program.as_ref().unwrap().meters[&source].blocks.loudness.as_ref().unwrap();
+
+                assert_eq!(snapshot[&source].maximum, loudness.maximum());
+
+                if let Some(eval) = loudness.evaluate(0.0) {
+                    assert_abs_diff_eq!(eval.integrated, snapshot[&source].integrated.unwrap(), epsilon = 0.01);
+                }
+            }
+        }
+
+        // Taking snapshots along the way has no effect on the final results.
+        uninterrupted.feed(frames.iter().copied());
+
+        let expected = uninterrupted.calculate();
+        let produced = pipeline.calculate();
+
+        assert_eq!(expected.averages, produced.averages);
+    }
+
+    #[test]
+    fn checkpoint_and_resume() {
+        const SAMPLE_RATE: u32 = 44100;
+
+        let builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .averages(vec![Gating::Momentary, Gating::Shortterm])
+            .maximum(Gating::Momentary)
+            .clone();
+
+        let mut frames = test_frames(SAMPLE_RATE, 20).collect::<Vec<_>>();
+
+        // Non-finite samples before the checkpoint, and in a run that
+        // straddles it.
+        frames[1000][0] = f64::NAN;
+        for frame in frames[123_455..123_460].iter_mut() {
+            frame[1] = f64::INFINITY;
+        }
+
+        let mut pipeline = builder.build();
+        pipeline.feed(frames.iter().copied());
+        let expected_counts = pipeline.non_finite_counts();
+        let expected = pipeline.calculate();
+
+        // Stop partway through a sub-block, to make sure that partial state is
+        // carried over as well.
+        let mut pipeline = builder.build();
+        pipeline.feed(frames[..123_457].iter().copied());
+        let checkpoint = pipeline.checkpoint();
+
+        assert_eq!(checkpoint.frames(), 123_457);
+
+        let mut pipeline = builder.resume(&checkpoint).unwrap();
+        pipeline.feed(frames[checkpoint.frames() as usize..].iter().copied());
+        let produced_counts = pipeline.non_finite_counts();
+        let produced = pipeline.calculate();
+
+        assert_eq!(expected.averages, produced.averages);
+        assert_eq!(expected.maximums, produced.maximums);
+
+        // Non-finite samples from before the checkpoint are still reported,
+        // and the run that straddles it is not split in two.
+        assert_eq!(expected_counts, produced_counts);
+        assert_eq!(expected.non_finite, produced.non_finite);
+        assert_eq!(produced.non_finite, vec![
+            NonFiniteRun { frames: 1000..1001, samples: 1 },
+            NonFiniteRun { frames: 123_455..123_460, samples: 5 },
+        ]);
+
+        // Checkpoints only apply to pipelines with the same configuration.
+        let other = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .average(Gating::Momentary)
+            .clone();
+        assert_eq!(other.resume(&checkpoint).err(), Some(CheckpointError::Mismatch));
+
+        let other = PipelineBuilder::new(48000, G_WEIGHTS);
+        assert_eq!(other.resume(&checkpoint).err(), Some(CheckpointError::Mismatch));
+
+        let mut old = checkpoint.clone();
+        old.version = 0;
+        assert_eq!(builder.resume(&old).err(), Some(CheckpointError::Version(0)));
+
+        // At 11025 Hz, sub-blocks are a fractional number of frames long, so
+        // the phase of the current one is carried over as well.
+        let builder = PipelineBuilder::new(11025, G_WEIGHTS)
+            .average(Gating::Momentary)
+            .clone();
+
+        let frames = test_frames(11025, 20).collect::<Vec<_>>();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(frames.iter().copied());
+        let expected = pipeline.calculate();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(frames[..2000].iter().copied());
+        let checkpoint = pipeline.checkpoint();
+
+        // The saved sub-block state starts with the frame count and phase.
+        assert_eq!(checkpoint.program.as_ref().unwrap().sub_blocks[1], 1.0);
+
+        let mut pipeline = builder.resume(&checkpoint).unwrap();
+        pipeline.feed(frames[2000..].iter().copied());
+
+        assert_eq!(expected.averages, pipeline.calculate().averages);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn checkpoint_serde() {
+        const SAMPLE_RATE: u32 = 48000;
+
+        let builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .average(Gating::Momentary)
+            .clone();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(test_frames(SAMPLE_RATE, 5));
+        let checkpoint = pipeline.checkpoint();
+
+        let json = serde_json::to_string(&checkpoint).unwrap();
+        let restored: Checkpoint = serde_json::from_str(&json).unwrap();
+
+        let mut pipeline = builder.resume(&restored).unwrap();
+        pipeline.feed(test_frames(SAMPLE_RATE, 10).skip(restored.frames() as usize));
+        let produced = pipeline.calculate();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(test_frames(SAMPLE_RATE, 10));
+        let expected = pipeline.calculate();
+
+        assert_abs_diff_eq!(
+            expected.averages[&BlockSource::from(Gating::Momentary)].unwrap(),
+            produced.averages[&BlockSource::from(Gating::Momentary)].unwrap(),
+            epsilon = 1e-9,
+        );
+    }
+
+    #[test]
+    fn regions_and_exclusions() {
+        const SAMPLE_RATE: u32 = 48000;
+        const SECS: usize = 30;
+
+        let momentary = BlockSource::from(Gating::Momentary);
+
+        let frames = test_frames(SAMPLE_RATE, SECS).collect::<Vec<_>>();
+
+        // Measures the frames that pass `keep`, filtering the whole signal so
+        // that the filter history is the same as in the pipeline.
+        let reference = |keep: &dyn Fn(u64) -> bool| {
+            let mut k_filter = KWeightFilter::new(SAMPLE_RATE);
+            let mut gated_powers = GatedPowers::with_accumulation(
+                SAMPLE_RATE, Gating::Momentary, Accumulation::Exact,
+            );
+            let mut loudness = Loudness::new(G_WEIGHTS);
+
+            for (i, frame) in frames.iter().enumerate() {
+                let filtered = k_filter.process(*frame);
+
+                if keep(i as u64) {
+                    if let Some(gp) = gated_powers.process(filtered) {
+                        loudness.push(gp);
+                    }
+                }
+            }
+
+            loudness.calculate().unwrap()
+        };
+
+        let builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .average(Gating::Momentary)
+            .exclude_secs(0.0..5.0)
+            .exclude(480_000..720_000)
+            .region_secs(2.0..12.0)
+            .region(600_000..1_200_000)
+            .clone();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(frames.iter().copied());
+
+        assert!(pipeline.region_snapshot(1).unwrap().contains_key(&momentary));
+        assert!(pipeline.region_snapshot(2).is_none());
+
+        let output = pipeline.calculate();
+
+        let program = output.averages[&momentary].unwrap();
+        let expected = reference(&|i| i >= 240_000 && !(480_000..720_000).contains(&i));
+        assert_abs_diff_eq!(program, expected, epsilon = 1e-9);
+
+        assert_eq!(output.regions.len(), 2);
+        for region in output.regions.iter() {
+            let range = region.range.clone();
+            let expected = reference(&|i| range.contains(&i));
+            assert_abs_diff_eq!(region.averages[&momentary].unwrap(), expected, epsilon = 1e-9);
+        }
+
+        // Without the programme, the frames outside of all regions can be
+        // skipped by seeking.
+        let mut pipeline = builder.clone().program(false).build();
+        let mut num_decoded = 0;
+
+        while let Some(next) = pipeline.next_needed() {
+            if next as usize >= frames.len() {
+                break;
+            }
+
+            pipeline.seek(next);
+            pipeline.push(frames[next as usize]);
+            num_decoded += 1;
+        }
+
+        assert!(num_decoded < frames.len());
+
+        let seeked = pipeline.calculate();
+        assert!(seeked.averages.is_empty());
+
+        for (e, p) in output.regions.iter().zip(seeked.regions.iter()) {
+            assert_eq!(e.range, p.range);
+            assert_abs_diff_eq!(
+                e.averages[&momentary].unwrap(),
+                p.averages[&momentary].unwrap(),
+                epsilon = 1e-6,
+            );
+        }
+    }
+
+    #[test]
+    fn sliding_average() {
+        const SAMPLE_RATE: u32 = 48000;
+        const WINDOW_MS: u64 = 5000;
+
+        let momentary = BlockSource::from(Gating::Momentary);
+
+        let builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .sliding_average(Gating::Momentary, WINDOW_MS)
+            .clone();
+
+        let frames = test_frames(SAMPLE_RATE, 30).collect::<Vec<_>>();
+
+        let mut k_filter = KWeightFilter::new(SAMPLE_RATE);
+        let mut gated_powers = GatedPowers::new(SAMPLE_RATE, Gating::Momentary);
+        let mut blocks = Vec::new();
+
+        for frame in frames.iter() {
+            if let Some(gp) = gated_powers.process(k_filter.process(*frame)) {
+                blocks.push(gp);
+            }
+        }
+
+        // Only the blocks that lie entirely within the last 5 seconds count.
+        let expected = |blocks: &[[f64; 2]]| {
+            let mut loudness = Loudness::new(G_WEIGHTS);
+            for gp in blocks[blocks.len().saturating_sub(47)..].iter() {
+                loudness.push(*gp);
+            }
+
+            loudness.calculate().unwrap()
+        };
+
+        let mut pipeline = builder.build();
+        let mut num_blocks = 0;
+
+        for chunk in frames.chunks(SAMPLE_RATE as usize * 3) {
+            pipeline.feed(chunk.iter().copied());
+            num_blocks = (pipeline.frames() as usize - 19200) / 4800 + 1;
+
+            let produced = pipeline.sliding_average(Gating::Momentary, WINDOW_MS).unwrap();
+            assert_abs_diff_eq!(produced, expected(&blocks[..num_blocks]), epsilon = 0.01);
+        }
+
+        assert_eq!(num_blocks, blocks.len());
+
+        // A gating that is only used for a sliding window keeps only the
+        // blocks within the window, in memory and in checkpoints.
+        assert!(pipeline.program.as_ref().unwrap().meters[&momentary].blocks.loudness.is_none());
+
+        let checkpoint = pipeline.checkpoint();
+        let saved = &checkpoint.program.as_ref().unwrap().meters[0];
+        assert!(saved.blocks.is_empty());
+        assert_eq!(saved.sliding.len(), 1);
+        assert_eq!(saved.sliding[0].len(), 47);
+
+        // The window is rebuilt when resuming from a checkpoint.
+        let resumed = builder.resume(&checkpoint).unwrap();
+        assert_eq!(
+            pipeline.sliding_average(Gating::Momentary, WINDOW_MS),
+            resumed.sliding_average(Gating::Momentary, WINDOW_MS),
+        );
+
+        let output = pipeline.calculate();
+        assert!(output.averages.is_empty());
+        assert_abs_diff_eq!(
+            output.sliding_averages[&(momentary, WINDOW_MS)].unwrap(),
+            expected(&blocks),
+            epsilon = 0.01,
+        );
+    }
+
+    #[test]
+    fn weighting() {
+        const SAMPLE_RATE: u32 = 48000;
+
+        let momentary = BlockSource::from(Gating::Momentary);
+
+        let frames = test_frames(SAMPLE_RATE, 10).collect::<Vec<_>>();
+
+        let measure = |weighting: StandardWeighting| {
+            let mut pipeline = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+                .average(Gating::Momentary)
+                .weighting(&weighting)
+                .build();
+
+            pipeline.feed(frames.iter().copied());
+            pipeline.calculate().averages[&momentary].unwrap()
+        };
+
+        let mut default = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .average(Gating::Momentary)
+            .build();
+
+        default.feed(frames.iter().copied());
+
+        assert_eq!(default.calculate().averages[&momentary].unwrap(), measure(StandardWeighting::K));
+
+        // The test signal is mostly made up of a 997 Hz tone, where A- and
+        // C-weighting have almost no effect, and a quieter 220 Hz tone that
+        // A-weighting cuts by about 10 dB.
+        let flat = measure(StandardWeighting::Z);
+        assert!(measure(StandardWeighting::A) < flat);
+        assert_abs_diff_eq!(measure(StandardWeighting::C), flat, epsilon = 0.1);
+
+        // Filter state only resumes into a filter with the same weighting.
+        let builder = |weighting: StandardWeighting| {
+            PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+                .average(Gating::Momentary)
+                .weighting(&weighting)
+                .clone()
+        };
+
+        let mut pipeline = builder(StandardWeighting::A).build();
+        pipeline.feed(frames.iter().copied());
+        let checkpoint = pipeline.checkpoint();
+
+        assert!(builder(StandardWeighting::A).resume(&checkpoint).is_ok());
+        assert_eq!(builder(StandardWeighting::K).resume(&checkpoint).err(), Some(CheckpointError::Mismatch));
+        assert_eq!(builder(StandardWeighting::C).resume(&checkpoint).err(), Some(CheckpointError::Mismatch));
+    }
+
+    #[test]
+    fn interleaved_and_planar() {
+        const SAMPLE_RATE: u32 = 48000;
+
+        let builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .averages(vec![Gating::Momentary, Gating::Shortterm])
+            .maximum(Gating::Momentary)
+            .clone();
+
+        // Round the test signal to single precision, so that the same input
+        // can be given as both `f32` and `f64` samples.
+        let frames = test_frames(SAMPLE_RATE, 10)
+            .map(|[l, r]| [l as f32, r as f32])
+            .collect::<Vec<_>>();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(frames.iter().map(|&[l, r]| [l as f64, r as f64]));
+        let expected = pipeline.calculate();
+
+        let interleaved = frames.iter().flat_map(|f| f.iter().copied()).collect::<Vec<f32>>();
+        let mut pipeline = builder.build();
+        pipeline.process_interleaved(&interleaved);
+        let produced_interleaved = pipeline.calculate();
+
+        let left = frames.iter().map(|f| f[0] as f64).collect::<Vec<f64>>();
+        let right = frames.iter().map(|f| f[1] as f64).collect::<Vec<f64>>();
+        let mut pipeline = builder.build();
+        pipeline.process_planar(&[&left[..], &right[..]]);
+        let produced_planar = pipeline.calculate();
+
+        for produced in [produced_interleaved, produced_planar].iter() {
+            assert_eq!(expected.averages, produced.averages);
+            assert_eq!(expected.maximums, produced.maximums);
+        }
+    }
+
+    #[test]
+    fn non_finite_policies() {
+        const SAMPLE_RATE: u32 = 48000;
+
+        let builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .averages(vec![Gating::Momentary, Gating::Shortterm])
+            .clone();
+
+        let clean = test_frames(SAMPLE_RATE, 10).collect::<Vec<_>>();
+        let bad = [(1000, [f64::NAN, 0.5]), (1001, [0.5, f64::INFINITY]), (30000, [f64::NAN, f64::NAN])];
+
+        let mut frames = clean.clone();
+        for &(i, frame) in bad.iter() {
+            frames[i] = frame;
+        }
+
+        let expected_runs = vec![
+            NonFiniteRun { frames: 1000..1002, samples: 2 },
+            NonFiniteRun { frames: 30000..30001, samples: 2 },
+        ];
+
+        // Zeroed samples measure the same as a clean signal with those
+        // samples set to zero.
+        let mut zeroed = frames.clone();
+        for frame in zeroed.iter_mut() {
+            for x in frame.iter_mut().filter(|x| !x.is_finite()) {
+                *x = 0.0;
+            }
+        }
+
+        let mut pipeline = builder.clone().non_finite(NonFinite::Zero).build();
+        pipeline.feed(frames.iter().copied());
+        assert_eq!(pipeline.non_finite_counts().samples, 4);
+        let produced = pipeline.calculate();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(zeroed.iter().copied());
+        let expected = pipeline.calculate();
+
+        assert_eq!(expected.averages, produced.averages);
+        assert_eq!(expected_runs, produced.non_finite);
+        assert!(expected.non_finite.is_empty());
+
+        // Skipped frames measure the same as if they were never there.
+        let mut pipeline = builder.clone().non_finite(NonFinite::Skip).build();
+        pipeline.feed(frames.iter().copied());
+        assert_eq!(pipeline.frames(), frames.len() as u64);
+        let produced = pipeline.calculate();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(frames.iter().copied().filter(|f| f.iter().all(|x| x.is_finite())));
+        let expected = pipeline.calculate();
+
+        assert_eq!(expected.averages, produced.averages);
+        assert_eq!(expected_runs, produced.non_finite);
+
+        // Rejected frames stop processing, and can be fixed and pushed again.
+        let mut pipeline = builder.clone().non_finite(NonFinite::Reject).build();
+        assert_eq!(
+            pipeline.try_feed(frames.iter().copied()),
+            Err(NonFiniteError { frame: 1000, channel: 0 }),
+        );
+        assert_eq!(pipeline.frames(), 1000);
+
+        pipeline.try_feed(clean[1000..].iter().copied()).unwrap();
+        let produced = pipeline.calculate();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(clean.iter().copied());
+
+        assert_eq!(pipeline.calculate().averages, produced.averages);
+        assert!(produced.non_finite.is_empty());
+    }
+
+    #[test]
+    fn block_schemes() {
+        const SAMPLE_RATE: u32 = 48000;
+
+        // Back-to-back blocks, summed from scratch.
+        #[derive(Clone)]
+        struct Consecutive {
+            len: usize,
+            count: usize,
+            sum: [f64; 2],
+        }
+
+        impl BlockGenerator<[f64; 2], 2> for Consecutive {
+            fn push(&mut self, [l, r]: [f64; 2]) -> Option<[f64; 2]> {
+                self.sum[0] += l * l;
+                self.sum[1] += r * r;
+                self.count += 1;
+
+                if self.count < self.len {
+                    return None;
+                }
+
+                let len = self.len as f64;
+                let mean_sq = [self.sum[0] / len, self.sum[1] / len];
+                self.reset();
+
+                Some(mean_sq)
+            }
+
+            fn reset(&mut self) {
+                self.count = 0;
+                self.sum = [0.0; 2];
+            }
+
+            fn state(&self) -> Option<Vec<f64>> {
+                Some(vec![self.count as f64, self.sum[0], self.sum[1]])
+            }
+
+            fn set_state(&mut self, state: &[f64]) -> bool {
+                match *state {
+                    [count, l, r] if count >= 0.0 && (count as usize) < self.len => {
+                        self.count = count as usize;
+                        self.sum = [l, r];
+                        true
+                    },
+                    _ => false,
+                }
+            }
+        }
+
+        // The same blocks, but without any saved state.
+        #[derive(Clone)]
+        struct Stateless(Consecutive);
+
+        impl BlockGenerator<[f64; 2], 2> for Stateless {
+            fn push(&mut self, frame: [f64; 2]) -> Option<[f64; 2]> {
+                self.0.push(frame)
+            }
+
+            fn reset(&mut self) {
+                self.0.reset()
+            }
+        }
+
+        let gating = Gating::Custom { gate_len_ms: 400, delta_len_ms: 400 };
+        let consecutive = Consecutive { len: 19200, count: 0, sum: [0.0; 2] };
+
+        let builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .average(gating)
+            .maximum(gating)
+            .sliding_average(gating, 4000)
+            .scheme("consecutive", consecutive.clone())
+            .scheme_sliding_average("consecutive", 10)
+            .scheme("gated", GatedPowers::new(SAMPLE_RATE, gating))
+            .region(0..(SAMPLE_RATE as u64 * 5))
+            .track_snapshots(true)
+            .clone();
+
+        let gating = BlockSource::from(gating);
+        let consecutive_source = BlockSource::from("consecutive");
+        let gated = BlockSource::from("gated");
+
+        let frames = test_frames(SAMPLE_RATE, 20).collect::<Vec<_>>();
+
+        let mut pipeline = builder.build();
+        pipeline.feed(frames.iter().copied());
+
+        // The scheme gives the same blocks as the equivalent custom gating, so
+        // its readings should only differ by rounding.
+        let snapshot = pipeline.snapshot();
+
+        let expected = [
+            (snapshot[&gating].integrated, snapshot[&consecutive_source].integrated),
+            (snapshot[&gating].maximum, snapshot[&consecutive_source].maximum),
+            (pipeline.sliding_average(gating.clone(), 4000), pipeline.sliding_average("consecutive", 10)),
+        ];
+
+        for &(e, p) in expected.iter() {
+            assert_abs_diff_eq!(e.unwrap(), p.unwrap(), epsilon = 1e-9);
+        }
+
+        let output = pipeline.calculate();
+
+        let expected = [
+            (output.averages[&gating], output.averages[&consecutive_source]),
+            (
+                output.sliding_averages[&(gating.clone(), 4000)],
+                output.sliding_averages[&(consecutive_source.clone(), 10)],
+            ),
+            (output.regions[0].averages[&gating], output.regions[0].averages[&consecutive_source]),
+        ];
+
+        for &(e, p) in expected.iter() {
+            assert_abs_diff_eq!(e.unwrap(), p.unwrap(), epsilon = 1e-9);
+        }
+
+        let channels = output.channel_averages[&gating].as_ref().unwrap();
+        let scheme_channels = output.channel_averages[&consecutive_source].as_ref().unwrap();
+
+        for (e, p) in channels.iter().zip(scheme_channels.iter()) {
+            assert_abs_diff_eq!(e, p, epsilon = 1e-9);
+        }
+
+        // A built-in generator that is added as a scheme shares the sub-blocks
+        // of the gatings, so it measures exactly the same. Maximums are only
+        // reported for the gatings that asked for them.
+        assert_eq!(output.averages[&gated], output.averages[&gating]);
+        assert_eq!(output.channel_averages[&gated], output.channel_averages[&gating]);
+        assert_eq!(output.maximums.keys().collect::<Vec<_>>(), vec![&gating]);
+
+        // Schemes are resumed from checkpoints along with everything else,
+        // partway through a block.
+        let mut pipeline = builder.build();
+        pipeline.feed(frames[..123_457].iter().copied());
+
+        let checkpoint = pipeline.checkpoint();
+        let mut pipeline = builder.resume(&checkpoint).unwrap();
+        pipeline.feed(frames[checkpoint.frames() as usize..].iter().copied());
+
+        let produced = pipeline.calculate();
+
+        assert_eq!(produced.averages, output.averages);
+        assert_eq!(produced.maximums, output.maximums);
+        assert_eq!(produced.channel_averages, output.channel_averages);
+        assert_eq!(produced.sliding_averages, output.sliding_averages);
+        assert_eq!(produced.regions[0].averages, output.regions[0].averages);
+
+        // A scheme that cannot save its state cannot be resumed.
+        let stateless = builder.clone()
+            .scheme("consecutive", Stateless(consecutive))
+            .clone();
+
+        let checkpoint = stateless.build().checkpoint();
+        assert_eq!(stateless.resume(&checkpoint).err(), Some(CheckpointError::Mismatch));
+    }
 }
@@ -0,0 +1,327 @@
+//! Allocation-free, real-time-safe loudness metering.
+//!
+//! All memory is allocated up front when a [`RealtimeMeter`] is created. After
+//! that, processing audio never allocates, locks, or panics, which makes it
+//! safe to use inside an audio callback. Readings are published after every
+//! completed sub-block through a wait-free triple buffer, and can be picked up
+//! from any other thread with a [`ReadingReceiver`].
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use sampara::Frame;
+
+use crate::filter::{KWeightFilter, UnsupportedSampleRate};
+use crate::gated_loudness::{shared_sub_block, Gating, Histogram, SubBlockGate, SubBlockPowers};
+use crate::util::Util;
+
+// The lowest bits of the shared index hold the slot index, and this bit marks
+// the slot as holding a reading that the receiver has not seen yet.
+const FRESH_BIT: usize = 0b100;
+const INDEX_MASK: usize = 0b011;
+
+/// A set of loudness readings at a point in time. Loudness values are in LKFS,
+/// and the range is in LU.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Reading {
+    /// The number of frames that have been processed.
+    pub frames: u64,
+
+    /// The loudness of the latest momentary block.
+    pub momentary: Option<f64>,
+
+    /// The loudness of the latest short-term block.
+    pub shortterm: Option<f64>,
+
+    /// The integrated loudness of all momentary blocks so far.
+    pub integrated: Option<f64>,
+
+    /// The highest momentary loudness so far.
+    pub max_momentary: Option<f64>,
+
+    /// The highest short-term loudness so far.
+    pub max_shortterm: Option<f64>,
+
+    /// The loudness range (LRA) of all short-term blocks so far.
+    pub range: Option<f64>,
+}
+
+struct Shared {
+    slots: [UnsafeCell<Reading>; 3],
+    latest: AtomicUsize,
+}
+
+// The slots are only ever accessed by the one sender and the one receiver, and
+// the index swaps guarantee that they never access the same slot at once.
+unsafe impl Sync for Shared {}
+
+struct ReadingSender {
+    shared: Arc<Shared>,
+    back: usize,
+}
+
+impl ReadingSender {
+    fn publish(&mut self, reading: Reading) {
+        // SAFETY: The back slot is owned exclusively by the sender.
+        unsafe { *self.shared.slots[self.back].get() = reading; }
+
+        let prev = self.shared.latest.swap(self.back | FRESH_BIT, Ordering::AcqRel);
+        self.back = prev & INDEX_MASK;
+    }
+}
+
+/// Receives the readings published by a [`RealtimeMeter`], possibly on a
+/// different thread. Reading never blocks the meter, and never waits for it.
+pub struct ReadingReceiver {
+    shared: Arc<Shared>,
+    front: usize,
+}
+
+impl ReadingReceiver {
+    /// Returns the most recently published reading.
+    pub fn latest(&mut self) -> Reading {
+        if self.shared.latest.load(Ordering::Relaxed) & FRESH_BIT != 0 {
+            let prev = self.shared.latest.swap(self.front, Ordering::AcqRel);
+            self.front = prev & INDEX_MASK;
+        }
+
+        // SAFETY: The front slot is owned exclusively by the receiver.
+        unsafe { *self.shared.slots[self.front].get() }
+    }
+}
+
+fn triple_buffer() -> (ReadingSender, ReadingReceiver) {
+    let shared = Arc::new(Shared {
+        slots: Default::default(),
+        latest: AtomicUsize::new(1),
+    });
+
+    let sender = ReadingSender { shared: Arc::clone(&shared), back: 0 };
+    let receiver = ReadingReceiver { shared, front: 2 };
+
+    (sender, receiver)
+}
+
+/// A loudness meter for momentary, short-term, and integrated loudness, as
+/// well as loudness range, with all of its memory allocated up front.
+///
+/// Integrated loudness and loudness range are calculated from fixed-size
+/// histograms, and so are accurate to within 0.01 LU.
+pub struct RealtimeMeter<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    g_weights: F,
+    k_filter: KWeightFilter<F, N>,
+    sub_blocks: SubBlockPowers<F, N>,
+    momentary_gate: SubBlockGate<F, N, Vec<F>>,
+    shortterm_gate: SubBlockGate<F, N, Vec<F>>,
+    momentary_hist: Box<Histogram>,
+    shortterm_hist: Box<Histogram>,
+    reading: Reading,
+    sender: ReadingSender,
+}
+
+impl<F, const N: usize> RealtimeMeter<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Creates a new meter, along with a receiver for its readings.
+    ///
+    /// Panics if the sample rate is not supported by the K-weighting filter.
+    pub fn new(sample_rate: u32, g_weights: F) -> (Self, ReadingReceiver) {
+        match Self::try_new(sample_rate, g_weights) {
+            Ok(pair) => pair,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates a new meter, along with a receiver for its readings, or
+    /// returns an error if the sample rate is not supported by the
+    /// K-weighting filter.
+    pub fn try_new(sample_rate: u32, g_weights: F) -> Result<(Self, ReadingReceiver), UnsupportedSampleRate> {
+        let k_filter = KWeightFilter::try_new(sample_rate)?;
+
+        let (m_gate_len, m_step) = Gating::Momentary.frames(sample_rate);
+        let (s_gate_len, s_step) = Gating::Shortterm.frames(sample_rate);
+
+        let sub_block = shared_sub_block([(m_gate_len, m_step), (s_gate_len, s_step)].iter().copied());
+
+        let (sender, receiver) = triple_buffer();
+
+        let meter = Self {
+            g_weights,
+            k_filter,
+            sub_blocks: SubBlockPowers::new(sub_block),
+            momentary_gate: SubBlockGate::new(sub_block, m_gate_len, m_step),
+            shortterm_gate: SubBlockGate::new(sub_block, s_gate_len, s_step),
+            momentary_hist: Histogram::boxed(),
+            shortterm_hist: Histogram::boxed(),
+            reading: Reading::default(),
+            sender,
+        };
+
+        Ok((meter, receiver))
+    }
+
+    /// Resets the meter, and publishes an empty reading.
+    pub fn reset(&mut self) {
+        self.k_filter.reset();
+        self.sub_blocks.reset();
+        self.momentary_gate.reset();
+        self.shortterm_gate.reset();
+        self.momentary_hist.reset();
+        self.shortterm_hist.reset();
+        self.reading = Reading::default();
+        self.sender.publish(self.reading);
+    }
+
+    /// Returns the latest reading, as seen by the meter itself.
+    pub fn reading(&self) -> Reading {
+        self.reading
+    }
+
+    pub fn push(&mut self, input: F) {
+        self.push_array(Util::frame_to_array(input));
+    }
+
+    /// Processes a buffer of interleaved samples. Unlike the other buffer
+    /// processing methods, any incomplete frame at the end of the buffer is
+    /// ignored instead of causing a panic.
+    pub fn process_interleaved<S>(&mut self, samples: &[S])
+    where
+        S: Copy + Into<f64>,
+    {
+        for chunk in samples.chunks_exact(N) {
+            let mut x = [0.0; N];
+            for (x, s) in x.iter_mut().zip(chunk.iter()) {
+                *x = (*s).into();
+            }
+
+            self.push_array(x);
+        }
+    }
+
+    fn push_array(&mut self, input: [f64; N]) {
+        let mut x = input;
+        self.k_filter.process_array(&mut x);
+        let filtered_frame: F = Util::array_to_frame(x);
+
+        self.reading.frames += 1;
+
+        let sub_block_sum = match self.sub_blocks.push(filtered_frame) {
+            Some(s) => s,
+            None => return,
+        };
+
+        let max = |m: Option<f64>, l: f64| Some(m.map_or(l, |m| m.max(l)));
+
+        if let Some(gated_powers) = self.momentary_gate.push(sub_block_sum) {
+            let power = Util::weighted_power(gated_powers, self.g_weights);
+            let loudness = Util::lufs(power);
+
+            self.momentary_hist.push(loudness, power);
+
+            self.reading.momentary = Some(loudness);
+            self.reading.max_momentary = max(self.reading.max_momentary, loudness);
+            self.reading.integrated = self.momentary_hist.integrated();
+        }
+
+        if let Some(gated_powers) = self.shortterm_gate.push(sub_block_sum) {
+            let power = Util::weighted_power(gated_powers, self.g_weights);
+            let loudness = Util::lufs(power);
+
+            self.shortterm_hist.push(loudness, power);
+
+            self.reading.shortterm = Some(loudness);
+            self.reading.max_shortterm = max(self.reading.max_shortterm, loudness);
+            self.reading.range = self.shortterm_hist.range();
+        }
+
+        self.sender.publish(self.reading);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f64::consts::PI;
+
+    use crate::pipeline::{BlockSource, PipelineBuilder};
+
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn matches_pipeline() {
+        const SAMPLE_RATE: u32 = 48000;
+        const G_WEIGHTS: [f64; 2] = [1.0, 1.0];
+
+        let frames = (0..(SAMPLE_RATE as usize * 20))
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                let amp = 0.5 + 0.49 * (2.0 * PI * 0.1 * t).sin();
+
+                [amp * (2.0 * PI * 997.0 * t).sin(), amp * (2.0 * PI * 440.0 * t).sin()]
+            })
+            .collect::<Vec<_>>();
+
+        let (mut meter, mut receiver) = RealtimeMeter::new(SAMPLE_RATE, G_WEIGHTS);
+
+        let handle = std::thread::spawn(move || {
+            for frame in frames.iter() {
+                meter.push(*frame);
+            }
+
+            (meter.reading(), frames)
+        });
+
+        // Readings can be taken while the meter is running, and the frame
+        // count should never go backwards.
+        let mut last_frames = 0;
+        while !handle.is_finished() {
+            let reading = receiver.latest();
+            assert!(reading.frames >= last_frames);
+            last_frames = reading.frames;
+        }
+
+        let (expected_reading, frames) = handle.join().unwrap();
+
+        assert_eq!(receiver.latest(), expected_reading);
+        assert_eq!(expected_reading.frames, frames.len() as u64);
+
+        let mut pipeline = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS)
+            .averages(vec![Gating::Momentary, Gating::Shortterm])
+            .build();
+
+        pipeline.feed(frames);
+
+        // The pipeline reports the loudest block of each gating in its
+        // snapshots, rather than in its output.
+        let snapshot = pipeline.snapshot();
+        let output = pipeline.calculate();
+
+        let momentary = BlockSource::from(Gating::Momentary);
+        let shortterm = BlockSource::from(Gating::Shortterm);
+
+        assert_abs_diff_eq!(
+            output.averages[&momentary].unwrap(),
+            expected_reading.integrated.unwrap(),
+            epsilon = 0.01,
+        );
+        assert_abs_diff_eq!(
+            snapshot[&momentary].maximum.unwrap(),
+            expected_reading.max_momentary.unwrap(),
+            epsilon = 1e-9,
+        );
+        assert_abs_diff_eq!(
+            snapshot[&shortterm].maximum.unwrap(),
+            expected_reading.max_shortterm.unwrap(),
+            epsilon = 1e-9,
+        );
+    }
+}
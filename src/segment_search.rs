@@ -0,0 +1,447 @@
+//! Searching for the loudest and quietest stretches of a programme.
+
+use core::cmp::Ordering;
+use core::ops::Range;
+
+use alloc::collections::{BinaryHeap, VecDeque};
+use alloc::vec::Vec;
+
+use sampara::Frame;
+
+use crate::filter::{KWeightFilter, UnsupportedSampleRate};
+use crate::gated_loudness::{Frames, GatedPowers, Gating};
+use crate::util::Util;
+
+// Blocks at or below this loudness are considered silent, which is the same
+// as the absolute loudness threshold used for gating.
+const DEFAULT_SILENCE_THRESH: f64 = -70.0;
+
+/// The number of windows that [`SegmentSearch::loudest`] and
+/// [`SegmentSearch::quietest`] can return, unless it is changed with
+/// [`SegmentSearch::set_max_results`].
+pub const DEFAULT_MAX_RESULTS: usize = 10;
+
+/// A window found by a [`SegmentSearch`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// The frames covered by the window.
+    pub range: Range<u64>,
+
+    /// The start and end of the window, in seconds.
+    pub start_secs: f64,
+    pub end_secs: f64,
+
+    /// The loudness of the window, in LUFS.
+    pub loudness: f64,
+}
+
+// A window position that may still end up in the results. Candidates are
+// ordered from best to worst, so the top of a heap of them is the worst one.
+#[derive(Debug, Copy, Clone)]
+struct Candidate {
+    // The loudness for the quietest windows, and its negation for the loudest
+    // ones, so that lower is always better.
+    badness: f64,
+    index: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Of two equally loud windows, the earlier one is preferred.
+        self.badness.total_cmp(&other.badness).then(self.index.cmp(&other.index))
+    }
+}
+
+// A running sum that also tracks the rounding error of each addition, so that
+// adding and later removing very loud blocks does not leave an error that is
+// large next to quiet ones.
+#[derive(Debug, Copy, Clone, Default)]
+struct CompensatedSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl CompensatedSum {
+    fn add(&mut self, x: f64) {
+        let t = self.sum + x;
+
+        self.compensation +=
+            if self.sum.abs() >= x.abs() { (self.sum - t) + x }
+            else { (x - t) + self.sum }
+        ;
+
+        self.sum = t;
+    }
+
+    fn value(&self) -> f64 {
+        self.sum + self.compensation
+    }
+}
+
+/// Slides a fixed-length window over the momentary blocks of a signal, and
+/// finds the loudest and quietest positions of that window.
+///
+/// Only the blocks of the current window and the best window positions found
+/// so far are kept, so memory does not grow with the length of the input.
+/// The loudness of a window is the mean power of the blocks that lie entirely
+/// within it, without any relative gating.
+pub struct SegmentSearch<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    k_filter: KWeightFilter<F, N>,
+    gated_powers: GatedPowers<F, N>,
+    g_weights: F,
+    sample_rate: u32,
+    silence_threshold: f64,
+
+    gate_len: u64,
+    step: Frames,
+    window_blocks: usize,
+
+    // The weighted powers of the blocks in the current window, their sum and
+    // the number of them that are silent.
+    recent: VecDeque<f64>,
+    sum: CompensatedSum,
+    silent_blocks: usize,
+
+    // The number of window positions seen so far, and the best of them.
+    num_windows: usize,
+    max_results: usize,
+    loudest: BinaryHeap<Candidate>,
+    quietest: BinaryHeap<Candidate>,
+}
+
+impl<F, const N: usize> SegmentSearch<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Creates a new search with a window length of `window_ms`, which must be
+    /// at least as long as a momentary block.
+    ///
+    /// Panics if the sample rate is not supported by the K-weighting filter.
+    pub fn new(sample_rate: u32, g_weights: F, window_ms: u64) -> Self {
+        Self::with_silence_threshold(sample_rate, g_weights, window_ms, DEFAULT_SILENCE_THRESH)
+    }
+
+    /// Creates a new search in the same way as [`Self::new`], or returns an
+    /// error if the sample rate is not supported by the K-weighting filter.
+    pub fn try_new(sample_rate: u32, g_weights: F, window_ms: u64) -> Result<Self, UnsupportedSampleRate> {
+        Self::try_with_silence_threshold(sample_rate, g_weights, window_ms, DEFAULT_SILENCE_THRESH)
+    }
+
+    /// Creates a new search, where windows that contain any block at or below
+    /// `silence_threshold` LUFS are left out of the quietest windows.
+    pub fn with_silence_threshold(
+        sample_rate: u32,
+        g_weights: F,
+        window_ms: u64,
+        silence_threshold: f64,
+    ) -> Self
+    {
+        match Self::try_with_silence_threshold(sample_rate, g_weights, window_ms, silence_threshold) {
+            Ok(search) => search,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    pub fn try_with_silence_threshold(
+        sample_rate: u32,
+        g_weights: F,
+        window_ms: u64,
+        silence_threshold: f64,
+    ) -> Result<Self, UnsupportedSampleRate>
+    {
+        let k_filter = KWeightFilter::try_new(sample_rate)?;
+
+        let gating = Gating::Momentary;
+        let (gate_len, step) = gating.frames(sample_rate);
+        let gate_len = gate_len as u64;
+        let window_len = Util::ms_to_samples(window_ms, sample_rate);
+
+        assert!(window_len >= gate_len, "window is shorter than a block");
+
+        let window_blocks = ((window_len - gate_len) * step.den() / step.num()) as usize + 1;
+
+        Ok(Self {
+            k_filter,
+            gated_powers: GatedPowers::new(sample_rate, gating),
+            g_weights,
+            sample_rate,
+            silence_threshold,
+            gate_len,
+            step,
+            window_blocks,
+            recent: VecDeque::with_capacity(window_blocks),
+            sum: CompensatedSum::default(),
+            silent_blocks: 0,
+            num_windows: 0,
+            max_results: DEFAULT_MAX_RESULTS,
+            loudest: BinaryHeap::new(),
+            quietest: BinaryHeap::new(),
+        })
+    }
+
+    /// Sets the largest number of windows that [`Self::loudest`] and
+    /// [`Self::quietest`] can return, which is [`DEFAULT_MAX_RESULTS`] by
+    /// default. Memory use grows with this number times the number of blocks
+    /// in a window, since enough of the best positions are kept to always
+    /// find that many windows that do not overlap.
+    ///
+    /// Panics if any input has already been processed.
+    pub fn set_max_results(&mut self, max_results: usize) {
+        assert!(self.num_windows == 0 && self.recent.is_empty(), "search has already started");
+
+        self.max_results = max_results;
+    }
+
+    pub fn reset(&mut self) {
+        self.k_filter.reset();
+        self.gated_powers.reset();
+        self.recent.clear();
+        self.sum = CompensatedSum::default();
+        self.silent_blocks = 0;
+        self.num_windows = 0;
+        self.loudest.clear();
+        self.quietest.clear();
+    }
+
+    // The number of candidates that need to be kept to find `max_results`
+    // non-overlapping windows. Each window that is taken rules out at most
+    // `2 * window_blocks - 1` positions, including itself, so the last one
+    // taken is always within this many of the best positions.
+    fn num_candidates(&self) -> usize {
+        match self.max_results {
+            0 => 0,
+            k => (k - 1) * (2 * self.window_blocks - 1) + 1,
+        }
+    }
+
+    // Adds a candidate to a heap, dropping the worst one if there are then
+    // more than `limit`.
+    fn keep_candidate(heap: &mut BinaryHeap<Candidate>, candidate: Candidate, limit: usize) {
+        if heap.len() < limit {
+            heap.push(candidate);
+        }
+        else if heap.peek().map_or(false, |worst| candidate < *worst) {
+            heap.pop();
+            heap.push(candidate);
+        }
+    }
+
+    pub fn push(&mut self, input: F) {
+        let filtered = self.k_filter.process(input);
+
+        let gated_powers = match self.gated_powers.process(filtered) {
+            Some(gp) => gp,
+            None => return,
+        };
+
+        let silence_threshold = self.silence_threshold;
+        let is_silent = move |p: f64| Util::lufs(p) <= silence_threshold;
+
+        if self.recent.len() == self.window_blocks {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.sum.add(-oldest);
+                self.silent_blocks -= is_silent(oldest) as usize;
+            }
+        }
+
+        let power = Util::weighted_power(gated_powers, self.g_weights);
+
+        self.recent.push_back(power);
+        self.sum.add(power);
+        self.silent_blocks += is_silent(power) as usize;
+
+        if self.recent.len() < self.window_blocks {
+            return;
+        }
+
+        let mean_power = self.sum.value().max(0.0) / self.window_blocks as f64;
+        let loudness = Util::lufs(mean_power);
+        let index = self.num_windows;
+
+        self.num_windows += 1;
+
+        let limit = self.num_candidates();
+
+        Self::keep_candidate(&mut self.loudest, Candidate { badness: -loudness, index }, limit);
+
+        if self.silent_blocks == 0 {
+            Self::keep_candidate(&mut self.quietest, Candidate { badness: loudness, index }, limit);
+        }
+    }
+
+    pub fn feed<I>(&mut self, frames: I)
+    where
+        I: IntoIterator<Item = F>,
+    {
+        for frame in frames.into_iter() {
+            self.push(frame);
+        }
+    }
+
+    /// Returns up to `k` of the loudest windows seen so far, loudest first.
+    /// The returned windows never overlap each other.
+    ///
+    /// Panics if `k` is more than the maximum set with
+    /// [`Self::set_max_results`].
+    pub fn loudest(&self, k: usize) -> Vec<Segment> {
+        self.search(k, &self.loudest, |c| -c.badness)
+    }
+
+    /// Returns up to `k` of the quietest windows seen so far that contain no
+    /// silent blocks, quietest first. The returned windows never overlap each
+    /// other.
+    ///
+    /// Panics if `k` is more than the maximum set with
+    /// [`Self::set_max_results`].
+    pub fn quietest(&self, k: usize) -> Vec<Segment> {
+        self.search(k, &self.quietest, |c| c.badness)
+    }
+
+    fn search<L>(&self, k: usize, candidates: &BinaryHeap<Candidate>, loudness: L) -> Vec<Segment>
+    where
+        L: Fn(&Candidate) -> f64,
+    {
+        assert!(k <= self.max_results, "more results requested than are kept");
+
+        let mut candidates = candidates.iter().copied().collect::<Vec<_>>();
+        candidates.sort();
+
+        let mut found: Vec<Segment> = Vec::with_capacity(k);
+
+        // Greedily take the best windows that do not overlap any that have
+        // already been taken.
+        for candidate in candidates {
+            if found.len() == k {
+                break;
+            }
+
+            let range = self.window_range(candidate.index);
+            let overlaps = found.iter()
+                .any(|s| range.start < s.range.end && s.range.start < range.end);
+
+            if !overlaps {
+                found.push(Segment {
+                    start_secs: range.start as f64 / self.sample_rate as f64,
+                    end_secs: range.end as f64 / self.sample_rate as f64,
+                    range,
+                    loudness: loudness(&candidate),
+                });
+            }
+        }
+
+        found
+    }
+
+    // The frames covered by the window that starts with the given block. Each
+    // block starts on the last frame before its exact position, when the step
+    // is not a whole number of frames.
+    fn window_range(&self, index: usize) -> Range<u64> {
+        let block_start = |i: u64| i * self.step.num() / self.step.den();
+
+        let start = block_start(index as u64);
+        let end = block_start((index + self.window_blocks - 1) as u64) + self.gate_len;
+
+        start..end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use approx::assert_abs_diff_eq;
+
+    use crate::test_util::TestUtil;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    // The amplitude of the test signal at `t` seconds.
+    fn amplitude(t: f64) -> f64 {
+        match t {
+            t if (1.0..3.0).contains(&t) => 0.0,
+            t if (6.0..9.0).contains(&t) => 0.8,
+            t if (14.0..17.0).contains(&t) => 0.02,
+            _ => 0.1,
+        }
+    }
+
+    #[test]
+    fn loudest_and_quietest() {
+        let mut search = SegmentSearch::<f64, 1>::new(SAMPLE_RATE, 1.0, 3000);
+
+        search.feed(TestUtil::sine(SAMPLE_RATE, 997.0, 20.0, amplitude));
+
+        let loudest = search.loudest(2);
+        assert_eq!(loudest.len(), 2);
+        assert_abs_diff_eq!(loudest[0].start_secs, 6.0, epsilon = 1e-9);
+        assert_abs_diff_eq!(loudest[0].end_secs, 9.0, epsilon = 1e-9);
+        assert!(loudest[0].loudness > loudest[1].loudness);
+        assert!(loudest[1].range.start >= loudest[0].range.end || loudest[1].range.end <= loudest[0].range.start);
+
+        // The silent stretch is quieter, but is not a candidate.
+        let quietest = search.quietest(1);
+        assert_eq!(quietest.len(), 1);
+        assert_abs_diff_eq!(quietest[0].start_secs, 14.0, epsilon = 1e-9);
+        assert_abs_diff_eq!(quietest[0].end_secs, 17.0, epsilon = 1e-9);
+        assert!(quietest[0].loudness < loudest[1].loudness);
+    }
+
+    #[test]
+    fn matches_full_search() {
+        const SAMPLE_RATE: u32 = 8000;
+        const K: usize = 4;
+
+        // A level that changes every 0.7 seconds, so that many windows are
+        // close in loudness and overlap each other.
+        let level = |t: f64| ((t / 0.7) as u64 * 7919 % 97) as f64 / 97.0;
+        let frames = TestUtil::sine(SAMPLE_RATE, 997.0, 120.0, level).collect::<Vec<_>>();
+
+        let mut search = SegmentSearch::<f64, 1>::new(SAMPLE_RATE, 1.0, 2000);
+        search.set_max_results(K);
+        search.feed(frames.iter().copied());
+
+        // Every window position, found from scratch.
+        let mut k_filter = KWeightFilter::<f64, 1>::new(SAMPLE_RATE);
+        let mut gated_powers = GatedPowers::<f64, 1>::momentary(SAMPLE_RATE);
+        let powers = frames.iter()
+            .filter_map(|x| gated_powers.process(k_filter.process(*x)))
+            .collect::<Vec<_>>();
+
+        let windows = powers.windows(search.window_blocks)
+            .map(|w| Util::lufs(w.iter().sum::<f64>() / w.len() as f64))
+            .collect::<Vec<_>>();
+
+        let mut order = (0..windows.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| windows[b].total_cmp(&windows[a]));
+
+        let mut expected: Vec<Range<u64>> = Vec::new();
+        for i in order {
+            let range = search.window_range(i);
+
+            if expected.len() < K && expected.iter().all(|r| range.end <= r.start || r.end <= range.start) {
+                expected.push(range);
+            }
+        }
+
+        let produced = search.loudest(K);
+
+        assert_eq!(expected, produced.iter().map(|s| s.range.clone()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,377 @@
+//! Detection of silent regions, for trimming and splitting long recordings.
+
+use core::ops::Range;
+
+use alloc::vec::Vec;
+
+use sampara::Frame;
+
+use crate::filter::{KWeightFilter, UnsupportedSampleRate};
+use crate::gated_loudness::{GatedPowers, Gating};
+use crate::math;
+use crate::pipeline::PipelineBuilder;
+use crate::util::Util;
+
+/// The level below which a block is considered silent.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Threshold {
+    /// A K-weighted loudness, in LUFS.
+    Lufs(f64),
+
+    /// An unweighted RMS level of the loudest channel, in dBFS.
+    Dbfs(f64),
+}
+
+/// Finds the silent regions of a signal, by checking the level of each gated
+/// block against a threshold.
+///
+/// A silent region is made up of consecutive silent blocks, but never overlaps
+/// a block that is above the threshold, so region boundaries are accurate to
+/// within one gate length. Regions that are shorter than the minimum duration
+/// are ignored.
+pub struct SilenceDetector<F, const N: usize>
+where
+    F: Frame<N, Sample = f64>,
+{
+    // Only needed for thresholds in LUFS.
+    k_filter: Option<KWeightFilter<F, N>>,
+    gated_powers: GatedPowers<F, N>,
+    g_weights: F,
+    threshold: Threshold,
+    gate_len: u64,
+    min_len: u64,
+
+    frames: u64,
+
+    // The end of the most recent block that was above the threshold, and the
+    // start of the current run of silent blocks, if any.
+    loud_end: u64,
+    run_start: Option<u64>,
+
+    silences: Vec<Range<u64>>,
+}
+
+impl<F, const N: usize> SilenceDetector<F, N>
+where
+    F: Frame<N, Sample = f64>,
+{
+    /// Creates a detector using momentary gating.
+    ///
+    /// Panics if the threshold is in LUFS, and the sample rate is not
+    /// supported by the K-weighting filter.
+    pub fn new(sample_rate: u32, g_weights: F, threshold: Threshold, min_duration_ms: u64) -> Self {
+        Self::with_gating(sample_rate, g_weights, threshold, min_duration_ms, Gating::Momentary)
+    }
+
+    /// Creates a detector using momentary gating, or returns an error if the
+    /// threshold is in LUFS, and the sample rate is not supported by the
+    /// K-weighting filter.
+    pub fn try_new(
+        sample_rate: u32,
+        g_weights: F,
+        threshold: Threshold,
+        min_duration_ms: u64,
+    ) -> Result<Self, UnsupportedSampleRate>
+    {
+        Self::try_with_gating(sample_rate, g_weights, threshold, min_duration_ms, Gating::Momentary)
+    }
+
+    pub fn with_gating(
+        sample_rate: u32,
+        g_weights: F,
+        threshold: Threshold,
+        min_duration_ms: u64,
+        gating: Gating,
+    ) -> Self
+    {
+        match Self::try_with_gating(sample_rate, g_weights, threshold, min_duration_ms, gating) {
+            Ok(detector) => detector,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    pub fn try_with_gating(
+        sample_rate: u32,
+        g_weights: F,
+        threshold: Threshold,
+        min_duration_ms: u64,
+        gating: Gating,
+    ) -> Result<Self, UnsupportedSampleRate>
+    {
+        let k_filter = match threshold {
+            Threshold::Lufs(_) => Some(KWeightFilter::try_new(sample_rate)?),
+            Threshold::Dbfs(_) => None,
+        };
+
+        let (gate_len, _) = gating.frames(sample_rate);
+
+        Ok(Self {
+            k_filter,
+            gated_powers: GatedPowers::new(sample_rate, gating),
+            g_weights,
+            threshold,
+            gate_len: gate_len as u64,
+            min_len: Util::ms_to_samples(min_duration_ms, sample_rate),
+            frames: 0,
+            loud_end: 0,
+            run_start: None,
+            silences: Vec::new(),
+        })
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(k_filter) = self.k_filter.as_mut() {
+            k_filter.reset();
+        }
+
+        self.gated_powers.reset();
+        self.frames = 0;
+        self.loud_end = 0;
+        self.run_start = None;
+        self.silences.clear();
+    }
+
+    fn is_silent(&self, gated_powers: F) -> bool {
+        match self.threshold {
+            Threshold::Lufs(threshold) => {
+                Util::loudness(gated_powers, self.g_weights) < threshold
+            },
+            Threshold::Dbfs(threshold) => {
+                let max_power = gated_powers.channels().fold(0.0, f64::max);
+                10.0 * math::log10(max_power) < threshold
+            },
+        }
+    }
+
+    /// Processes a frame. If this ends a silent region that is long enough,
+    /// the region is returned, in frames.
+    pub fn push(&mut self, input: F) -> Option<Range<u64>> {
+        self.frames += 1;
+
+        let input = match self.k_filter.as_mut() {
+            Some(k_filter) => k_filter.process(input),
+            None => input,
+        };
+
+        let gated_powers = self.gated_powers.process(input)?;
+
+        let block_end = self.frames;
+        let block_start = block_end.saturating_sub(self.gate_len);
+
+        if self.is_silent(gated_powers) {
+            if self.run_start.is_none() {
+                self.run_start = Some(block_start.max(self.loud_end));
+            }
+
+            None
+        }
+        else {
+            self.loud_end = block_end;
+
+            let run_start = self.run_start.take()?;
+            self.close_run(run_start..block_start)
+        }
+    }
+
+    fn close_run(&mut self, range: Range<u64>) -> Option<Range<u64>> {
+        if range.end > range.start && range.end - range.start >= self.min_len {
+            self.silences.push(range.clone());
+            Some(range)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn feed<I>(&mut self, frames: I)
+    where
+        I: IntoIterator<Item = F>,
+    {
+        for frame in frames.into_iter() {
+            self.push(frame);
+        }
+    }
+
+    /// Finishes detection, closing any silent region that runs up to the end
+    /// of the signal.
+    pub fn finish(mut self) -> Silences {
+        if let Some(run_start) = self.run_start.take() {
+            self.close_run(run_start..self.frames);
+        }
+
+        Silences {
+            ranges: self.silences,
+            total_frames: self.frames,
+        }
+    }
+}
+
+/// The silent regions found in a signal, along with helpers for trimming and
+/// segmenting it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Silences {
+    ranges: Vec<Range<u64>>,
+    total_frames: u64,
+}
+
+impl Silences {
+    /// The silent regions, in frames and in order.
+    pub fn ranges(&self) -> &[Range<u64>] {
+        &self.ranges
+    }
+
+    pub fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+
+    /// Returns the range of frames that remains after trimming leading and
+    /// trailing silence, or `None` if the whole signal is silent.
+    pub fn trim_points(&self) -> Option<Range<u64>> {
+        let start = self.ranges.first()
+            .filter(|r| r.start == 0)
+            .map_or(0, |r| r.end);
+
+        let end = self.ranges.last()
+            .filter(|r| r.end == self.total_frames)
+            .map_or(self.total_frames, |r| r.start);
+
+        if start < end { Some(start..end) } else { None }
+    }
+
+    /// Returns the points at which to split the signal into sections, which
+    /// are the midpoints of the silent regions between non-silent audio.
+    pub fn split_points(&self) -> Vec<u64> {
+        self.interior()
+            .map(|r| r.start + (r.end - r.start) / 2)
+            .collect()
+    }
+
+    /// Returns the non-silent sections of the signal, with all silence
+    /// removed.
+    pub fn segments(&self) -> Vec<Range<u64>> {
+        let trimmed = match self.trim_points() {
+            Some(trimmed) => trimmed,
+            None => return Vec::new(),
+        };
+
+        let mut segments = Vec::new();
+        let mut start = trimmed.start;
+
+        for r in self.interior() {
+            segments.push(start..r.start);
+            start = r.end;
+        }
+
+        segments.push(start..trimmed.end);
+        segments
+    }
+
+    /// Adds each non-silent section as a region to a pipeline builder, so
+    /// that every section is measured independently in a single pass.
+    pub fn add_regions<F, const N: usize>(&self, builder: &mut PipelineBuilder<F, N>)
+    where
+        F: Frame<N, Sample = f64>,
+    {
+        for segment in self.segments() {
+            builder.region(segment);
+        }
+    }
+
+    // The silent regions that touch neither the start nor the end.
+    fn interior(&self) -> impl Iterator<Item = &Range<u64>> {
+        let total_frames = self.total_frames;
+        self.ranges.iter().filter(move |r| r.start > 0 && r.end < total_frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use approx::assert_abs_diff_eq;
+
+    use crate::pipeline::BlockSource;
+    use crate::test_util::TestUtil;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const G_WEIGHTS: [f64; 2] = [1.0, 1.0];
+
+    // Silence and tones, with lengths in seconds.
+    const SECTIONS: [(f64, bool); 5] = [
+        (2.0, false), (3.0, true), (2.0, false), (3.0, true), (1.5, false),
+    ];
+
+    fn test_frames() -> Vec<[f64; 2]> {
+        let mut frames = Vec::new();
+
+        for &(secs, is_tone) in SECTIONS.iter() {
+            let amp = if is_tone { 0.5 } else { 0.0 };
+            frames.extend(TestUtil::sine(SAMPLE_RATE, 997.0, secs, |_| amp).map(|x| [x, x]));
+        }
+
+        frames
+    }
+
+    #[test]
+    fn silences_and_segments() {
+        let frames = test_frames();
+
+        for &threshold in &[Threshold::Lufs(-60.0), Threshold::Dbfs(-60.0)] {
+            let mut detector = SilenceDetector::new(SAMPLE_RATE, G_WEIGHTS, threshold, 1000);
+            detector.feed(frames.iter().copied());
+            let silences = detector.finish();
+
+            let secs = |f: u64| f as f64 / SAMPLE_RATE as f64;
+
+            // Boundaries are within a gate length of the true ones.
+            let expected = [(0.0, 2.0), (5.0, 7.0), (10.0, 11.5)];
+            assert_eq!(silences.ranges().len(), expected.len());
+
+            for (r, (start, end)) in silences.ranges().iter().zip(expected.iter()) {
+                assert_abs_diff_eq!(secs(r.start), start, epsilon = 0.4);
+                assert_abs_diff_eq!(secs(r.end), end, epsilon = 0.4);
+            }
+
+            let trimmed = silences.trim_points().unwrap();
+            assert_abs_diff_eq!(secs(trimmed.start), 2.0, epsilon = 0.4);
+            assert_abs_diff_eq!(secs(trimmed.end), 10.0, epsilon = 0.4);
+
+            let split_points = silences.split_points();
+            assert_eq!(split_points.len(), 1);
+            assert_abs_diff_eq!(secs(split_points[0]), 6.0, epsilon = 0.4);
+
+            // Each segment is measured on its own. Blocks that straddle the
+            // edges of a tone pull its loudness down slightly.
+            let mut builder = PipelineBuilder::new(SAMPLE_RATE, G_WEIGHTS);
+            builder.average(Gating::Momentary).program(false);
+            silences.add_regions(&mut builder);
+
+            let mut pipeline = builder.build();
+            pipeline.feed(frames.iter().copied());
+            let output = pipeline.calculate();
+
+            assert_eq!(output.regions.len(), 2);
+            for region in output.regions.iter() {
+                let loudness = region.averages[&BlockSource::from(Gating::Momentary)].unwrap();
+                assert_abs_diff_eq!(loudness, -6.02, epsilon = 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn minimum_duration() {
+        let frames = test_frames();
+
+        let mut detector = SilenceDetector::new(SAMPLE_RATE, G_WEIGHTS, Threshold::Lufs(-60.0), 1500);
+        detector.feed(frames.iter().copied());
+        let silences = detector.finish();
+
+        // Silent regions are shortened by the blocks that overlap the tones
+        // on either side, which puts all but the leading one under the
+        // minimum.
+        assert_eq!(silences.ranges().len(), 1);
+        assert_eq!(silences.ranges()[0].start, 0);
+        assert_eq!(silences.split_points(), Vec::<u64>::new());
+        assert_eq!(silences.trim_points().unwrap().end, silences.total_frames());
+    }
+}
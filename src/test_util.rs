@@ -607,4 +607,18 @@ impl TestUtil {
 
         (flat_samples, sample_rate, num_channels)
     }
+
+    /// Generates `secs` seconds of a sine at `freq` Hz, with its amplitude at
+    /// each point in time, in seconds, given by `envelope`.
+    pub fn sine<E>(sample_rate: u32, freq: f64, secs: f64, envelope: E) -> impl Iterator<Item = f64>
+    where
+        E: Fn(f64) -> f64,
+    {
+        let sr = sample_rate as f64;
+
+        (0..(secs * sr) as usize).map(move |i| {
+            let t = i as f64 / sr;
+            envelope(t) * (2.0 * PI * freq * t).sin()
+        })
+    }
 }
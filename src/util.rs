@@ -1,5 +1,7 @@
 use sampara::Frame;
 
+use crate::math;
+
 const DEN_THRESHOLD: f64 = 1.0e-15;
 
 pub struct Util;
@@ -7,31 +9,87 @@ pub struct Util;
 impl Util {
     #[inline]
     pub fn lufs(x: f64) -> f64 {
-        -0.691 + 10.0 * x.log10()
+        -0.691 + 10.0 * math::log10(x)
     }
 
     /// Given the mean squares (powers) of an input signal and a set of
     /// per-channel weights, calculates the weighted loudness across all input
     /// channels. This is equation #4 in the ITU BS.1770 tech spec.
     pub fn loudness<F, const N: usize>(mean_sq: F, weights: F) -> f64
+    where
+        F: Frame<N, Sample = f64>,
+    {
+        Util::lufs(Util::weighted_power(mean_sq, weights))
+    }
+
+    /// Given the mean squares (powers) of an input signal and a set of
+    /// per-channel weights, calculates the weighted sum of the powers across
+    /// all input channels. This is the argument of the logarithm in equation
+    /// #4 in the ITU BS.1770 tech spec.
+    pub fn weighted_power<F, const N: usize>(mean_sq: F, weights: F) -> f64
     where
         F: Frame<N, Sample = f64>,
     {
         let zipped: F = mean_sq.mul_frame(weights.into_float_frame());
 
-        Util::lufs(zipped.channels().sum())
+        zipped.channels().sum()
     }
 
+    /// Returns the highest absolute sample value in a frame. NaN samples are
+    /// ignored, so a frame of only NaN samples has a peak of zero.
     pub fn frame_peak<F, const N: usize>(frame: F) -> f64
     where
         F: Frame<N, Sample = f64>,
     {
-        // Take the highest absolute value found in this sample.
-        // TODO: Handle NaN.
+        // `f64::max` returns the other value when one of them is NaN.
         frame.into_channels()
             .map(|x| x.abs())
-            .max_by(|a, b| a.partial_cmp(b).unwrap())
-            .unwrap_or(0.0)
+            .fold(0.0, f64::max)
+    }
+
+    /// Copies the channels of a frame into an array.
+    #[inline]
+    pub fn frame_to_array<F, const N: usize>(frame: F) -> [f64; N]
+    where
+        F: Frame<N, Sample = f64>,
+    {
+        let mut array = [0.0; N];
+
+        for (a, x) in array.iter_mut().zip(frame.into_channels()) {
+            *a = x;
+        }
+
+        array
+    }
+
+    /// Copies the elements of an array into the channels of a frame.
+    #[inline]
+    pub fn array_to_frame<F, const N: usize>(array: [f64; N]) -> F
+    where
+        F: Frame<N, Sample = f64>,
+    {
+        let mut frame: F = Frame::EQUILIBRIUM;
+
+        for (c, x) in frame.channels_mut().zip(array.iter()) {
+            *c = *x;
+        }
+
+        frame
+    }
+
+    /// Checks that a set of planar buffers has one buffer per channel, and
+    /// that all buffers have the same length, which is returned.
+    pub fn planar_len<I>(mut lens: I, num_channels: usize) -> usize
+    where
+        I: ExactSizeIterator<Item = usize>,
+    {
+        assert_eq!(lens.len(), num_channels, "wrong number of planar channels");
+
+        let len = lens.next().unwrap_or(0);
+
+        assert!(lens.all(|l| l == len), "planar channels have different lengths");
+
+        len
     }
 
     pub fn lufs_hist(count: u64, sum: f64, reference: f64) -> f64 {
@@ -52,6 +110,19 @@ impl Util {
         // Always round to the nearest sample.
         (num / 1000) + if num % 1000 >= 500 { 1 } else { 0 }
     }
+
+    /// Calculates the greatest common divisor of two numbers.
+    pub fn gcd(a: u64, b: u64) -> u64 {
+        let (mut a, mut b) = (a, b);
+
+        while b != 0 {
+            let r = a % b;
+            a = b;
+            b = r;
+        }
+
+        a
+    }
 }
 
 #[cfg(test)]
@@ -78,4 +149,21 @@ mod tests {
             assert_eq!(expected, produced)
         }
     }
+
+    #[test]
+    fn frame_peak() {
+        assert_eq!(Util::frame_peak([0.5, -0.75, 0.25]), 0.75);
+        assert_eq!(Util::frame_peak([f64::NAN, -0.75, 0.25]), 0.75);
+        assert_eq!(Util::frame_peak([f64::NAN, f64::NAN]), 0.0);
+        assert_eq!(Util::frame_peak([0.5, f64::NEG_INFINITY]), f64::INFINITY);
+    }
+
+    #[test]
+    fn gcd() {
+        assert_eq!(Util::gcd(4800, 19200), 4800);
+        assert_eq!(Util::gcd(4410, 17640), 4410);
+        assert_eq!(Util::gcd(4410, 132300), 4410);
+        assert_eq!(Util::gcd(7, 0), 7);
+        assert_eq!(Util::gcd(12, 18), 6);
+    }
 }